@@ -51,7 +51,7 @@ struct HelloFS;
 impl Filesystem for HelloFS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
+            reply.entry(&TTL, &HELLO_TXT_ATTR, 0, HELLO_TXT_ATTR.perm, HELLO_TXT_ATTR.gid);
         } else {
             reply.error(ENOENT);
         }
@@ -59,8 +59,8 @@ impl Filesystem for HelloFS {
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
+            1 => reply.attr(&TTL, &HELLO_DIR_ATTR, HELLO_DIR_ATTR.perm, HELLO_DIR_ATTR.gid),
+            2 => reply.attr(&TTL, &HELLO_TXT_ATTR, HELLO_TXT_ATTR.perm, HELLO_TXT_ATTR.gid),
             _ => reply.error(ENOENT),
         }
     }
@@ -116,6 +116,7 @@ fn main() -> std::io::Result<()> {
         MountOpt::Name("evented") + MountOpt::DefaultPermissions + MountOpt::AllowOther,
     )
     .unwrap();
+    let mut fs = HelloFS;
     let poll = Poll::new().unwrap();
     let mut buffer: Vec<u8> = Vec::with_capacity((16 * 1024 * 1024) + 4096);
     let mut events = Events::with_capacity(1024);
@@ -123,18 +124,19 @@ fn main() -> std::io::Result<()> {
         .unwrap();
     loop {
         poll.poll(&mut events, None).unwrap();
-        for e in events.iter().filter(|evt| !evt.readiness().is_empty()) {
-            'read_until_eagin: loop {
+        for _ in events.iter().filter(|evt| !evt.readiness().is_empty()) {
+            'read_until_eagain: loop {
                 match session.recv(&mut buffer) {
-                    RecvResult::Some(request) => {}
-                    RecvResult::Retry => break 'read_until_eagin,
+                    RecvResult::Some(request) => session.dispatch(request, &mut fs),
+                    RecvResult::Skipped => continue,
+                    RecvResult::Retry => break 'read_until_eagain,
                     RecvResult::Drop(e) => {
-                        eprintln!("Fs must be deregistred: {:?}", e);
+                        eprintln!("Fs must be deregistered: {:?}", e);
                         poll.deregister(&session)?;
+                        return Ok(());
                     }
                 }
             }
         }
     }
-    Ok(())
 }
@@ -47,8 +47,54 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 16;
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
 #[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
-#[cfg(feature = "abi-7-19")]
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(all(feature = "abi-7-23", not(feature = "abi-7-24")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
+#[cfg(all(feature = "abi-7-24", not(feature = "abi-7-25")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 24;
+#[cfg(all(feature = "abi-7-25", not(feature = "abi-7-26")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 25;
+#[cfg(all(feature = "abi-7-26", not(feature = "abi-7-27")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 26;
+#[cfg(all(feature = "abi-7-27", not(feature = "abi-7-28")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 27;
+#[cfg(all(feature = "abi-7-28", not(feature = "abi-7-29")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 28;
+#[cfg(all(feature = "abi-7-29", not(feature = "abi-7-30")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 29;
+#[cfg(all(feature = "abi-7-30", not(feature = "abi-7-31")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 30;
+#[cfg(all(feature = "abi-7-31", not(feature = "abi-7-32")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+#[cfg(all(feature = "abi-7-32", not(feature = "abi-7-33")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 32;
+#[cfg(all(feature = "abi-7-33", not(feature = "abi-7-34")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 33;
+#[cfg(all(feature = "abi-7-34", not(feature = "abi-7-35")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 34;
+#[cfg(all(feature = "abi-7-35", not(feature = "abi-7-36")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 35;
+#[cfg(all(feature = "abi-7-36", not(feature = "abi-7-37")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 36;
+#[cfg(all(feature = "abi-7-37", not(feature = "abi-7-38")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 37;
+#[cfg(all(feature = "abi-7-38", not(feature = "abi-7-39")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 38;
+#[cfg(all(feature = "abi-7-39", not(feature = "abi-7-40")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 39;
+#[cfg(all(feature = "abi-7-40", not(feature = "abi-7-41")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 40;
+#[cfg(all(feature = "abi-7-41", not(feature = "abi-7-42")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 41;
+#[cfg(feature = "abi-7-42")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 42;
 
 pub const FUSE_ROOT_ID: u64 = 1;
 
@@ -77,8 +123,10 @@ pub struct fuse_attr {
     pub flags: u32, // see chflags(2)
     #[cfg(feature = "abi-7-9")]
     pub blksize: u32,
-    #[cfg(feature = "abi-7-9")]
+    #[cfg(all(feature = "abi-7-9", any(target_os = "macos", not(feature = "abi-7-32"))))]
     pub padding: u32,
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    pub flags: u32, // see FUSE_ATTR_* flags
 }
 
 #[repr(C)]
@@ -120,6 +168,10 @@ pub mod consts {
     pub const FATTR_MTIME_NOW: u32 = 1 << 8;
     #[cfg(feature = "abi-7-9")]
     pub const FATTR_LOCKOWNER: u32 = 1 << 9;
+    #[cfg(feature = "abi-7-23")]
+    pub const FATTR_CTIME: u32 = 1 << 10;
+    #[cfg(feature = "abi-7-33")]
+    pub const FATTR_KILL_SUIDGID: u32 = 1 << 11;
 
     #[cfg(target_os = "macos")]
     pub const FATTR_CRTIME: u32 = 1 << 28;
@@ -135,6 +187,14 @@ pub mod consts {
     pub const FOPEN_KEEP_CACHE: u32 = 1 << 1; // don't invalidate the data cache on open
     #[cfg(feature = "abi-7-10")]
     pub const FOPEN_NONSEEKABLE: u32 = 1 << 2; // the file is not seekable
+    #[cfg(feature = "abi-7-28")]
+    pub const FOPEN_CACHE_DIR: u32 = 1 << 3; // allow caching this directory
+    #[cfg(feature = "abi-7-31")]
+    pub const FOPEN_STREAM: u32 = 1 << 4; // the file is stream-like (no file position at all)
+    #[cfg(feature = "abi-7-35")]
+    pub const FOPEN_NOFLUSH: u32 = 1 << 5; // don't flush data cache on close (unless FUSE_WRITEBACK_CACHE)
+    #[cfg(feature = "abi-7-38")]
+    pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6; // allow concurrent direct writes on the same inode
 
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_ATTR: u32 = 1 << 30;
@@ -166,6 +226,68 @@ pub mod consts {
     pub const FUSE_FLOCK_LOCKS: u32 = 1 << 10; // remote locking for BSD style file locks
     #[cfg(feature = "abi-7-18")]
     pub const FUSE_HAS_IOCTL_DIR: u32 = 1 << 11; // kernel supports ioctl on directories
+    #[cfg(feature = "abi-7-20")]
+    pub const FUSE_AUTO_INVAL_DATA: u32 = 1 << 12; // automatically invalidate cached pages
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_DO_READDIRPLUS: u32 = 1 << 13; // do READDIRPLUS (READDIR+LOOKUP in one)
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_READDIRPLUS_AUTO: u32 = 1 << 14; // adaptive readdirplus
+    #[cfg(feature = "abi-7-22")]
+    pub const FUSE_ASYNC_DIO: u32 = 1 << 15; // asynchronous direct I/O submission
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_WRITEBACK_CACHE: u32 = 1 << 16; // use writeback cache for buffered writes
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_NO_OPEN_SUPPORT: u32 = 1 << 17; // kernel supports zero-message opens
+    #[cfg(feature = "abi-7-25")]
+    pub const FUSE_PARALLEL_DIROPS: u32 = 1 << 18; // allow parallel lookups and readdir
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_HANDLE_KILLPRIV: u32 = 1 << 19; // fs handles killing suid/sgid/cap on write/chown/trunc
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_POSIX_ACL: u32 = 1 << 20; // filesystem supports posix acls
+    #[cfg(feature = "abi-7-27")]
+    pub const FUSE_ABORT_ERROR: u32 = 1 << 21; // reading the device after abort returns ECONNABORTED
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_MAX_PAGES: u32 = 1 << 22; // init_out.max_pages contains the max number of req pages
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23; // cache READLINK responses
+    #[cfg(feature = "abi-7-29")]
+    pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24; // kernel supports zero-message opendir
+    #[cfg(feature = "abi-7-30")]
+    pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25; // only invalidate cached pages on explicit request
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26; // init_out.map_alignment contains log2(byte alignment)
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    pub const FUSE_SUBMOUNTS: u32 = 1 << 27; // kernel supports auto-mounting directory submounts
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub const FUSE_HANDLE_KILLPRIV_V2: u32 = 1 << 28; // fs kills suid/sgid/cap on write/chown/trunc
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub const FUSE_SETXATTR_EXT: u32 = 1 << 29; // server supports extended struct fuse_setxattr_in
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    pub const FUSE_INIT_EXT: u32 = 1 << 30; // extended fuse_init_in request
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    pub const FUSE_INIT_RESERVED: u32 = 1 << 31; // reserved, do not use
+
+    // Init request/reply flags in flags2 (shifted by 32 bits, see FUSE_INIT_EXT)
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_SECURITY_CTX: u64 = 1 << 32; // add security context to create, mkdir, symlink and mknod
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_HAS_INODE_DAX: u64 = 1 << 33; // use per inode DAX
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_CREATE_SUPP_GROUP: u64 = 1 << 34; // add supplementary group info to create, mkdir, symlink and mknod
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_HAS_EXPIRE_ONLY: u64 = 1 << 35; // kernel supports expiry-only entry invalidation
+    #[cfg(feature = "abi-7-39")]
+    pub const FUSE_DIRECT_IO_ALLOW_MMAP: u64 = 1 << 36; // allow shared mmap in FOPEN_DIRECT_IO mode
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_PASSTHROUGH: u64 = 1 << 37; // passthrough read/write io for backing files
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_NO_EXPORT_SUPPORT: u64 = 1 << 38; // explicitly disable export support
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_HAS_RESEND: u64 = 1 << 39; // kernel supports resending pending requests
+    #[cfg(feature = "abi-7-41")]
+    pub const FUSE_ALLOW_IDMAP: u64 = 1 << 40; // allow creation of idmapped mounts
+    #[cfg(feature = "abi-7-42")]
+    pub const FUSE_OVER_IO_URING: u64 = 1 << 41; // exchange requests and replies over io_uring
 
     #[cfg(target_os = "macos")]
     pub const FUSE_ALLOCATE: u32 = 1 << 27;
@@ -182,6 +304,12 @@ pub mod consts {
     #[cfg(feature = "abi-7-12")]
     pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0; // use unrestricted ioctl
 
+    // Attribute flags (fuse_attr.flags)
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0; // object is a submount root
+    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+    pub const FUSE_ATTR_DAX: u32 = 1 << 1; // enable DAX for this file in per inode DAX mode
+
     // Release flags
     pub const FUSE_RELEASE_FLUSH: u32 = 1 << 0;
     #[cfg(feature = "abi-7-17")]
@@ -200,6 +328,18 @@ pub mod consts {
     pub const FUSE_WRITE_CACHE: u32 = 1 << 0; // delayed write from page cache, file handle is guessed
     #[cfg(feature = "abi-7-9")]
     pub const FUSE_WRITE_LOCKOWNER: u32 = 1 << 1; // lock_owner field is valid
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_WRITE_KILL_PRIV: u32 = 1 << 2; // kill suid and sgid bits
+    #[cfg(feature = "abi-7-33")]
+    pub const FUSE_WRITE_KILL_SUIDGID: u32 = FUSE_WRITE_KILL_PRIV; // kill suid and sgid bits
+
+    // Open flags (fuse_open_in.open_flags and fuse_create_in.open_flags)
+    #[cfg(feature = "abi-7-33")]
+    pub const FUSE_OPEN_KILL_SUIDGID: u32 = 1 << 0; // kill suid and sgid if executable
+
+    // Setxattr flags
+    #[cfg(feature = "abi-7-33")]
+    pub const FUSE_SETXATTR_ACL_KILL_SGID: u32 = 1 << 0; // clear SGID when system.posix_acl_access is set
 
     // Read flags
     #[cfg(feature = "abi-7-9")]
@@ -216,6 +356,8 @@ pub mod consts {
     pub const FUSE_IOCTL_32BIT: u32 = 1 << 3; // 32bit ioctl
     #[cfg(feature = "abi-7-18")]
     pub const FUSE_IOCTL_DIR: u32 = 1 << 4; // is a directory
+    #[cfg(feature = "abi-7-30")]
+    pub const FUSE_IOCTL_COMPAT_X32: u32 = 1 << 5; // x32 compat ioctl on 64bit machine (64bit time_t)
     #[cfg(feature = "abi-7-11")]
     pub const FUSE_IOCTL_MAX_IOV: u32 = 256; // maximum of in_iovecs + out_iovecs
 
@@ -223,6 +365,40 @@ pub mod consts {
     #[cfg(feature = "abi-7-9")]
     pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0; // request poll notify
 
+    // Notify inval entry flags
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_EXPIRE_ONLY: u32 = 1 << 0; // only expire the entry, don't invalidate it
+
+    // Setupmapping flags
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_READ: u64 = 1 << 1;
+
+    // Request extension types (see fuse_ext_header). Types 0..31 are reserved for
+    // fuse_secctx_header, so that a security context is a valid extension as well.
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_MAX_NR_SECCTX: u32 = 31;
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_EXT_GROUPS: u32 = 32;
+
+    // Device ioctl to attach a newly opened /dev/fuse fd to the connection of an existing
+    // one, i.e. _IOR(229, 0, uint32_t). Requests read from the clone are answered on it.
+    #[cfg(target_os = "linux")]
+    pub const FUSE_DEV_IOC_CLONE: u64 = 0x8004_e500;
+
+    // Commands of FUSE over io_uring (IORING_OP_URING_CMD on the FUSE device)
+    #[cfg(feature = "abi-7-42")]
+    pub const FUSE_IO_URING_CMD_REGISTER: u32 = 1; // register an entry and fetch a request
+    #[cfg(feature = "abi-7-42")]
+    pub const FUSE_IO_URING_CMD_COMMIT_AND_FETCH: u32 = 2; // commit a reply and fetch a request
+
+    // Size of the in/out header and the operation header areas of fuse_uring_req_header
+    #[cfg(feature = "abi-7-42")]
+    pub const FUSE_URING_IN_OUT_HEADER_SZ: usize = 128;
+    #[cfg(feature = "abi-7-42")]
+    pub const FUSE_URING_OP_IN_OUT_SZ: usize = 128;
+
     // The read buffer is required to be at least 8k, but may be much larger
     pub const FUSE_MIN_READ_BUFFER: usize = 8192;
 }
@@ -281,6 +457,22 @@ pub enum fuse_opcode {
     FUSE_BATCH_FORGET = 42,
     #[cfg(feature = "abi-7-19")]
     FUSE_FALLOCATE = 43,
+    #[cfg(feature = "abi-7-21")]
+    FUSE_READDIRPLUS = 44,
+    #[cfg(feature = "abi-7-23")]
+    FUSE_RENAME2 = 45,
+    #[cfg(feature = "abi-7-24")]
+    FUSE_LSEEK = 46,
+    #[cfg(feature = "abi-7-28")]
+    FUSE_COPY_FILE_RANGE = 47,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_SETUPMAPPING = 48,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_REMOVEMAPPING = 49,
+    #[cfg(feature = "abi-7-34")]
+    FUSE_SYNCFS = 50,
+    #[cfg(feature = "abi-7-37")]
+    FUSE_TMPFILE = 51,
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -347,6 +539,22 @@ impl TryFrom<u32> for fuse_opcode {
             42 => Ok(fuse_opcode::FUSE_BATCH_FORGET),
             #[cfg(feature = "abi-7-19")]
             43 => Ok(fuse_opcode::FUSE_FALLOCATE),
+            #[cfg(feature = "abi-7-21")]
+            44 => Ok(fuse_opcode::FUSE_READDIRPLUS),
+            #[cfg(feature = "abi-7-23")]
+            45 => Ok(fuse_opcode::FUSE_RENAME2),
+            #[cfg(feature = "abi-7-24")]
+            46 => Ok(fuse_opcode::FUSE_LSEEK),
+            #[cfg(feature = "abi-7-28")]
+            47 => Ok(fuse_opcode::FUSE_COPY_FILE_RANGE),
+            #[cfg(feature = "abi-7-31")]
+            48 => Ok(fuse_opcode::FUSE_SETUPMAPPING),
+            #[cfg(feature = "abi-7-31")]
+            49 => Ok(fuse_opcode::FUSE_REMOVEMAPPING),
+            #[cfg(feature = "abi-7-34")]
+            50 => Ok(fuse_opcode::FUSE_SYNCFS),
+            #[cfg(feature = "abi-7-37")]
+            51 => Ok(fuse_opcode::FUSE_TMPFILE),
 
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
@@ -394,7 +602,7 @@ pub enum fuse_notify_code {
 impl TryFrom<u32> for fuse_notify_code {
     type Error = InvalidNotifyCodeError;
 
-    fn from_u32(n: u32) -> Result<Self, Self::Error> {
+    fn try_from(n: u32) -> Result<Self, Self::Error> {
         match n {
             #[cfg(feature = "abi-7-11")]
             1 => Ok(fuse_notify_code::FUSE_POLL),
@@ -512,6 +720,15 @@ pub struct fuse_exchange_in {
     pub options: u64,
 }
 
+#[cfg(feature = "abi-7-23")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_rename2_in {
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_link_in {
@@ -531,10 +748,16 @@ pub struct fuse_setattr_in {
     pub lock_owner: u64,
     pub atime: u64,
     pub mtime: u64,
+    #[cfg(not(feature = "abi-7-23"))]
     pub unused2: u64,
+    #[cfg(feature = "abi-7-23")]
+    pub ctime: u64,
     pub atimensec: u32,
     pub mtimensec: u32,
+    #[cfg(not(feature = "abi-7-23"))]
     pub unused3: u32,
+    #[cfg(feature = "abi-7-23")]
+    pub ctimensec: u32,
     pub mode: u32,
     pub unused4: u32,
     pub uid: u32,
@@ -560,7 +783,10 @@ pub struct fuse_setattr_in {
 #[derive(Debug)]
 pub struct fuse_open_in {
     pub flags: u32,
+    #[cfg(not(feature = "abi-7-33"))]
     pub unused: u32,
+    #[cfg(feature = "abi-7-33")]
+    pub open_flags: u32, // FUSE_OPEN_...
 }
 
 #[repr(C)]
@@ -570,8 +796,10 @@ pub struct fuse_create_in {
     pub mode: u32,
     #[cfg(feature = "abi-7-12")]
     pub umask: u32,
-    #[cfg(feature = "abi-7-12")]
+    #[cfg(all(feature = "abi-7-12", not(feature = "abi-7-33")))]
     pub padding: u32,
+    #[cfg(feature = "abi-7-33")]
+    pub open_flags: u32, // FUSE_OPEN_...
 }
 
 #[repr(C)]
@@ -657,6 +885,7 @@ pub struct fuse_fsync_in {
 pub struct fuse_setxattr_in {
     pub size: u32,
     pub flags: u32,
+    // TODO: extended by setxattr_flags and padding since ABI 7.33 if FUSE_SETXATTR_EXT is set
     #[cfg(target_os = "macos")]
     pub position: u32,
     #[cfg(target_os = "macos")]
@@ -715,6 +944,16 @@ pub struct fuse_init_in {
     pub flags: u32,
 }
 
+/// Extended part of `fuse_init_in`. The kernel appends it to `fuse_init_in` if it sets the
+/// `FUSE_INIT_EXT` flag, older kernels only send the first four fields.
+#[cfg(feature = "abi-7-36")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_init_in_ext {
+    pub flags2: u32,
+    pub unused: [u32; 11],
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_init_out {
@@ -729,8 +968,32 @@ pub struct fuse_init_out {
     #[cfg(feature = "abi-7-13")]
     pub congestion_threshold: u16,
     pub max_write: u32,
+    #[cfg(feature = "abi-7-23")]
+    pub time_gran: u32,
+    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
+    pub unused: [u32; 9],
+    #[cfg(feature = "abi-7-28")]
+    pub max_pages: u16,
+    #[cfg(all(feature = "abi-7-28", not(feature = "abi-7-31")))]
+    pub padding: u16,
+    #[cfg(feature = "abi-7-31")]
+    pub map_alignment: u16,
+    #[cfg(all(feature = "abi-7-28", not(feature = "abi-7-36")))]
+    pub unused: [u32; 8],
+    #[cfg(feature = "abi-7-36")]
+    pub flags2: u32,
+    #[cfg(all(feature = "abi-7-36", not(feature = "abi-7-40")))]
+    pub unused: [u32; 7],
+    #[cfg(feature = "abi-7-40")]
+    pub max_stack_depth: u32,
+    #[cfg(feature = "abi-7-40")]
+    pub unused: [u32; 6],
 }
 
+/// Size of `fuse_init_out` up to ABI 7.22, which is what kernels before ABI 7.23 expect
+#[cfg(feature = "abi-7-23")]
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
 #[derive(Debug)]
@@ -813,7 +1076,10 @@ pub struct fuse_poll_in {
     pub fh: u64,
     pub kh: u64,
     pub flags: u32,
+    #[cfg(not(feature = "abi-7-21"))]
     pub padding: u32,
+    #[cfg(feature = "abi-7-21")]
+    pub events: u32,
 }
 
 #[cfg(feature = "abi-7-11")]
@@ -835,11 +1101,11 @@ pub struct fuse_notify_poll_wakeup_out {
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_fallocate_in {
-    fh: u64,
-    offset: u64,
-    length: u64,
-    mode: u32,
-    padding: u32,
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -852,7 +1118,12 @@ pub struct fuse_in_header {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
+    #[cfg(not(feature = "abi-7-38"))]
     pub padding: u32,
+    #[cfg(feature = "abi-7-38")]
+    pub total_extlen: u16, // length of extensions in 8 byte units
+    #[cfg(feature = "abi-7-38")]
+    pub padding: u16,
 }
 
 #[repr(C)]
@@ -873,6 +1144,14 @@ pub struct fuse_dirent {
     // followed by name of namelen bytes
 }
 
+#[cfg(feature = "abi-7-21")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
+
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
 #[derive(Debug)]
@@ -888,7 +1167,10 @@ pub struct fuse_notify_inval_inode_out {
 pub struct fuse_notify_inval_entry_out {
     pub parent: u64,
     pub namelen: u32,
+    #[cfg(not(feature = "abi-7-38"))]
     pub padding: u32,
+    #[cfg(feature = "abi-7-38")]
+    pub flags: u32,
 }
 
 #[cfg(feature = "abi-7-18")]
@@ -934,3 +1216,141 @@ pub struct fuse_notify_retrieve_in {
     pub dummy3: u64,
     pub dummy4: u64,
 }
+
+#[cfg(feature = "abi-7-24")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_lseek_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32,
+    pub padding: u32,
+}
+
+#[cfg(feature = "abi-7-24")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_lseek_out {
+    pub offset: u64,
+}
+
+#[cfg(feature = "abi-7-28")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_copy_file_range_in {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_setupmapping_in {
+    pub fh: u64,      // an already open handle
+    pub foffset: u64, // offset into the file to start the mapping
+    pub len: u64,     // length of mapping required
+    pub flags: u64,   // flags, FUSE_SETUPMAPPING_FLAG_*
+    pub moffset: u64, // offset in memory window
+}
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_removemapping_in {
+    pub count: u32, // number of fuse_removemapping_one follows
+}
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_removemapping_one {
+    pub moffset: u64, // offset into the dax window start the unmapping
+    pub len: u64,     // length of mapping required
+}
+
+#[cfg(feature = "abi-7-34")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_syncfs_in {
+    pub padding: u64,
+}
+
+/// For each security context, a fuse_secctx is sent, followed by the context name (zero
+/// terminated) and the context itself.
+#[cfg(feature = "abi-7-36")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_secctx {
+    pub size: u32,
+    pub padding: u32,
+}
+
+/// Header of the security contexts sent with create, mkdir, symlink and mknod requests. Contains
+/// the total size of all contexts (including this header) and the number of contexts.
+#[cfg(feature = "abi-7-36")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_secctx_header {
+    pub size: u32,
+    pub nr_secctx: u32,
+}
+
+/// Header of a request extension. `size` is the total size of the extension including this
+/// header and is a multiple of 8 bytes. `typ` is one of the extension types.
+#[cfg(feature = "abi-7-38")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_ext_header {
+    pub size: u32,
+    pub typ: u32,
+}
+
+/// Supplementary groups extension, followed by `nr_groups` group ids.
+#[cfg(feature = "abi-7-38")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_supp_groups {
+    pub nr_groups: u32,
+    // followed by nr_groups u32 group ids
+}
+
+/// Metadata of an io_uring entry. The kernel sets the id to commit the reply with and the size
+/// of the request payload, userspace sets the size of the reply payload.
+#[cfg(feature = "abi-7-42")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_uring_ent_in_out {
+    pub flags: u64,
+    pub commit_id: u64,
+    pub payload_sz: u32,
+    pub padding: u32,
+    pub reserved: u64,
+}
+
+/// Header buffer of an io_uring entry. `in_out` holds the fuse_in_header of a request or the
+/// fuse_out_header of a reply, `op_in` the operation specific header of a request (e.g.
+/// fuse_read_in). Any further arguments are passed in the separate payload buffer.
+#[cfg(feature = "abi-7-42")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_uring_req_header {
+    pub in_out: [u8; consts::FUSE_URING_IN_OUT_HEADER_SZ],
+    pub op_in: [u8; consts::FUSE_URING_OP_IN_OUT_SZ],
+    pub ring_ent_in_out: fuse_uring_ent_in_out,
+}
+
+/// Command data of an io_uring FUSE command (in the 80 bytes command area of a 128 bytes SQE)
+#[cfg(feature = "abi-7-42")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_uring_cmd_req {
+    pub flags: u64,
+    pub commit_id: u64,
+    pub qid: u16,
+    pub padding: [u8; 6],
+}
@@ -0,0 +1,620 @@
+//! Asynchronous filesystem interface
+//!
+//! An `AsyncFilesystem` returns the result of an operation from an `async fn` instead of
+//! answering a reply object. An `AsyncSession` reads requests from the kernel driver through
+//! tokio, spawns a task for every request and writes the reply as soon as the task's future
+//! completes, so that many requests can be in flight at the same time.
+
+use async_trait::async_trait;
+use libc::c_int;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{Capabilities, FileAttr, FileType, OpenReplyFlags, Request};
+
+mod session;
+pub use session::{mount, AsyncSession};
+
+/// Error number
+///
+/// The error an operation failed with, sent to the kernel as a negated errno value.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Errno(pub c_int);
+
+impl Errno {
+    /// Operation not permitted
+    pub const EPERM: Errno = Errno(libc::EPERM);
+    /// No such file or directory
+    pub const ENOENT: Errno = Errno(libc::ENOENT);
+    /// I/O error
+    pub const EIO: Errno = Errno(libc::EIO);
+    /// Bad file handle
+    pub const EBADF: Errno = Errno(libc::EBADF);
+    /// Permission denied
+    pub const EACCES: Errno = Errno(libc::EACCES);
+    /// File exists
+    pub const EEXIST: Errno = Errno(libc::EEXIST);
+    /// Not a directory
+    pub const ENOTDIR: Errno = Errno(libc::ENOTDIR);
+    /// Is a directory
+    pub const EISDIR: Errno = Errno(libc::EISDIR);
+    /// Invalid argument
+    pub const EINVAL: Errno = Errno(libc::EINVAL);
+    /// No space left on device
+    pub const ENOSPC: Errno = Errno(libc::ENOSPC);
+    /// Result too large (e.g. xattr buffer too small)
+    pub const ERANGE: Errno = Errno(libc::ERANGE);
+    /// Operation not implemented
+    pub const ENOSYS: Errno = Errno(libc::ENOSYS);
+    /// Directory not empty
+    pub const ENOTEMPTY: Errno = Errno(libc::ENOTEMPTY);
+
+    /// Returns the raw error number
+    pub fn code(self) -> c_int {
+        self.0
+    }
+}
+
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Errno {
+        Errno(err.raw_os_error().unwrap_or(libc::EIO))
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.0))
+    }
+}
+
+/// Information about a request
+///
+/// An owned copy of what a `Request` tells about the process that triggered it, since the
+/// request itself can't outlive the session's receive buffer.
+#[derive(Clone, Debug)]
+pub struct RequestInfo {
+    /// Unique identifier of the request
+    pub unique: u64,
+    /// User id of the process
+    pub uid: u32,
+    /// Group id of the process
+    pub gid: u32,
+    /// Process id
+    pub pid: u32,
+    /// See `Request::kill_suidgid` (ABI 7.33)
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub kill_suidgid: bool,
+    /// See `Request::security_context` (ABI 7.36)
+    #[cfg(feature = "abi-7-36")]
+    pub security_context: Option<(OsString, Vec<u8>)>,
+    /// See `Request::supplementary_groups` (ABI 7.38)
+    #[cfg(feature = "abi-7-38")]
+    pub supplementary_groups: Vec<u32>,
+    /// See `Request::deadline`
+    pub deadline: Option<Instant>,
+}
+
+impl<'a> From<&Request<'a>> for RequestInfo {
+    fn from(req: &Request<'a>) -> RequestInfo {
+        RequestInfo {
+            unique: req.unique(),
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+            #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+            kill_suidgid: req.kill_suidgid(),
+            #[cfg(feature = "abi-7-36")]
+            security_context: req
+                .security_context()
+                .map(|(name, value)| (name.to_os_string(), value.to_vec())),
+            #[cfg(feature = "abi-7-38")]
+            supplementary_groups: req.supplementary_groups().to_vec(),
+            deadline: req.deadline(),
+        }
+    }
+}
+
+/// Directory entry (see `ReplyEntry::entry`)
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// Time the entry and its attributes may be cached
+    pub ttl: Duration,
+    /// Attributes of the inode
+    pub attr: FileAttr,
+    /// Generation of the inode
+    pub generation: u64,
+    /// Permission mask applied to the attributes
+    pub mask: u16,
+    /// Group id reported for the inode
+    pub gid: u32,
+}
+
+/// File attributes (see `ReplyAttr::attr`)
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    /// Time the attributes may be cached
+    pub ttl: Duration,
+    /// Attributes of the inode
+    pub attr: FileAttr,
+    /// Permission mask applied to the attributes
+    pub mask: u16,
+    /// Group id reported for the inode
+    pub gid: u32,
+}
+
+/// Opened file or directory (see `ReplyOpen::opened`)
+#[derive(Clone, Copy, Debug)]
+pub struct Open {
+    /// File handle
+    pub fh: u64,
+    /// Flags for the kernel
+    pub flags: OpenReplyFlags,
+}
+
+/// Created and opened file (see `ReplyCreate::created`)
+#[derive(Clone, Copy, Debug)]
+pub struct Created {
+    /// Entry of the new file
+    pub entry: Entry,
+    /// Handle of the opened file
+    pub open: Open,
+}
+
+/// Entry of a directory listing (see `ReplyDirectory::add`)
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset the kernel passes to `readdir` to continue after this entry
+    pub offset: i64,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// Filesystem statistics (see `ReplyStatfs::statfs`)
+#[derive(Clone, Copy, Debug)]
+pub struct Statfs {
+    /// Total data blocks
+    pub blocks: u64,
+    /// Free blocks
+    pub bfree: u64,
+    /// Free blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+    /// Block size
+    pub bsize: u32,
+    /// Maximum length of file names
+    pub namelen: u32,
+    /// Fragment size
+    pub frsize: u32,
+}
+
+/// Extended attribute result (see `ReplyXattr`)
+#[derive(Clone, Debug)]
+pub enum Xattr {
+    /// Size of the value (or list), if the request was made with size 0
+    Size(u32),
+    /// Value (or list) of the extended attribute
+    Data(Vec<u8>),
+}
+
+/// POSIX file lock (see `ReplyLock::locked`)
+#[derive(Clone, Copy, Debug)]
+pub struct Lock {
+    /// Start of the locked range
+    pub start: u64,
+    /// End of the locked range
+    pub end: u64,
+    /// Type of the lock
+    pub typ: u32,
+    /// Process holding the lock
+    pub pid: u32,
+}
+
+/// Extended times (see `ReplyXTimes::xtimes`)
+#[cfg(target_os = "macos")]
+#[derive(Clone, Copy, Debug)]
+pub struct XTimes {
+    /// Time of last backup
+    pub bkuptime: SystemTime,
+    /// Time of creation
+    pub crtime: SystemTime,
+}
+
+/// Asynchronous filesystem trait.
+///
+/// The asynchronous counterpart of `Filesystem`: every operation is an `async fn` that
+/// returns its result, which the session sends to the kernel once the future completes.
+/// Operations run concurrently in separate tasks, so methods take `&self`. Default
+/// implementations behave like the ones of `Filesystem`, and operations the kernel stops
+/// sending after failing with `ENOSYS` are not called again.
+// Operations take the same arguments as the ones of `Filesystem`
+#[allow(clippy::too_many_arguments)]
+#[async_trait]
+pub trait AsyncFilesystem: Send + Sync + 'static {
+    /// Initialize filesystem.
+    /// Called before any other filesystem method.
+    async fn init(&self, _req: &RequestInfo) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Optional kernel features the filesystem implements (see `Filesystem::capabilities`).
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    /// Clean up filesystem.
+    /// Called on filesystem exit.
+    async fn destroy(&self, _req: &RequestInfo) {}
+
+    /// Look up a directory entry by name and get its attributes.
+    async fn lookup(&self, _req: &RequestInfo, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Forget about an inode (see `Filesystem::forget`).
+    async fn forget(&self, _req: &RequestInfo, _ino: u64, _nlookup: u64) {}
+
+    /// Get file attributes.
+    async fn getattr(&self, _req: &RequestInfo, _ino: u64) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set file attributes.
+    async fn setattr(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+    ) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read symbolic link.
+    async fn readlink(&self, _req: &RequestInfo, _ino: u64) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create file node.
+    async fn mknod(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    async fn mkdir(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    async fn unlink(&self, _req: &RequestInfo, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    async fn rmdir(&self, _req: &RequestInfo, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    async fn symlink(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _link: &Path,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file.
+    async fn rename(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link.
+    async fn link(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a file (see `Filesystem::open`).
+    async fn open(&self, _req: &RequestInfo, _ino: u64, _flags: u32) -> Result<Open, Errno> {
+        Ok(Open { fh: 0, flags: OpenReplyFlags::empty() })
+    }
+
+    /// Read data (see `Filesystem::read`).
+    async fn read(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+    ) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Write data (see `Filesystem::write`). Returns the number of bytes written.
+    async fn write(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _flags: u32,
+    ) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Flush method (see `Filesystem::flush`).
+    async fn flush(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open file (see `Filesystem::release`).
+    async fn release(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize file contents.
+    async fn fsync(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a directory (see `Filesystem::opendir`).
+    async fn opendir(&self, _req: &RequestInfo, _ino: u64, _flags: u32) -> Result<Open, Errno> {
+        Ok(Open { fh: 0, flags: OpenReplyFlags::empty() })
+    }
+
+    /// Read directory. Returns the entries following the given offset. The session sends
+    /// as many of them as fit into the kernel's buffer, the kernel asks for the rest with
+    /// the offset of the last entry it received.
+    async fn readdir(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+    ) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open directory.
+    async fn releasedir(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize directory contents.
+    async fn fsyncdir(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    async fn statfs(&self, _req: &RequestInfo, _ino: u64) -> Result<Statfs, Errno> {
+        Ok(Statfs {
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            bsize: 512,
+            namelen: 255,
+            frsize: 0,
+        })
+    }
+
+    /// Set an extended attribute.
+    async fn setxattr(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get an extended attribute (see `Filesystem::getxattr`).
+    async fn getxattr(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _name: &OsStr,
+        _size: u32,
+    ) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List extended attribute names (see `Filesystem::listxattr`).
+    async fn listxattr(&self, _req: &RequestInfo, _ino: u64, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    async fn removexattr(&self, _req: &RequestInfo, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check file access permissions (see `Filesystem::access`).
+    async fn access(&self, _req: &RequestInfo, _ino: u64, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open a file (see `Filesystem::create`).
+    async fn create(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+    ) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    async fn getlk(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+    ) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock (see `Filesystem::setlk`).
+    async fn setlk(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+        _sleep: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Map block index within file to block index within device (see `Filesystem::bmap`).
+    async fn bmap(
+        &self,
+        _req: &RequestInfo,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+    ) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only: Rename the volume.
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, _req: &RequestInfo, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only (undocumented)
+    #[cfg(target_os = "macos")]
+    async fn exchange(
+        &self,
+        _req: &RequestInfo,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _options: u64,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only: Query extended times (bkuptime and crtime).
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, _req: &RequestInfo, _ino: u64) -> Result<XTimes, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Android only: Path of the underlying file (see `Filesystem::canonicalpath`).
+    #[cfg(target_os = "android")]
+    async fn canonicalpath(&self, _ino: u64) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Errno;
+    use std::io;
+
+    #[test]
+    fn errno_from_io_error() {
+        let err = io::Error::from_raw_os_error(libc::EACCES);
+        assert_eq!(Errno::from(err), Errno::EACCES);
+        let err = io::Error::other("no errno");
+        assert_eq!(Errno::from(err), Errno::EIO);
+    }
+}
@@ -0,0 +1,867 @@
+//! Asynchronous filesystem session
+//!
+//! Reads requests from a non-blocking channel registered with tokio and dispatches them
+//! through the regular request dispatcher. The filesystem methods the dispatcher calls
+//! spawn a task per request that awaits the `AsyncFilesystem` and answers the reply.
+
+use fuse_abi::fuse_opcode;
+use libc::{c_int, EINTR, ENODEV, ENOENT};
+use log::info;
+use std::ffi::OsStr;
+use std::future::{self, Future};
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::SystemTime;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use super::{AsyncFilesystem, RequestInfo, Xattr};
+use crate::channel::Channel;
+use crate::deadline::{Deadlines, Watchdog};
+use crate::request::{Request, RequestDispatcher};
+use crate::scheduler::Scheduler;
+use crate::session::{FuseSessionStore, RequestErrorCounters, BUFFER_SIZE};
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+use crate::{Capabilities, Filesystem};
+use crate::{ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty};
+use crate::{ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr};
+
+/// The asynchronous session data structure
+#[derive(Debug)]
+pub struct AsyncSession<FS: AsyncFilesystem> {
+    filesystem: Arc<FS>,
+    ch: Channel,
+    store: FuseSessionStore,
+    /// Scheduler of requests
+    scheduler: Option<Scheduler>,
+}
+
+impl<FS: AsyncFilesystem> AsyncSession<FS> {
+    /// Create a new session by mounting the given filesystem to the given mountpoint
+    pub fn new(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<AsyncSession<FS>> {
+        info!("Mounting {}", mountpoint.display());
+        Channel::new(mountpoint, options).map(|ch| AsyncSession::with_channel(filesystem, ch))
+    }
+
+    /// Create a new session on the given channel
+    fn with_channel(filesystem: FS, ch: Channel) -> AsyncSession<FS> {
+        AsyncSession {
+            filesystem: Arc::new(filesystem),
+            ch,
+            store: FuseSessionStore::new(),
+            scheduler: None,
+        }
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// Return the counters of requests that failed to parse
+    pub fn request_errors(&self) -> RequestErrorCounters {
+        self.store.request_errors
+    }
+
+    /// Schedule requests with the given scheduler, which limits the number of requests in
+    /// flight, i.e. tasks that didn't reply yet, per class of requests (see `Scheduler`).
+    /// Every session needs its own scheduler.
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = Some(scheduler);
+    }
+
+    /// Let a watchdog reply with an error to requests whose task didn't reply within the
+    /// given deadlines (see `Deadlines`). Later replies of the tasks are suppressed (see
+    /// `Deadlines::on_suppressed`).
+    pub fn set_deadlines(&mut self, deadlines: Deadlines) {
+        self.store.watchdog = Some(Watchdog::new(deadlines));
+    }
+
+    /// Run the session loop that receives kernel requests and spawns a task for each of
+    /// them. Must be called within a tokio runtime. Returns when the filesystem was
+    /// unmounted; tasks still in flight at that point can't send their replies anymore.
+    pub async fn run(&mut self) -> io::Result<()> {
+        self.ch.set_nonblocking(true)?;
+        let fd = SessionFd::channel(&self.ch).register()?;
+        let mut dispatcher = Dispatcher {
+            filesystem: self.filesystem.clone(),
+            init_result: Ok(()),
+        };
+        let wake = match &self.scheduler {
+            Some(scheduler) => Some(SessionFd::wake(scheduler).register()?),
+            None => None,
+        };
+        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+        loop {
+            // Dispatch deferred requests first if possible, and stop receiving requests while
+            // too many are deferred
+            if let (Some(scheduler), Some(wake)) = (&self.scheduler, &wake) {
+                scheduler.dispatch_ready(&mut dispatcher, &mut self.store);
+                let full = scheduler.is_full();
+                if scheduler.queued() > 0 && !wait_receivable(&fd, wake, full).await? {
+                    continue;
+                }
+            }
+            let mut guard = fd.readable().await?;
+            match guard.try_io(|_| self.ch.receive(&mut buffer)) {
+                // The other end of an fd that isn't a FUSE device (e.g. a socket) was closed
+                Ok(Ok(())) if buffer.is_empty() => break,
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => match err.raw_os_error() {
+                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                    Some(ENOENT) => continue,
+                    // Interrupted system call, retry
+                    Some(EINTR) => continue,
+                    // Filesystem was unmounted, quit the loop
+                    Some(ENODEV) => break,
+                    // Unhandled error
+                    _ => return Err(err),
+                },
+                // No request available (anymore), wait for the channel to become readable
+                Err(_would_block) => continue,
+            }
+            let mut req = match Request::new(
+                self.ch.sender(),
+                &buffer,
+                self.store.unimplemented.clone(),
+            ) {
+                Ok(req) => req,
+                Err(err) => {
+                    Request::reply_invalid(
+                        self.ch.sender(),
+                        &buffer,
+                        &err,
+                        &mut self.store.request_errors,
+                    );
+                    continue;
+                }
+            };
+            if let Some(scheduler) = &self.scheduler {
+                if !scheduler.admit(&mut req) {
+                    continue;
+                }
+            }
+            // The dispatcher needs the result of init and destroy before it replies, so
+            // these are awaited here instead of in a separate task. Like the dispatcher, only
+            // call init for a supported protocol version and destroy once after init.
+            if req.is_supported_init() {
+                let info = RequestInfo::from(&req);
+                dispatcher.init_result = self.filesystem.init(&info).await.map_err(|err| err.0);
+            } else if req.opcode() == fuse_opcode::FUSE_DESTROY as u32
+                && self.store.initialized
+                && !self.store.destroyed
+            {
+                self.filesystem.destroy(&RequestInfo::from(&req)).await;
+            }
+            dispatcher.dispatch(&mut req, &mut self.store);
+        }
+        // Requests that are still deferred can't be replied to anymore
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.clear();
+        }
+        Ok(())
+    }
+}
+
+/// File descriptor of the session that is registered with tokio. It's borrowed from the
+/// channel or scheduler that owns it, so that it can't be closed while tokio polls it.
+struct SessionFd<'a> {
+    fd: RawFd,
+    owner: PhantomData<&'a ()>,
+}
+
+impl<'a> SessionFd<'a> {
+    /// Borrow the FUSE device fd of the given channel
+    fn channel(ch: &'a Channel) -> SessionFd<'a> {
+        SessionFd { fd: ch.as_raw_fd(), owner: PhantomData }
+    }
+
+    /// Borrow the fd of the given scheduler that is readable while a deferred request may
+    /// have become ready
+    fn wake(scheduler: &'a Scheduler) -> SessionFd<'a> {
+        SessionFd { fd: scheduler.wake_fd(), owner: PhantomData }
+    }
+
+    /// Register the fd with tokio to wait until it's readable
+    fn register(self) -> io::Result<AsyncFd<SessionFd<'a>>> {
+        // The fd stays open and is the same as long as it's borrowed from its owner
+        let fd = unsafe { AsyncFd::register_with_interest(self, Interest::READABLE) }?;
+        Ok(fd)
+    }
+}
+
+impl AsRawFd for SessionFd<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Wait until a request can be received from the channel, or a deferred request may have
+/// become ready. If too many requests are deferred, only waits for the latter. Returns false
+/// if deferred requests should be dispatched first.
+async fn wait_receivable(
+    fd: &AsyncFd<SessionFd<'_>>,
+    wake: &AsyncFd<SessionFd<'_>>,
+    full: bool,
+) -> io::Result<bool> {
+    future::poll_fn(|cx| {
+        if let Poll::Ready(guard) = wake.poll_read_ready(cx) {
+            guard?.clear_ready();
+            return Poll::Ready(Ok(false));
+        }
+        if full {
+            return Poll::Pending;
+        }
+        fd.poll_read_ready(cx).map_ok(|_guard| true)
+    })
+    .await
+}
+
+/// Mount the given asynchronous filesystem to the given mountpoint. The returned future
+/// completes when the filesystem is unmounted. Must be awaited within a tokio runtime.
+pub async fn mount<FS: AsyncFilesystem, P: AsRef<Path>>(
+    filesystem: FS,
+    mountpoint: P,
+    options: &[&OsStr],
+) -> io::Result<()> {
+    let mut se = AsyncSession::new(filesystem, mountpoint.as_ref(), options)?;
+    se.run().await
+}
+
+/// Spawn a task that answers a request. With the `tracing` feature, the task runs within the
+/// request's span, which the dispatcher entered.
+fn spawn<F: Future<Output = ()> + Send + 'static>(task: F) {
+    #[cfg(feature = "tracing")]
+    let task = tracing::Instrument::in_current_span(task);
+    tokio::spawn(task);
+}
+
+/// Adapts an asynchronous filesystem to the synchronous `Filesystem` interface used by the
+/// request dispatcher. Every operation copies its arguments and spawns a task that awaits
+/// the filesystem and answers the reply.
+struct Dispatcher<FS> {
+    filesystem: Arc<FS>,
+    /// Result of the filesystem's init, awaited by the session before dispatching
+    init_result: Result<(), c_int>,
+}
+
+impl<FS: AsyncFilesystem> Filesystem for Dispatcher<FS> {
+    fn init(&mut self, _req: &Request<'_>) -> Result<(), c_int> {
+        self.init_result
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.filesystem.capabilities()
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.lookup(&req, parent, &name).await {
+                Ok(e) => reply.entry(&e.ttl, &e.attr, e.generation, e.mask, e.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move { fs.forget(&req, ino, nlookup).await });
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.getattr(&req, ino).await {
+                Ok(a) => reply.attr(&a.ttl, &a.attr, a.mask, a.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            let res = fs
+                .setattr(
+                    &req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime,
+                    flags,
+                )
+                .await;
+            match res {
+                Ok(a) => reply.attr(&a.ttl, &a.attr, a.mask, a.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.readlink(&req, ino).await {
+                Ok(data) => reply.data(&data),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.mknod(&req, parent, &name, mode, rdev).await {
+                Ok(e) => reply.entry(&e.ttl, &e.attr, e.generation, e.mask, e.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.mkdir(&req, parent, &name, mode).await {
+                Ok(e) => reply.entry(&e.ttl, &e.attr, e.generation, e.mask, e.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.unlink(&req, parent, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.rmdir(&req, parent, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        let link = link.to_owned();
+        spawn(async move {
+            match fs.symlink(&req, parent, &name, &link).await {
+                Ok(e) => reply.entry(&e.ttl, &e.attr, e.generation, e.mask, e.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        let newname = newname.to_owned();
+        spawn(async move {
+            match fs.rename(&req, parent, &name, newparent, &newname).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        let newname = newname.to_owned();
+        spawn(async move {
+            match fs.link(&req, ino, newparent, &newname).await {
+                Ok(e) => reply.entry(&e.ttl, &e.attr, e.generation, e.mask, e.gid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.open(&req, ino, flags).await {
+                Ok(open) => reply.opened(open.fh, open.flags),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.read(&req, ino, fh, offset, size).await {
+                Ok(data) => reply.data(&data),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let (fs, req, data) = (self.filesystem.clone(), RequestInfo::from(req), data.to_vec());
+        spawn(async move {
+            match fs.write(&req, ino, fh, offset, &data, flags).await {
+                Ok(size) => reply.written(size),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.flush(&req, ino, fh, lock_owner).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.release(&req, ino, fh, flags, lock_owner, flush).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.fsync(&req, ino, fh, datasync).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.opendir(&req, ino, flags).await {
+                Ok(open) => reply.opened(open.fh, open.flags),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.readdir(&req, ino, fh, offset).await {
+                Ok(entries) => {
+                    for entry in entries {
+                        if reply.add(entry.ino, entry.offset, entry.kind, &entry.name) {
+                            break;
+                        }
+                    }
+                    reply.ok();
+                }
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.releasedir(&req, ino, fh, flags).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn fsyncdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.fsyncdir(&req, ino, fh, datasync).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.statfs(&req, ino).await {
+                Ok(st) => reply.statfs(
+                    st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen,
+                    st.frsize,
+                ),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        let value = value.to_vec();
+        spawn(async move {
+            match fs.setxattr(&req, ino, &name, &value, flags, position).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.getxattr(&req, ino, &name, size).await {
+                Ok(Xattr::Size(size)) => reply.size(size),
+                Ok(Xattr::Data(data)) => reply.data(&data),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.listxattr(&req, ino, size).await {
+                Ok(Xattr::Size(size)) => reply.size(size),
+                Ok(Xattr::Data(data)) => reply.data(&data),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.removexattr(&req, ino, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.access(&req, ino, mask).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.create(&req, parent, &name, mode, flags).await {
+                Ok(c) => reply.created(
+                    &c.entry.ttl,
+                    &c.entry.attr,
+                    c.entry.generation,
+                    c.open.fh,
+                    c.open.flags,
+                    c.entry.mask,
+                    c.entry.gid,
+                ),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.getlk(&req, ino, fh, lock_owner, start, end, typ, pid).await {
+                Ok(lk) => reply.locked(lk.start, lk.end, lk.typ, lk.pid),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.setlk(&req, ino, fh, lock_owner, start, end, typ, pid, sleep).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.bmap(&req, ino, blocksize, idx).await {
+                Ok(block) => reply.bmap(block),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        spawn(async move {
+            match fs.setvolname(&req, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        options: u64,
+        reply: ReplyEmpty,
+    ) {
+        let (fs, req, name) = (self.filesystem.clone(), RequestInfo::from(req), name.to_owned());
+        let newname = newname.to_owned();
+        spawn(async move {
+            match fs.exchange(&req, parent, &name, newparent, &newname, options).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        let (fs, req) = (self.filesystem.clone(), RequestInfo::from(req));
+        spawn(async move {
+            match fs.getxtimes(&req, ino).await {
+                Ok(xtimes) => reply.xtimes(xtimes.bkuptime, xtimes.crtime),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+
+    #[cfg(target_os = "android")]
+    fn canonicalpath(&mut self, ino: u64, reply: ReplyData) {
+        let fs = self.filesystem.clone();
+        spawn(async move {
+            match fs.canonicalpath(ino).await {
+                Ok(path) => reply.data(&path),
+                Err(err) => reply.error(err.0),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncSession;
+    use crate::async_fs::{AsyncFilesystem, Attr, Errno, RequestInfo};
+    use crate::test_util::{channel, RawRequest};
+    use async_trait::async_trait;
+    use fuse_abi::{fuse_opcode, FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Filesystem that counts calls of its init method and fails getattr
+    struct CountingFilesystem {
+        inits: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AsyncFilesystem for CountingFilesystem {
+        async fn init(&self, _req: &RequestInfo) -> Result<(), Errno> {
+            self.inits.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn getattr(&self, _req: &RequestInfo, _ino: u64) -> Result<Attr, Errno> {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    /// Build an init request with the given protocol version
+    fn init(unique: u64, major: u32, minor: u32) -> RawRequest {
+        let arg = [
+            major.to_ne_bytes(),
+            minor.to_ne_bytes(),
+            0u32.to_ne_bytes(),
+            0u32.to_ne_bytes(),
+        ]
+        .concat();
+        RawRequest::new(fuse_opcode::FUSE_INIT, unique, 0, &arg)
+    }
+
+    /// Read the next reply and return its error and unique identifier
+    fn reply(kernel: &mut impl Read) -> (i32, u64) {
+        let mut reply = [0; 256];
+        let len = kernel.read(&mut reply).unwrap();
+        assert!(len >= 16);
+        let error = i32::from_ne_bytes([reply[4], reply[5], reply[6], reply[7]]);
+        let mut unique = [0; 8];
+        unique.copy_from_slice(&reply[8..16]);
+        (error, u64::from_ne_bytes(unique))
+    }
+
+    #[test]
+    fn dispatches_requests() {
+        let (ch, mut kernel) = channel();
+        let inits = Arc::new(AtomicUsize::new(0));
+        let filesystem = CountingFilesystem {
+            inits: inits.clone(),
+        };
+        let mut se = AsyncSession::with_channel(filesystem, ch);
+        let session = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(se.run())
+        });
+
+        // Init isn't called for an unsupported protocol version
+        kernel.write_all(init(1, 7, 5).as_bytes()).unwrap();
+        assert_eq!(reply(&mut kernel), (-libc::EPROTO, 1));
+        assert_eq!(inits.load(Ordering::SeqCst), 0);
+        let request = init(2, FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION);
+        kernel.write_all(request.as_bytes()).unwrap();
+        assert_eq!(reply(&mut kernel), (0, 2));
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+
+        // Operations are answered by their task
+        let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 3, 1, &[0; 16]);
+        kernel.write_all(getattr.as_bytes()).unwrap();
+        assert_eq!(reply(&mut kernel), (-libc::ENOENT, 3));
+
+        // Closing the device ends the session
+        drop(kernel);
+        session.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! Pooled request buffers
+//!
+//! By default, a session reads every request into the same buffer, so request data is only
+//! valid while the request is dispatched. With a buffer pool, every request is read into a
+//! buffer taken from the pool. The filesystem can keep the payload of a write request (see
+//! `Request::write_data`) after returning from `write`, e.g. to hand it to an asynchronous
+//! backend without copying. The buffer returns to the pool once the payload is dropped.
+//! Memory use is bounded by the number of buffers in the pool: if all buffers are in use,
+//! the session waits for one to be returned before reading the next request.
+
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::session::BUFFER_SIZE;
+
+/// Buffers of a pool
+#[derive(Debug, Default)]
+struct PoolBuffers {
+    /// Buffers that are currently unused
+    free: Vec<Vec<u8>>,
+    /// Number of buffers allocated so far
+    allocated: usize,
+}
+
+/// State of a pool shared with its buffers
+#[derive(Debug)]
+struct PoolInner {
+    /// Buffers of the pool
+    buffers: Mutex<PoolBuffers>,
+    /// Notified whenever a buffer is returned
+    returned: Condvar,
+    /// Number of buffers in the pool
+    count: usize,
+}
+
+/// Pool of buffers for receiving requests
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+impl BufferPool {
+    /// Create a pool of the given number of buffers (at least one). Each buffer is large
+    /// enough for the largest request (`MAX_WRITE_SIZE` plus 4k). Buffers are allocated
+    /// when they're first used.
+    pub fn new(count: usize) -> BufferPool {
+        let count = count.max(1);
+        BufferPool {
+            inner: Arc::new(PoolInner {
+                buffers: Mutex::new(PoolBuffers::default()),
+                returned: Condvar::new(),
+                count,
+            }),
+        }
+    }
+
+    /// Return the number of buffers in the pool
+    pub fn count(&self) -> usize {
+        self.inner.count
+    }
+
+    /// Take a buffer from the pool, waiting for one to be returned if all are in use
+    pub(crate) fn get(&self) -> RequestBuffer {
+        let mut buffers = self
+            .inner
+            .buffers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        loop {
+            if let Some(data) = buffers.free.pop() {
+                return RequestBuffer::new(data, self.inner.clone());
+            }
+            if buffers.allocated < self.inner.count {
+                buffers.allocated += 1;
+                let data = Vec::with_capacity(BUFFER_SIZE);
+                return RequestBuffer::new(data, self.inner.clone());
+            }
+            buffers = self
+                .inner
+                .returned
+                .wait(buffers)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+}
+
+/// Buffer a request was received into, returned to its pool when dropped
+pub(crate) struct RequestBuffer {
+    data: Vec<u8>,
+    pool: Arc<PoolInner>,
+}
+
+impl RequestBuffer {
+    fn new(data: Vec<u8>, pool: Arc<PoolInner>) -> RequestBuffer {
+        RequestBuffer { data, pool }
+    }
+
+    /// Return the underlying vector to receive into
+    pub(crate) fn as_mut_vec(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl Deref for RequestBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for RequestBuffer {
+    fn drop(&mut self) {
+        let data = mem::take(&mut self.data);
+        let mut buffers = self
+            .pool
+            .buffers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        buffers.free.push(data);
+        drop(buffers);
+        self.pool.returned.notify_one();
+    }
+}
+
+impl fmt::Debug for RequestBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "RequestBuffer({} bytes)", self.data.len())
+    }
+}
+
+/// Payload of a write request, which keeps its request buffer from returning to the pool
+/// until it's dropped
+#[derive(Clone)]
+pub struct WriteData {
+    buffer: Arc<RequestBuffer>,
+    range: Range<usize>,
+}
+
+impl WriteData {
+    pub(crate) fn new(buffer: Arc<RequestBuffer>, range: Range<usize>) -> WriteData {
+        WriteData { buffer, range }
+    }
+}
+
+impl Deref for WriteData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for WriteData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for WriteData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "WriteData({} bytes)", self.range.len())
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<WriteData> for bytes::Bytes {
+    fn from(data: WriteData) -> bytes::Bytes {
+        bytes::Bytes::from_owner(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufferPool, WriteData};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn pool_is_bounded() {
+        let pool = BufferPool::new(2);
+        let mut first = pool.get();
+        first.as_mut_vec().extend_from_slice(b"hello world");
+        let first = Arc::new(first);
+        let data = WriteData::new(first.clone(), 6..11);
+        drop(first);
+        let _second = pool.get();
+        // Both buffers are in use until the write data is dropped
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().len())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        assert_eq!(&*data, b"world");
+        drop(data);
+        // The returned buffer is handed out again instead of allocating another one
+        assert_eq!(waiter.join().unwrap(), 11);
+    }
+}
@@ -8,9 +8,29 @@ use std::ffi::{CStr, CString, OsStr};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 
+#[cfg(target_os = "linux")]
+use crate::reply;
 use crate::reply::ReplySender;
+#[cfg(target_os = "linux")]
+use crate::splice::{self, Pipe};
+#[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+use crate::uring::EntryRef;
+
+/// Helper function to provide options as a fuse_args struct
+/// (which contains an argc count and an argv pointer)
+#[cfg(not(target_os = "android"))]
+fn with_fuse_args<T, F: FnOnce(&fuse_sys::fuse_args) -> T>(options: &[&OsStr], f: F) -> T {
+    let mut args = vec![CString::new("rust-fuse").unwrap()];
+    args.extend(options.iter().map(|s| CString::new(s.as_bytes()).unwrap()));
+    let argptrs: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
+    f(&fuse_sys::fuse_args {
+        argc: argptrs.len() as i32,
+        argv: argptrs.as_ptr(),
+        allocated: 0,
+    })
+}
 
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
@@ -18,6 +38,7 @@ pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
     skip_uninit: bool,
+    unmount_on_drop: bool,
 }
 
 impl Channel {
@@ -28,20 +49,6 @@ impl Channel {
     #[cfg(not(target_os = "android"))]
     pub fn new(mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
         use fuse_sys::fuse_mount_compat25;
-        use fuse_sys::fuse_args;
-
-        /// Helper function to provide options as a fuse_args struct
-        /// (which contains an argc count and an argv pointer)
-        fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T {
-            let mut args = vec![CString::new("rust-fuse").unwrap()];
-            args.extend(options.iter().map(|s| CString::new(s.as_bytes()).unwrap()));
-            let argptrs: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
-            f(&fuse_args {
-                argc: argptrs.len() as i32,
-                argv: argptrs.as_ptr(),
-                allocated: 0,
-            })
-        }
 
         let mountpoint = mountpoint.canonicalize()?;
         with_fuse_args(options, |args| {
@@ -50,7 +57,7 @@ impl Channel {
             if fd < 0 {
                 Err(io::Error::last_os_error())
             } else {
-                Ok(Channel { skip_uninit: false, mountpoint, fd })
+                Ok(Channel { skip_uninit: false, unmount_on_drop: true, mountpoint, fd })
             }
         })
     }
@@ -67,7 +74,27 @@ impl Channel {
         if fd < 0 {
             Err(io::Error::last_os_error())
         } else {
-            Ok(Channel { skip_uninit: false, mountpoint, fd })
+            Ok(Channel { skip_uninit: false, unmount_on_drop: true, mountpoint, fd })
+        }
+    }
+
+    /// Create a communication channel from a FUSE device fd of a filesystem that was
+    /// mounted elsewhere, e.g. by a privileged helper that passed the fd over a unix
+    /// socket (`SCM_RIGHTS`). The given mountpoint is only used to unmount and isn't
+    /// required to exist in this process' mount namespace. If `unmount_on_drop` is false,
+    /// dropping the channel only closes the fd and leaves the filesystem mounted.
+    ///
+    /// # Safety
+    ///
+    /// The fd needs to be an open FUSE device fd of a mounted filesystem. The channel takes
+    /// ownership of it and closes it when dropped, so it must not be used or closed
+    /// elsewhere.
+    pub unsafe fn from_fd(fd: RawFd, mountpoint: &Path, unmount_on_drop: bool) -> Channel {
+        Channel {
+            mountpoint: mountpoint.to_path_buf(),
+            fd,
+            skip_uninit: false,
+            unmount_on_drop,
         }
     }
 
@@ -76,6 +103,72 @@ impl Channel {
         &self.mountpoint
     }
 
+    /// Returns true if dropping the channel unmounts the filesystem
+    #[cfg(feature = "handover")]
+    pub(crate) fn unmount_on_drop(&self) -> bool {
+        self.unmount_on_drop
+    }
+
+    /// Set whether dropping the channel unmounts the filesystem
+    #[cfg(feature = "handover")]
+    pub(crate) fn set_unmount_on_drop(&mut self, unmount_on_drop: bool) {
+        self.unmount_on_drop = unmount_on_drop;
+    }
+
+    /// Create another channel to the same mounted filesystem by cloning the FUSE
+    /// device fd. The kernel driver distributes requests across all clones, and each
+    /// request needs to be answered on the channel it was received from. Dropping
+    /// the clone closes its fd but doesn't unmount the filesystem.
+    #[cfg(target_os = "linux")]
+    pub fn clone_fd(&self) -> io::Result<Channel> {
+        use fuse_abi::consts::FUSE_DEV_IOC_CLONE;
+
+        let path = CString::new("/dev/fuse")?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut master = self.fd as u32;
+        let rc = unsafe { libc::ioctl(fd, FUSE_DEV_IOC_CLONE as _, &mut master as *mut u32) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err);
+        }
+        Ok(Channel {
+            mountpoint: self.mountpoint.clone(),
+            fd,
+            skip_uninit: false,
+            unmount_on_drop: false,
+        })
+    }
+
+    /// Cloning the FUSE device fd is only supported on Linux
+    #[cfg(not(target_os = "linux"))]
+    pub fn clone_fd(&self) -> io::Result<Channel> {
+        Err(io::Error::new(io::ErrorKind::Other, "cloning the FUSE device fd is not supported"))
+    }
+
+    /// Switch the channel to non-blocking mode. Receiving from a non-blocking channel
+    /// fails with EAGAIN if there's no request available.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     /// Receives data up to the capacity of the given buffer (can block).
     pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
         let rc = unsafe {
@@ -95,6 +188,13 @@ impl Channel {
         }
     }
 
+    /// Receives a request by splicing it into the given pipe and reading it from there into
+    /// the given buffer (can block). The pipe needs to hold the capacity of the buffer.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn receive_splice(&self, pipe: &Pipe, buffer: &mut Vec<u8>) -> io::Result<()> {
+        splice::receive(self.fd, pipe, buffer)
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
@@ -103,7 +203,26 @@ impl Channel {
         // a sender by using the same fd and use it in other threads. Only
         // the channel closes the fd when dropped. If any sender is used after
         // dropping the channel, it'll return an EBADF error.
-        ChannelSender { fd: self.fd }
+        ChannelSender {
+            fd: self.fd,
+            #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+            entry: None,
+        }
+    }
+
+    /// Returns a sender object that commits the reply to the given io_uring entry
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    pub(crate) fn uring_sender(&self, entry: EntryRef) -> ChannelSender {
+        ChannelSender {
+            fd: self.fd,
+            entry: Some(entry),
+        }
+    }
+}
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
     }
 }
 
@@ -126,19 +245,30 @@ impl Drop for Channel {
         unsafe {
             libc::close(self.fd);
         }
-        // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        // Unmount this channel's mount point (unless it's a clone)
+        if self.unmount_on_drop {
+            let _ = unmount(&self.mountpoint);
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct ChannelSender {
     fd: c_int,
+    /// Entry of an io_uring the request was received through
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    entry: Option<EntryRef>,
 }
 
 impl ChannelSender {
     /// Send all data in the slice of slice of bytes in a single write (can block).
     pub fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+        {
+            if let Some(entry) = &self.entry {
+                return entry.commit(buffer);
+            }
+        }
         let iovecs: Vec<_> = buffer
             .iter()
             .map(|d| libc::iovec {
@@ -161,6 +291,23 @@ impl ReplySender for ChannelSender {
             error!("Failed to send FUSE reply: {}", err);
         }
     }
+
+    /// Splices the data to the FUSE device if possible, copies it otherwise
+    #[cfg(target_os = "linux")]
+    fn send_fd(&self, unique: u64, fd: RawFd, offset: i64, len: usize) -> io::Result<usize> {
+        // Replies to requests received through io_uring need to be committed to the entry
+        #[cfg(all(feature = "uring", feature = "abi-7-42"))]
+        let spliced = match self.entry {
+            None => splice::send_reply(self.fd, unique, fd, offset, len)?,
+            Some(_) => None,
+        };
+        #[cfg(not(all(feature = "uring", feature = "abi-7-42")))]
+        let spliced = splice::send_reply(self.fd, unique, fd, offset, len)?;
+        match spliced {
+            Some(sent) => Ok(sent),
+            None => reply::send_fd_copy(self, unique, fd, offset, len),
+        }
+    }
 }
 
 /// Unmount an arbitrary mount point
@@ -222,7 +369,7 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "android")))]
 mod test {
     use super::with_fuse_args;
     use std::ffi::{CStr, OsStr};
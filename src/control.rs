@@ -0,0 +1,629 @@
+//! Control socket of a running session
+//!
+//! A session can serve a unix socket to inspect and control it while it's running (see
+//! `Session::serve_control`), e.g. to find out which requests a stuck mount is waiting for.
+//! Clients send requests as JSON objects, one per line, and get a JSON object per request
+//! in return, e.g.:
+//!
+//! ```text
+//! {"command":"in_flight"}
+//! {"result":"in_flight","requests":[{"unique":42,"opcode":15,"nodeid":7,"pid":1234,...}]}
+//! {"command":"invalidate_entry","parent":1,"name":"foo"}
+//! {"result":"ok"}
+//! ```
+//!
+//! Requests are limited to 64 KiB and a socket serves up to 16 clients at a time.
+//! Invalidating kernel caches needs the `abi-7-12` feature.
+//! `ControlClient` implements the client side of the protocol.
+
+use fuse_abi::fuse_opcode;
+#[cfg(feature = "abi-7-12")]
+use fuse_abi::fuse_out_header;
+#[cfg(feature = "abi-7-12")]
+use fuse_abi::{fuse_notify_code, fuse_notify_inval_entry_out, fuse_notify_inval_inode_out};
+use libc::EINTR;
+use log::{info, warn, LevelFilter};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+#[cfg(feature = "abi-7-12")]
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(feature = "abi-7-12")]
+use std::mem;
+#[cfg(feature = "abi-7-12")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "abi-7-12")]
+use crate::channel::Channel;
+#[cfg(feature = "abi-7-12")]
+use crate::reply::as_bytes;
+use crate::session::WeakSessionHandle;
+use crate::stats::SessionStats;
+
+/// Time after which idle control threads check whether the session ended
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Max size of a request line in bytes. Connections sending longer lines are closed.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Max number of connections a control socket serves at a time. Further connections are
+/// answered with an error and closed.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Request to a control socket
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// List the requests the filesystem didn't reply to yet
+    InFlight,
+    /// Return the statistics of the session
+    Stats,
+    /// Set the maximum log level of the process. The logger may filter messages by its own
+    /// level as well.
+    SetLogLevel {
+        /// Name of the level: `off`, `error`, `warn`, `info`, `debug` or `trace`
+        level: String,
+    },
+    /// Invalidate the cached attributes and the given range of cached data of an inode
+    #[cfg(feature = "abi-7-12")]
+    InvalidateInode {
+        /// Inode to invalidate
+        ino: u64,
+        /// Start of the data to invalidate, none if negative
+        offset: i64,
+        /// Length of the data to invalidate, up to the end of the file if 0
+        len: i64,
+    },
+    /// Invalidate the cached lookup of the given name in a directory
+    #[cfg(feature = "abi-7-12")]
+    InvalidateEntry {
+        /// Inode of the directory
+        parent: u64,
+        /// Name of the entry. Names that aren't valid UTF-8 are sent as an array of bytes.
+        #[serde(with = "name")]
+        name: OsString,
+    },
+    /// Unmount the filesystem and wait until the session ended
+    Unmount,
+}
+
+/// Response of a control socket
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// The request succeeded
+    Ok,
+    /// Requests in flight
+    InFlight {
+        /// Requests the filesystem didn't reply to yet, oldest first
+        requests: Vec<InFlightRequest>,
+    },
+    /// Statistics of the session
+    Stats {
+        /// Statistics since the session was created
+        stats: SessionStats,
+    },
+    /// The request failed
+    Error {
+        /// Why the request failed
+        message: String,
+    },
+}
+
+/// Request the filesystem didn't reply to yet
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InFlightRequest {
+    /// Unique identifier of the request
+    pub unique: u64,
+    /// Opcode of the request
+    pub opcode: u32,
+    /// Inode the request refers to
+    pub nodeid: u64,
+    /// Process that caused the request
+    pub pid: u32,
+    /// Time since the request was received
+    pub age: Duration,
+}
+
+impl InFlightRequest {
+    /// Return the operation of the request, None if the opcode is unknown
+    pub fn operation(&self) -> Option<fuse_opcode> {
+        fuse_opcode::try_from(self.opcode).ok()
+    }
+}
+
+/// Client of a session's control socket
+#[derive(Debug)]
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    /// Connect to the control socket at the given path
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<ControlClient> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(ControlClient { reader, writer })
+    }
+
+    /// Send the given request and return the response
+    pub fn request(&mut self, request: &ControlRequest) -> io::Result<ControlResponse> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(serde_json::from_slice(&line)?)
+    }
+
+    /// Return the requests the filesystem didn't reply to yet, oldest first
+    pub fn in_flight(&mut self) -> io::Result<Vec<InFlightRequest>> {
+        match self.request(&ControlRequest::InFlight)? {
+            ControlResponse::InFlight { requests } => Ok(requests),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Return the statistics of the session
+    pub fn stats(&mut self) -> io::Result<SessionStats> {
+        match self.request(&ControlRequest::Stats)? {
+            ControlResponse::Stats { stats } => Ok(stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Set the maximum log level of the serving process
+    pub fn set_log_level(&mut self, level: LevelFilter) -> io::Result<()> {
+        let level = level.to_string();
+        self.expect_ok(&ControlRequest::SetLogLevel { level })
+    }
+
+    /// Invalidate the cached attributes and data of an inode (see
+    /// `ControlRequest::InvalidateInode`)
+    #[cfg(feature = "abi-7-12")]
+    pub fn invalidate_inode(&mut self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        self.expect_ok(&ControlRequest::InvalidateInode { ino, offset, len })
+    }
+
+    /// Invalidate the cached lookup of the given name in a directory
+    #[cfg(feature = "abi-7-12")]
+    pub fn invalidate_entry(&mut self, parent: u64, name: &OsStr) -> io::Result<()> {
+        let name = name.to_os_string();
+        self.expect_ok(&ControlRequest::InvalidateEntry { parent, name })
+    }
+
+    /// Unmount the filesystem and wait until the session ended
+    pub fn unmount(&mut self) -> io::Result<()> {
+        self.expect_ok(&ControlRequest::Unmount)
+    }
+
+    /// Send the given request and fail unless it succeeded
+    fn expect_ok(&mut self, request: &ControlRequest) -> io::Result<()> {
+        match self.request(request)? {
+            ControlResponse::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Return the error of an unexpected response
+fn unexpected(response: ControlResponse) -> io::Error {
+    match response {
+        ControlResponse::Error { message } => io::Error::other(message),
+        response => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response {:?}", response),
+        ),
+    }
+}
+
+/// Serializes names as strings if they're valid UTF-8 and as arrays of bytes otherwise, and
+/// accepts both
+#[cfg(feature = "abi-7-12")]
+mod name {
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::ffi::{OsStr, OsString};
+    use std::fmt;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    pub(super) fn serialize<S: Serializer>(name: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+        match name.to_str() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.collect_seq(name.as_bytes()),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OsString, D::Error> {
+        deserializer.deserialize_any(NameVisitor)
+    }
+
+    struct NameVisitor;
+
+    impl<'de> Visitor<'de> for NameVisitor {
+        type Value = OsString;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<OsString, E> {
+            Ok(OsString::from(name))
+        }
+
+        fn visit_bytes<E: de::Error>(self, name: &[u8]) -> Result<OsString, E> {
+            Ok(OsString::from_vec(name.to_vec()))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OsString, A::Error> {
+            let mut name = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                name.push(byte);
+            }
+            Ok(OsString::from_vec(name))
+        }
+    }
+}
+
+/// Control server of a session, shared by its connections
+struct Control {
+    session: WeakSessionHandle,
+    /// Channel to send notifications to the kernel with (a duplicate of the session's FUSE
+    /// device fd)
+    #[cfg(feature = "abi-7-12")]
+    ch: Channel,
+    /// Number of connections being served
+    connections: AtomicUsize,
+}
+
+/// Serve control connections on the given listener in a background thread until the session
+/// ended. Notifications are sent to the kernel through the given channel.
+pub(crate) fn serve(
+    listener: UnixListener,
+    session: WeakSessionHandle,
+    #[cfg(feature = "abi-7-12")] ch: Channel,
+) -> io::Result<()> {
+    let control = Arc::new(Control {
+        session,
+        #[cfg(feature = "abi-7-12")]
+        ch,
+        connections: AtomicUsize::new(0),
+    });
+    thread::Builder::new()
+        .name("fuse-control".to_string())
+        .spawn(move || {
+            while control.session.upgrade().is_some() {
+                let mut fds = [libc::pollfd {
+                    fd: listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                }];
+                let timeout = IDLE_TIMEOUT.as_millis() as libc::c_int;
+                if unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) } < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() == Some(EINTR) {
+                        continue;
+                    }
+                    warn!("Failed to wait for control connections: {}", err);
+                    break;
+                }
+                if fds[0].revents == 0 {
+                    continue;
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => control.clone().accept(stream),
+                    Err(err) => warn!("Failed to accept control connection: {}", err),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+impl Control {
+    /// Serve the given connection in a thread of its own, unless too many are served already
+    fn accept(self: Arc<Control>, mut stream: UnixStream) {
+        if self.connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            self.connections.fetch_sub(1, Ordering::AcqRel);
+            warn!("Too many control connections, closing a new one");
+            let response = ControlResponse::Error {
+                message: "too many connections".to_string(),
+            };
+            let _ = send(&mut stream, &response);
+            return;
+        }
+        let control = self.clone();
+        let res = thread::Builder::new()
+            .name("fuse-control".to_string())
+            .spawn(move || {
+                control.serve_connection(stream);
+                control.connections.fetch_sub(1, Ordering::AcqRel);
+            });
+        if let Err(err) = res {
+            self.connections.fetch_sub(1, Ordering::AcqRel);
+            warn!("Failed to start control connection thread: {}", err);
+        }
+    }
+
+    /// Answer requests on the given connection until it's closed or the session ended
+    fn serve_connection(&self, stream: UnixStream) {
+        if let Err(err) = self.try_serve_connection(stream) {
+            warn!("Control connection failed: {}", err);
+        }
+    }
+
+    fn try_serve_connection(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = Vec::new();
+        loop {
+            // Bytes read before a timeout stay in the line
+            let limit = MAX_LINE.saturating_sub(line.len()) as u64;
+            match (&mut reader).take(limit).read_until(b'\n', &mut line) {
+                Ok(_) if line.len() >= MAX_LINE && line.last() != Some(&b'\n') => {
+                    let response = ControlResponse::Error {
+                        message: format!("request longer than {} bytes", MAX_LINE),
+                    };
+                    return send(&mut stream, &response);
+                }
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(err) if is_timeout(&err) => {
+                    if self.session.upgrade().is_none() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+            let response = match serde_json::from_slice(&line) {
+                Ok(request) => self.handle(request),
+                Err(err) => ControlResponse::Error {
+                    message: format!("invalid request: {}", err),
+                },
+            };
+            line.clear();
+            send(&mut stream, &response)?;
+        }
+    }
+
+    /// Carry out the given request
+    fn handle(&self, request: ControlRequest) -> ControlResponse {
+        let session = match self.session.upgrade() {
+            Some(session) => session,
+            None => {
+                return ControlResponse::Error {
+                    message: "session ended".to_string(),
+                }
+            }
+        };
+        let res = match request {
+            ControlRequest::InFlight => {
+                let requests = session.in_flight();
+                return ControlResponse::InFlight { requests };
+            }
+            ControlRequest::Stats => {
+                let stats = session.stats();
+                return ControlResponse::Stats { stats };
+            }
+            ControlRequest::SetLogLevel { level } => level
+                .parse::<LevelFilter>()
+                .map(|level| {
+                    info!("Setting log level to {}", level);
+                    log::set_max_level(level);
+                })
+                .map_err(|_| io::Error::other(format!("invalid log level {:?}", level))),
+            #[cfg(feature = "abi-7-12")]
+            ControlRequest::InvalidateInode { ino, offset, len } => {
+                invalidate_inode(&self.ch, ino, offset, len)
+            }
+            #[cfg(feature = "abi-7-12")]
+            ControlRequest::InvalidateEntry { parent, name } => {
+                invalidate_entry(&self.ch, parent, &name)
+            }
+            ControlRequest::Unmount => session.shutdown(),
+        };
+        match res {
+            Ok(()) => ControlResponse::Ok,
+            Err(err) => ControlResponse::Error {
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Send the given response on a connection
+fn send(stream: &mut UnixStream, response: &ControlResponse) -> io::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+/// Return true if the given error is a timeout of a read
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Send a notification with the given code and data to the kernel
+#[cfg(feature = "abi-7-12")]
+fn notify(ch: &Channel, code: fuse_notify_code, data: &[&[u8]]) -> io::Result<()> {
+    let len = data.iter().map(|bytes| bytes.len()).sum::<usize>();
+    let header = fuse_out_header {
+        len: (mem::size_of::<fuse_out_header>() + len) as u32,
+        error: code as i32,
+        unique: 0,
+    };
+    as_bytes(&header, |header| {
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(data);
+        ch.sender().send(&bytes)
+    })
+}
+
+/// Ask the kernel to invalidate the cached attributes and data of an inode
+#[cfg(feature = "abi-7-12")]
+fn invalidate_inode(ch: &Channel, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+    let out = fuse_notify_inval_inode_out {
+        ino,
+        off: offset,
+        len,
+    };
+    as_bytes(&out, |out| {
+        notify(ch, fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, out)
+    })
+}
+
+/// Ask the kernel to invalidate the cached lookup of a name in a directory
+#[cfg(feature = "abi-7-12")]
+fn invalidate_entry(ch: &Channel, parent: u64, name: &OsStr) -> io::Result<()> {
+    let out = fuse_notify_inval_entry_out {
+        parent,
+        namelen: name.len() as u32,
+        #[cfg(not(feature = "abi-7-38"))]
+        padding: 0,
+        #[cfg(feature = "abi-7-38")]
+        flags: 0,
+    };
+    as_bytes(&out, |out| {
+        let mut data = out.to_vec();
+        data.extend_from_slice(&[name.as_bytes(), &[0]]);
+        notify(ch, fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY, &data)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "abi-7-12")]
+    use super::invalidate_entry;
+    use super::{ControlClient, ControlRequest, ControlResponse, MAX_CONNECTIONS, MAX_LINE};
+    use crate::session::Session;
+    use crate::test_util::channel;
+    use crate::Filesystem;
+    #[cfg(feature = "abi-7-12")]
+    use fuse_abi::fuse_notify_code;
+    #[cfg(feature = "abi-7-12")]
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    #[cfg(feature = "abi-7-12")]
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+
+    struct NullFilesystem;
+
+    impl Filesystem for NullFilesystem {}
+
+    /// Serve the control socket of a session at a new path in the temporary directory
+    fn serve(name: &str) -> (Session<NullFilesystem>, PathBuf) {
+        let file = format!("rust-fuse-control-{}-{}", name, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (ch, _kernel) = channel();
+        let mut se = Session::with_channel(NullFilesystem, ch);
+        se.serve_control(listener).unwrap();
+        (se, path)
+    }
+
+    #[test]
+    fn protocol() {
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(r#"{"command":"in_flight"}"#).unwrap(),
+            ControlRequest::InFlight
+        );
+        let response = ControlResponse::Error {
+            message: "session ended".to_string(),
+        };
+        let line = r#"{"result":"error","message":"session ended"}"#;
+        assert_eq!(serde_json::to_string(&response).unwrap(), line);
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    #[test]
+    fn entry_names() {
+        let request = ControlRequest::InvalidateEntry {
+            parent: 1,
+            name: "foo".into(),
+        };
+        let line = r#"{"command":"invalidate_entry","parent":1,"name":"foo"}"#;
+        assert_eq!(serde_json::to_string(&request).unwrap(), line);
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(line).unwrap(),
+            request
+        );
+
+        // Names that aren't valid UTF-8 are arrays of bytes
+        let request = ControlRequest::InvalidateEntry {
+            parent: 1,
+            name: OsStr::from_bytes(b"f\xff").into(),
+        };
+        let line = r#"{"command":"invalidate_entry","parent":1,"name":[102,255]}"#;
+        assert_eq!(serde_json::to_string(&request).unwrap(), line);
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(line).unwrap(),
+            request
+        );
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    #[test]
+    fn notifies_entry_invalidation() {
+        let (ch, mut kernel) = channel();
+        invalidate_entry(&ch, 7, OsStr::new("foo")).unwrap();
+
+        let mut notification = [0; 36];
+        kernel.read_exact(&mut notification).unwrap();
+        let code = fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32;
+        assert_eq!(&notification[0..4], &36u32.to_ne_bytes());
+        assert_eq!(&notification[4..8], &code.to_ne_bytes());
+        assert_eq!(&notification[8..16], &0u64.to_ne_bytes());
+        assert_eq!(&notification[16..24], &7u64.to_ne_bytes());
+        assert_eq!(&notification[24..28], &3u32.to_ne_bytes());
+        assert_eq!(&notification[32..36], b"foo\0");
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let (_se, path) = serve("line");
+        let mut stream = UnixStream::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        stream.write_all(&vec![b' '; MAX_LINE + 1]).unwrap();
+        // The connection is reset after the response, since the last byte wasn't read
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        let expected = format!("request longer than {} bytes", MAX_LINE);
+        let response: ControlResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response, ControlResponse::Error { message: expected });
+    }
+
+    #[test]
+    fn limits_connections() {
+        let (_se, path) = serve("connections");
+        let mut clients = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            let mut client = ControlClient::connect(&path).unwrap();
+            client.stats().unwrap();
+            clients.push(client);
+        }
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let response: ControlResponse = serde_json::from_str(&response).unwrap();
+        let message = "too many connections".to_string();
+        assert_eq!(response, ControlResponse::Error { message });
+    }
+}
@@ -0,0 +1,538 @@
+//! Request deadlines
+//!
+//! If the filesystem doesn't reply to a request, the process that triggered it hangs until
+//! the reply is sent, or until the reply object is dropped, which replies EIO. With deadlines,
+//! a watchdog thread replies with an error (ETIMEDOUT by default) to requests the filesystem
+//! didn't reply to in time, so that the process can go on. A later reply of the filesystem is
+//! suppressed and logged. The filesystem can check the time left to reply to a request with
+//! `Request::remaining_time`. Deadlines start when a request is received, so time a request
+//! was deferred by the scheduler counts as well.
+//!
+//! A suppressed reply never reaches the kernel, so neither do the inodes and file handles it
+//! hands out. The kernel won't forget or release them, which the filesystem can do itself
+//! when notified of them (see `Deadlines::on_suppressed`).
+
+use fuse_abi::fuse_opcode;
+use libc::{c_int, ETIMEDOUT};
+use log::{error, warn};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::channel::ChannelSender;
+use crate::reply::{Reply, ReplyEmpty};
+use crate::request::Request;
+use crate::scheduler::Permit;
+use crate::stats::RequestStats;
+
+/// Max time the watchdog thread waits before checking whether its session ended
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Min number of requests the watchdog waits for before it drops those replied to already
+const MIN_PRUNE: usize = 64;
+
+/// Size of `fuse_open_out`, which ends replies to open, opendir and create requests
+const OPEN_OUT_SIZE: usize = 16;
+
+/// Inode or file handle that a reply suppressed after the request's deadline would have handed
+/// to the kernel. The kernel doesn't know about it, so the filesystem needs to forget or
+/// release it itself.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SuppressedReply {
+    /// Reply to a lookup, mknod, mkdir, symlink or link request, which would have increased
+    /// the lookup count of the inode by one
+    Entry {
+        /// Inode of the entry
+        ino: u64,
+    },
+    /// Reply to a create request, which would have increased the lookup count of the inode
+    /// by one and opened the file handle
+    Create {
+        /// Inode of the created file
+        ino: u64,
+        /// File handle of the opened file
+        fh: u64,
+    },
+    /// Reply to an open request with the file handle
+    Open {
+        /// Inode of the file
+        ino: u64,
+        /// File handle of the opened file
+        fh: u64,
+    },
+    /// Reply to an opendir request with the file handle
+    OpenDir {
+        /// Inode of the directory
+        ino: u64,
+        /// File handle of the opened directory
+        fh: u64,
+    },
+}
+
+impl SuppressedReply {
+    /// Return what the successful reply with the given payload to a request with the given
+    /// opcode for the given inode hands out, if anything
+    fn new(opcode: u32, ino: u64, payload: &[u8]) -> Option<SuppressedReply> {
+        let u64_at = |offset: usize| -> Option<u64> {
+            let bytes = payload.get(offset..offset.checked_add(8)?)?;
+            Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+        };
+        let fh = || u64_at(payload.len().checked_sub(OPEN_OUT_SIZE)?);
+        match fuse_opcode::try_from(opcode).ok()? {
+            fuse_opcode::FUSE_LOOKUP
+            | fuse_opcode::FUSE_MKNOD
+            | fuse_opcode::FUSE_MKDIR
+            | fuse_opcode::FUSE_SYMLINK
+            | fuse_opcode::FUSE_LINK => {
+                // A lookup reply with inode 0 is a negative entry
+                let ino = u64_at(0).filter(|&ino| ino != 0)?;
+                Some(SuppressedReply::Entry { ino })
+            }
+            fuse_opcode::FUSE_CREATE => Some(SuppressedReply::Create {
+                ino: u64_at(0)?,
+                fh: fh()?,
+            }),
+            fuse_opcode::FUSE_OPEN => Some(SuppressedReply::Open { ino, fh: fh()? }),
+            fuse_opcode::FUSE_OPENDIR => Some(SuppressedReply::OpenDir { ino, fh: fh()? }),
+            _ => None,
+        }
+    }
+}
+
+/// Callback for suppressed replies
+#[derive(Clone)]
+pub(crate) struct SuppressedHandler(Arc<dyn Fn(&SuppressedReply) + Send + Sync>);
+
+impl fmt::Debug for SuppressedHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "SuppressedHandler")
+    }
+}
+
+/// Time the filesystem has to reply to requests (see `Session::set_deadlines`)
+#[derive(Clone, Debug)]
+pub struct Deadlines {
+    /// Time to reply to requests whose opcode has no deadline of its own
+    default: Option<Duration>,
+    /// Time to reply to requests per opcode
+    opcodes: HashMap<u32, Duration>,
+    /// Error to reply with after the deadline passed
+    errno: c_int,
+    /// Callback for suppressed replies that handed out inodes or file handles
+    suppressed: Option<SuppressedHandler>,
+}
+
+impl Deadlines {
+    /// Create deadlines that give the filesystem the given time to reply to any request, or
+    /// no deadline unless one is set for the request's opcode. Requests without a reply
+    /// (forget) and init, destroy, interrupt and notify reply requests have no deadline.
+    pub fn new(default: Option<Duration>) -> Deadlines {
+        Deadlines {
+            default,
+            opcodes: HashMap::new(),
+            errno: ETIMEDOUT,
+            suppressed: None,
+        }
+    }
+
+    /// Give the filesystem the given time to reply to requests with the given opcode
+    pub fn set(&mut self, opcode: fuse_opcode, timeout: Duration) {
+        self.opcodes.insert(opcode as u32, timeout);
+    }
+
+    /// Reply with the given error to requests past their deadline (ETIMEDOUT by default,
+    /// EIO is understood by more applications)
+    pub fn set_error(&mut self, errno: c_int) {
+        self.errno = errno;
+    }
+
+    /// Call the given handler with the inode or file handle of every successful reply to a
+    /// lookup, mknod, mkdir, symlink, link, create, open or opendir request that is suppressed
+    /// because its deadline passed, so that the filesystem can forget or release it. Called
+    /// by the thread sending the reply.
+    pub fn on_suppressed<F: Fn(&SuppressedReply) + Send + Sync + 'static>(&mut self, handler: F) {
+        self.suppressed = Some(SuppressedHandler(Arc::new(handler)));
+    }
+
+    /// Return the time to reply to requests with the given opcode
+    fn timeout(&self, opcode: u32) -> Option<Duration> {
+        self.opcodes.get(&opcode).copied().or(self.default)
+    }
+}
+
+/// Deadline of a request, shared by its reply senders and the watchdog
+pub(crate) struct Deadline {
+    /// Unique identifier of the request
+    unique: u64,
+    /// Inode the request is for
+    ino: u64,
+    /// Sender for sending the reply
+    ch: ChannelSender,
+    /// When the watchdog replies
+    at: Instant,
+    /// True once the request was replied to, by the filesystem or the watchdog
+    replied: AtomicBool,
+    /// Slot of the scheduler the request was admitted with, released by the watchdog's reply
+    permit: Option<Arc<Permit>>,
+    /// Counts the watchdog's reply in the statistics of the session
+    stats: Option<RequestStats>,
+    /// Span of the request, records the watchdog's reply
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    /// Callback for a suppressed reply of the filesystem
+    suppressed: Option<SuppressedHandler>,
+}
+
+impl Deadline {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        unique: u64,
+        ino: u64,
+        ch: ChannelSender,
+        at: Instant,
+        permit: Option<Arc<Permit>>,
+        stats: Option<RequestStats>,
+        #[cfg(feature = "tracing")] span: tracing::Span,
+        suppressed: Option<SuppressedHandler>,
+    ) -> Deadline {
+        Deadline {
+            unique,
+            ino,
+            ch,
+            at,
+            replied: AtomicBool::new(false),
+            permit,
+            stats,
+            suppressed,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    /// Returns the unique identifier of the request
+    pub(crate) fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Returns when the watchdog replies
+    pub(crate) fn at(&self) -> Instant {
+        self.at
+    }
+
+    /// Mark the request as replied to. Returns false if it was replied to before, i.e. the
+    /// watchdog replied since the deadline passed.
+    pub(crate) fn reply(&self) -> bool {
+        !self.replied.swap(true, atomic::Ordering::AcqRel)
+    }
+
+    /// Report the inode or file handle the given successful reply of the filesystem to a
+    /// request with the given opcode handed out, since it was suppressed
+    pub(crate) fn suppressed(&self, opcode: u32, data: &[&[u8]]) {
+        let handler = match &self.suppressed {
+            Some(SuppressedHandler(handler)) => handler,
+            None => return,
+        };
+        // The first part is the reply header
+        let payload = data[data.len().min(1)..].concat();
+        if let Some(reply) = SuppressedReply::new(opcode, self.ino, &payload) {
+            handler(&reply);
+        }
+    }
+
+    /// Reply with the given error unless the filesystem replied already
+    fn expire(&self, errno: c_int) {
+        if !self.reply() {
+            return;
+        }
+        warn!(
+            "Request {} passed its deadline, replying with error {}",
+            self.unique, errno
+        );
+        let reply: ReplyEmpty = Reply::new(self.unique, self.ch.clone());
+        reply.error(errno);
+        if let Some(stats) = &self.stats {
+            stats.reply(errno, None);
+        }
+        #[cfg(feature = "tracing")]
+        self.span.record("errno", errno);
+        if let Some(permit) = &self.permit {
+            permit.release();
+        }
+    }
+}
+
+impl fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Deadline({})", self.unique)
+    }
+}
+
+/// Request the watchdog waits for
+struct Entry {
+    /// When the watchdog replies
+    at: Instant,
+    /// Deadline of the request, gone once the request was replied to and dropped
+    deadline: Weak<Deadline>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        self.at.cmp(&other.at)
+    }
+}
+
+/// Requests of a watchdog
+#[derive(Default)]
+struct WatchdogState {
+    /// Requests waiting for their reply, earliest deadline first
+    entries: BinaryHeap<Reverse<Entry>>,
+    /// True once the watchdog thread was started
+    started: bool,
+    /// Number of requests at which those replied to already are dropped
+    prune_at: usize,
+}
+
+impl WatchdogState {
+    /// Drop the requests that were replied to already and whose deadline is gone, so that
+    /// requests replied to in time don't pile up until their deadline
+    fn prune(&mut self) {
+        let mut entries = mem::take(&mut self.entries).into_vec();
+        entries.retain(|Reverse(entry)| entry.deadline.strong_count() > 0);
+        self.entries = BinaryHeap::from(entries);
+        self.prune_at = (self.entries.len() * 2).max(MIN_PRUNE);
+    }
+
+    /// Take the deadlines that passed at the given time
+    fn expired(&mut self, now: Instant) -> Vec<Arc<Deadline>> {
+        let mut expired = Vec::new();
+        while let Some(Reverse(entry)) = self.entries.peek() {
+            if entry.at > now {
+                break;
+            }
+            if let Some(Reverse(entry)) = self.entries.pop() {
+                expired.extend(entry.deadline.upgrade());
+            }
+        }
+        expired
+    }
+}
+
+/// State of a watchdog shared with its thread
+struct WatchdogInner {
+    /// Deadlines of requests
+    deadlines: Deadlines,
+    /// Requests of the watchdog
+    state: Mutex<WatchdogState>,
+    /// Notified whenever a request with an earlier deadline than all others is added
+    changed: Condvar,
+}
+
+impl WatchdogInner {
+    fn lock(&self) -> MutexGuard<'_, WatchdogState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Watchdog that replies to requests past their deadline. Its thread is started for the
+/// first request with a deadline and ends once the watchdog is dropped.
+#[derive(Clone)]
+pub(crate) struct Watchdog {
+    inner: Arc<WatchdogInner>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(deadlines: Deadlines) -> Watchdog {
+        Watchdog {
+            inner: Arc::new(WatchdogInner {
+                deadlines,
+                state: Mutex::new(WatchdogState::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Set the deadline of the given request if it has one
+    pub(crate) fn watch(&self, req: &mut Request<'_>) {
+        // Requests without a class don't need a reply or are answered by the dispatcher
+        if req.class().is_none() {
+            return;
+        }
+        let timeout = match self.inner.deadlines.timeout(req.opcode()) {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let suppressed = self.inner.deadlines.suppressed.clone();
+        let deadline = req.set_deadline(timeout, suppressed);
+        let mut state = self.inner.lock();
+        if state.entries.len() >= state.prune_at.max(MIN_PRUNE) {
+            state.prune();
+        }
+        let earliest = match state.entries.peek() {
+            Some(Reverse(entry)) => deadline.at() < entry.at,
+            None => true,
+        };
+        state.entries.push(Reverse(Entry {
+            at: deadline.at(),
+            deadline: Arc::downgrade(&deadline),
+        }));
+        if !state.started {
+            state.started = true;
+            let inner = Arc::downgrade(&self.inner);
+            let res = thread::Builder::new()
+                .name("fuse-watchdog".to_string())
+                .spawn(move || run(inner));
+            if let Err(err) = res {
+                error!(
+                    "Failed to start watchdog thread, deadlines are ignored: {}",
+                    err
+                );
+            }
+        } else if earliest {
+            self.inner.changed.notify_one();
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Watchdog({:?})", self.inner.deadlines)
+    }
+}
+
+/// Run the watchdog thread until the watchdog is dropped
+fn run(inner: Weak<WatchdogInner>) {
+    while let Some(inner) = inner.upgrade() {
+        let expired = {
+            let state = inner.lock();
+            let timeout = state.entries.peek().map_or(IDLE_TIMEOUT, |Reverse(entry)| {
+                entry
+                    .at
+                    .saturating_duration_since(Instant::now())
+                    .min(IDLE_TIMEOUT)
+            });
+            let (mut state, _) = inner
+                .changed
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(|err| err.into_inner());
+            state.expired(Instant::now())
+        };
+        for deadline in expired {
+            deadline.expire(inner.deadlines.errno);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Deadlines, SuppressedReply, Watchdog, MIN_PRUNE};
+    use crate::request::{Request, UnimplementedOps};
+    use crate::test_util::{channel, RawRequest};
+    use fuse_abi::fuse_opcode;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timeout_per_opcode() {
+        let mut deadlines = Deadlines::new(None);
+        assert_eq!(deadlines.timeout(fuse_opcode::FUSE_LOOKUP as u32), None);
+        deadlines.set(fuse_opcode::FUSE_READ, Duration::from_secs(5));
+        let timeout = deadlines.timeout(fuse_opcode::FUSE_READ as u32);
+        assert_eq!(timeout, Some(Duration::from_secs(5)));
+        let mut deadlines = Deadlines::new(Some(Duration::from_secs(1)));
+        deadlines.set(fuse_opcode::FUSE_READ, Duration::from_secs(5));
+        let timeout = deadlines.timeout(fuse_opcode::FUSE_LOOKUP as u32);
+        assert_eq!(timeout, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn replies_after_deadline() {
+        let (ch, mut kernel) = channel();
+        let mut deadlines = Deadlines::new(None);
+        deadlines.set(fuse_opcode::FUSE_GETATTR, Duration::from_millis(10));
+        let watchdog = Watchdog::new(deadlines);
+
+        // GETATTR request that the filesystem doesn't reply to
+        let data = RawRequest::new(fuse_opcode::FUSE_GETATTR, 7, 1, &[]);
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        let mut req = Request::new(ch.sender(), data.as_bytes(), unimplemented).unwrap();
+        watchdog.watch(&mut req);
+        assert!(req.remaining_time().unwrap() <= Duration::from_millis(10));
+
+        let mut reply = [0; 16];
+        kernel.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[0..4], &16u32.to_ne_bytes());
+        assert_eq!(&reply[4..8], &(-libc::ETIMEDOUT).to_ne_bytes());
+        assert_eq!(&reply[8..16], &7u64.to_ne_bytes());
+        assert_eq!(req.remaining_time(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn deadline_starts_at_receipt() {
+        let (ch, _kernel) = channel();
+        let watchdog = Watchdog::new(Deadlines::new(Some(Duration::from_secs(60))));
+        let data = RawRequest::new(fuse_opcode::FUSE_GETATTR, 7, 1, &[]);
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        let mut req = Request::new(ch.sender(), data.as_bytes(), unimplemented).unwrap();
+        // The request was deferred for 30s before it's dispatched
+        req.set_received(Instant::now() - Duration::from_secs(30));
+        watchdog.watch(&mut req);
+        assert!(req.remaining_time().unwrap() <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn prunes_replied_requests() {
+        let (ch, _kernel) = channel();
+        let watchdog = Watchdog::new(Deadlines::new(Some(Duration::from_secs(60))));
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        for unique in 0..MIN_PRUNE as u64 * 2 {
+            let data = RawRequest::new(fuse_opcode::FUSE_GETATTR, unique, 1, &[]);
+            let mut req = Request::new(ch.sender(), data.as_bytes(), unimplemented.clone());
+            watchdog.watch(req.as_mut().unwrap());
+        }
+        assert!(watchdog.inner.lock().entries.len() <= MIN_PRUNE);
+    }
+
+    #[test]
+    fn suppressed_replies() {
+        let lookup = fuse_opcode::FUSE_LOOKUP as u32;
+        let entry = [5u64.to_ne_bytes(), [0; 8]].concat();
+        let reply = SuppressedReply::new(lookup, 1, &entry);
+        assert_eq!(reply, Some(SuppressedReply::Entry { ino: 5 }));
+        let negative = [0; 16];
+        assert_eq!(SuppressedReply::new(lookup, 1, &negative), None);
+
+        let create = fuse_opcode::FUSE_CREATE as u32;
+        let data = [5u64.to_ne_bytes(), [0; 8], 9u64.to_ne_bytes(), [0; 8]].concat();
+        let reply = SuppressedReply::new(create, 1, &data);
+        assert_eq!(reply, Some(SuppressedReply::Create { ino: 5, fh: 9 }));
+
+        let open = fuse_opcode::FUSE_OPEN as u32;
+        let data = [9u64.to_ne_bytes(), [0; 8]].concat();
+        let reply = SuppressedReply::new(open, 3, &data);
+        assert_eq!(reply, Some(SuppressedReply::Open { ino: 3, fh: 9 }));
+
+        let getattr = fuse_opcode::FUSE_GETATTR as u32;
+        assert_eq!(SuppressedReply::new(getattr, 1, &data), None);
+    }
+}
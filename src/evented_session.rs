@@ -0,0 +1,169 @@
+//! Evented filesystem session
+//!
+//! A session that doesn't run its own loop, but can be registered with a mio event loop.
+//! Whenever the session is readable, the caller receives requests until none are left and
+//! dispatches each of them to a filesystem explicitly.
+
+use libc::{EAGAIN, EINTR, ENOENT};
+use log::info;
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::channel::Channel;
+use crate::request::Request;
+use crate::session::{FuseSessionStore, RequestErrorCounters};
+use crate::unwind;
+use crate::Filesystem;
+
+/// Result of receiving from an evented session
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // requests are received in a loop, boxing would allocate
+pub enum RecvResult<'a> {
+    /// A request that needs to be dispatched (see `EventedSession::dispatch`)
+    Some(Request<'a>),
+    /// A request that failed to parse was answered with an error, receive the next one
+    Skipped,
+    /// No request available right now, wait until the session is readable again
+    Retry,
+    /// The filesystem was unmounted or the channel failed. The session should be
+    /// deregistered and dropped.
+    Drop(io::Error),
+}
+
+/// The evented session data structure
+#[derive(Debug)]
+pub struct EventedSession {
+    ch: Channel,
+    store: FuseSessionStore,
+}
+
+impl EventedSession {
+    /// Create a new evented session by mounting to the given mountpoint. Requests can't be
+    /// received before the session becomes readable.
+    pub fn new(mountpoint: &Path, options: &[&OsStr]) -> io::Result<EventedSession> {
+        info!("Mounting {}", mountpoint.display());
+        let ch = Channel::new(mountpoint, options)?;
+        ch.set_nonblocking(true)?;
+        Ok(EventedSession {
+            ch,
+            store: FuseSessionStore::new(),
+        })
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// Return the counters of requests that failed to parse
+    pub fn request_errors(&self) -> RequestErrorCounters {
+        self.store.request_errors
+    }
+
+    /// Receive the next request into the given buffer without blocking. The buffer's
+    /// capacity needs to be large enough for the largest request (`MAX_WRITE_SIZE` plus
+    /// 4k). Requests that fail to parse are answered right away (see `RecvResult::Skipped`).
+    pub fn recv<'a>(&mut self, buffer: &'a mut Vec<u8>) -> RecvResult<'a> {
+        loop {
+            if let Err(err) = self.ch.receive(buffer) {
+                match err.raw_os_error() {
+                    // No request available
+                    Some(EAGAIN) => return RecvResult::Retry,
+                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                    Some(ENOENT) => continue,
+                    // Interrupted system call, retry
+                    Some(EINTR) => continue,
+                    // Filesystem was unmounted (ENODEV) or unhandled error
+                    _ => return RecvResult::Drop(err),
+                }
+            }
+            break;
+        }
+        match Request::new(self.ch.sender(), buffer, self.store.unimplemented.clone()) {
+            Ok(req) => RecvResult::Some(req),
+            Err(err) => {
+                Request::reply_invalid(
+                    self.ch.sender(),
+                    buffer,
+                    &err,
+                    &mut self.store.request_errors,
+                );
+                RecvResult::Skipped
+            }
+        }
+    }
+
+    /// Dispatch a received request to the given filesystem. The same filesystem needs to
+    /// be used for all requests of a session.
+    pub fn dispatch<FS: Filesystem>(&mut self, mut request: Request<'_>, filesystem: &mut FS) {
+        unwind::dispatch(filesystem, &mut request, &mut self.store);
+    }
+}
+
+impl Evented for EventedSession {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.ch.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.ch.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.ch.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventedSession, RecvResult};
+    use crate::session::FuseSessionStore;
+    use crate::test_util::{channel, RawRequest};
+    use fuse_abi::fuse_opcode;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn recv_skips_invalid_requests() {
+        let (ch, mut kernel) = channel();
+        ch.set_nonblocking(true).unwrap();
+        let mut se = EventedSession {
+            ch,
+            store: FuseSessionStore::new(),
+        };
+        let mut buffer = Vec::with_capacity(4096);
+        assert!(matches!(se.recv(&mut buffer), RecvResult::Retry));
+
+        // A read request without its argument is answered with EIO
+        let read = RawRequest::new(fuse_opcode::FUSE_READ, 1, 1, &[]);
+        kernel.write_all(read.as_bytes()).unwrap();
+        assert!(matches!(se.recv(&mut buffer), RecvResult::Skipped));
+        let mut reply = [0; 16];
+        kernel.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[4..8], &(-libc::EIO).to_ne_bytes());
+        assert_eq!(se.request_errors().malformed, 1);
+
+        let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 2, 1, &[0; 16]);
+        kernel.write_all(getattr.as_bytes()).unwrap();
+        match se.recv(&mut buffer) {
+            RecvResult::Some(req) => assert_eq!(req.unique(), 2),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(se.recv(&mut buffer), RecvResult::Retry));
+    }
+}
@@ -0,0 +1,318 @@
+//! Live handover of a mounted filesystem
+//!
+//! A running session can hand its FUSE connection over to another process, e.g. to upgrade
+//! a filesystem daemon without unmounting it. The old process listens on a unix socket (see
+//! `Session::run_with_handover`). The new process connects to it (see `Handover::connect`),
+//! receives the FUSE device fd and the serialized session and filesystem state, and resumes
+//! serving the mount (see `Handover::into_session`).
+//!
+//! The handover message starts with a header of the protocol version and the payload
+//! length, sent together with the fd as `SCM_RIGHTS` ancillary data. The bincode encoded
+//! payload follows. The new process acknowledges the handover with a single byte, after
+//! which the old process stops serving. If the handover fails before that, the old process
+//! keeps serving the mount.
+
+use libc::{self, c_int, c_void};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::channel::Channel;
+use crate::session::{FuseSessionStore, Session};
+use crate::Filesystem;
+
+/// Version of the handover protocol
+const HANDOVER_VERSION: u32 = 1;
+
+/// Size of the header: protocol version and payload length
+const HEADER_SIZE: usize = 12;
+
+/// Max size of the payload. Larger payloads are rejected, so that a malformed header can't
+/// make the new process allocate arbitrary amounts of memory.
+const MAX_PAYLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Byte the new process acknowledges a handover with
+const HANDOVER_ACK: u8 = 1;
+
+/// Payload of a handover message
+#[derive(Serialize, Deserialize)]
+struct HandoverMessage<S> {
+    /// Path of the mounted filesystem (raw bytes, since it may not be valid UTF-8)
+    mountpoint: Vec<u8>,
+    /// True if the session unmounts the filesystem when dropped
+    unmount_on_drop: bool,
+    /// State of the session
+    store: FuseSessionStore,
+    /// State of the filesystem
+    state: S,
+}
+
+/// Send the channel's fd, the session state and the given filesystem state to the new
+/// process and wait for it to acknowledge the handover
+pub(crate) fn send<S: Serialize>(
+    stream: &mut UnixStream,
+    ch: &Channel,
+    store: &FuseSessionStore,
+    state: &S,
+) -> io::Result<()> {
+    let message = HandoverMessage {
+        mountpoint: ch.mountpoint().as_os_str().as_bytes().to_vec(),
+        unmount_on_drop: ch.unmount_on_drop(),
+        store: store.clone(),
+        state,
+    };
+    let payload = bincode::serialize(&message).map_err(invalid_data)?;
+    if payload.len() as u64 > MAX_PAYLOAD_SIZE {
+        return Err(invalid_data("handover state too large"));
+    }
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(&HANDOVER_VERSION.to_le_bytes());
+    header[4..].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+    send_with_fd(stream, &header, ch.as_raw_fd())?;
+    stream.write_all(&payload)?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack)?;
+    if ack[0] != HANDOVER_ACK {
+        return Err(invalid_data("unexpected handover acknowledgement"));
+    }
+    Ok(())
+}
+
+/// FUSE connection received from a process that handed over its session
+#[derive(Debug)]
+pub struct Handover<S> {
+    /// Channel of the received FUSE device fd
+    ch: Channel,
+    /// State of the session
+    store: FuseSessionStore,
+    /// State of the filesystem
+    state: S,
+}
+
+impl<S: DeserializeOwned> Handover<S> {
+    /// Connect to the unix socket a session listens on for handovers and receive its
+    /// FUSE connection. Once this returns, the old process stopped serving the mount.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Handover<S>> {
+        let mut stream = UnixStream::connect(path)?;
+        Handover::receive(&mut stream)
+    }
+
+    /// Receive a FUSE connection over the given unix socket. Once this returns, the old
+    /// process stopped serving the mount. Handovers with more than 64 MiB of state are
+    /// rejected.
+    pub fn receive(stream: &mut UnixStream) -> io::Result<Handover<S>> {
+        let mut header = [0; HEADER_SIZE];
+        let fd = recv_with_fd(stream, &mut header)?;
+        // Take ownership of the fd right away, so that it's closed if anything fails. The
+        // mountpoint isn't known yet and the filesystem must not be unmounted on failure.
+        let ch = unsafe { Channel::from_fd(fd, Path::new(""), false) };
+        let mut version = [0; 4];
+        version.copy_from_slice(&header[..4]);
+        let version = u32::from_le_bytes(version);
+        if version != HANDOVER_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported handover version {}",
+                version
+            )));
+        }
+        let mut len = [0; 8];
+        len.copy_from_slice(&header[4..]);
+        let len = u64::from_le_bytes(len);
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(invalid_data(format!(
+                "handover payload too large ({} bytes)",
+                len
+            )));
+        }
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload)?;
+        let message: HandoverMessage<S> = bincode::deserialize(&payload).map_err(invalid_data)?;
+        let mountpoint = PathBuf::from(OsStr::from_bytes(&message.mountpoint));
+        stream.write_all(&[HANDOVER_ACK])?;
+        info!("Took over {} from fd {}", mountpoint.display(), fd);
+        let ch =
+            unsafe { Channel::from_fd(ch.into_raw_fd(), &mountpoint, message.unmount_on_drop) };
+        Ok(Handover {
+            ch,
+            store: message.store,
+            state: message.state,
+        })
+    }
+}
+
+impl<S> Handover<S> {
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// Return the state of the filesystem
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Resume serving the mount with a filesystem created from the received state. The
+    /// filesystem won't see an init request if the old process initialized it already.
+    pub fn into_session<FS: Filesystem, F: FnOnce(S) -> FS>(self, f: F) -> Session<FS> {
+        Session::resume(f(self.state), self.ch, self.store)
+    }
+}
+
+/// Send data together with the given fd as `SCM_RIGHTS` ancillary data
+fn send_with_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, fd);
+    }
+    let rc = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else if rc as usize != data.len() {
+        Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "short handover header",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Receive data together with an fd sent as `SCM_RIGHTS` ancillary data
+fn recv_with_fd(stream: &UnixStream, data: &mut [u8]) -> io::Result<RawFd> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = space as _;
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC | libc::MSG_WAITALL;
+    #[cfg(not(target_os = "linux"))]
+    let flags = libc::MSG_WAITALL;
+    let rc = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, flags) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                fd = Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    let fd = fd.ok_or_else(|| invalid_data("handover without a FUSE device fd"))?;
+    if rc as usize != data.len() {
+        unsafe {
+            libc::close(fd);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "short handover header",
+        ));
+    }
+    Ok(fd)
+}
+
+/// Create an error for malformed handover data
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{recv_with_fd, send, send_with_fd, Handover, HANDOVER_VERSION, HEADER_SIZE};
+    use crate::channel::Channel;
+    use crate::session::FuseSessionStore;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::thread;
+
+    #[test]
+    fn pass_fd() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (mut a, b) = UnixStream::pair().unwrap();
+        send_with_fd(&tx, b"header", b.as_raw_fd()).unwrap();
+        let mut data = [0; 6];
+        let fd = recv_with_fd(&rx, &mut data).unwrap();
+        assert_eq!(&data, b"header");
+        assert_ne!(fd, b.as_raw_fd());
+        // The received fd refers to the same socket
+        let mut received = unsafe { UnixStream::from_raw_fd(fd) };
+        a.write_all(b"x").unwrap();
+        let mut buf = [0; 1];
+        received.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
+    }
+
+    #[test]
+    fn handover_roundtrip() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        // Any fd can stand in for the FUSE device fd
+        let (dev, _peer) = UnixStream::pair().unwrap();
+        let ch = unsafe { Channel::from_fd(dev.into_raw_fd(), Path::new("/mnt/test"), true) };
+        let mut store = FuseSessionStore::new();
+        store.proto_major = 7;
+        store.proto_minor = 31;
+        store.initialized = true;
+        store.request_errors.malformed = 2;
+        let receiver = thread::spawn(move || Handover::<Vec<u64>>::receive(&mut new));
+        send(&mut old, &ch, &store, &vec![1u64, 2, 3]).unwrap();
+        let handover = receiver.join().unwrap().unwrap();
+        assert_eq!(handover.mountpoint(), Path::new("/mnt/test"));
+        assert_eq!(handover.state(), &vec![1, 2, 3]);
+        assert_eq!(handover.store.proto_minor, 31);
+        assert!(handover.store.initialized);
+        assert_eq!(handover.store.request_errors.malformed, 2);
+        assert!(handover.ch.unmount_on_drop());
+        // Neither channel may unmount the made up mountpoint when dropped
+        let mut ch = ch;
+        ch.set_unmount_on_drop(false);
+        let Handover { mut ch, .. } = handover;
+        ch.set_unmount_on_drop(false);
+    }
+
+    #[test]
+    fn reject_large_payload() {
+        let (tx, mut rx) = UnixStream::pair().unwrap();
+        let (dev, _peer) = UnixStream::pair().unwrap();
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&HANDOVER_VERSION.to_le_bytes());
+        header[4..].copy_from_slice(&u64::MAX.to_le_bytes());
+        send_with_fd(&tx, &header, dev.as_raw_fd()).unwrap();
+        let err = Handover::<()>::receive(&mut rx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -5,30 +5,69 @@
 //! and unmount calls which are needed to establish a fd to talk to the kernel driver.
 
 #![warn(missing_docs, missing_debug_implementations, rust_2018_idioms)]
+pub use buffer::{BufferPool, WriteData};
 pub use channel::unmount;
+#[cfg(feature = "control")]
+pub use control::{ControlClient, ControlRequest, ControlResponse, InFlightRequest};
 use channel::Channel;
+pub use deadline::{Deadlines, SuppressedReply};
 pub use fuse_abi::consts;
+pub use fuse_abi::fuse_opcode;
 pub use fuse_abi::FUSE_ROOT_ID;
+#[cfg(feature = "handover")]
+pub use handover::Handover;
 use libc::{c_int, ENOSYS};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
-pub use reply::{Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen};
+pub use reply::{OpenReplyFlags, Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen};
 pub use reply::{ReplyBmap, ReplyCreate, ReplyDirectory, ReplyLock, ReplyStatfs, ReplyWrite};
+pub use evented_session::{EventedSession, RecvResult};
+pub use mount_options::{MountOpt, MountOptions};
 pub use request::Request;
+#[cfg(target_os = "linux")]
+pub use sandbox::Sandbox;
+pub use scheduler::{RequestClass, Scheduler};
 use serde_derive::{Deserialize, Serialize};
-pub use session::{BackgroundSession, Session};
+pub use session::{BackgroundSession, ConnectionInfo, RequestErrorCounters, Session};
+pub use session::{SessionEvent, SessionExit, SessionHandle};
+pub use stats::{LatencyHistogram, OperationStats, SessionStats};
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::io;
+use std::ops::{BitOr, BitOrAssign};
 use std::os::unix::io::IntoRawFd;
 use std::path::Path;
+pub use sync_filesystem::SyncFilesystem;
+pub use unwind::PanicPolicy;
 use std::time::SystemTime;
+#[cfg(feature = "async")]
+pub mod async_fs;
+mod buffer;
 mod channel;
+#[cfg(feature = "control")]
+mod control;
+mod deadline;
+mod evented_session;
+#[cfg(feature = "handover")]
+mod handover;
 mod ll;
+mod mount_options;
 mod reply;
 mod request;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod scheduler;
 mod session;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+mod sync_filesystem;
+#[cfg(test)]
+mod test_util;
+#[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+mod uring;
+mod unwind;
 
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
@@ -78,10 +117,81 @@ pub struct FileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags (macOS: see chflags(2), others: `FUSE_ATTR_SUBMOUNT` with ABI 7.32 if the
+    /// filesystem has the `SUBMOUNTS` capability, other flags are ignored)
     pub flags: u32,
 }
 
+/// Capabilities
+///
+/// Optional kernel features a filesystem implements. A capability is only enabled if the
+/// kernel supports it as well.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The filesystem clears the setuid and setgid bits itself on write, truncate and open
+    /// with O_TRUNC if the kernel asks for it (see `Request::kill_suidgid`), instead of the
+    /// kernel sending a separate setattr (ABI 7.33)
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub const HANDLE_KILLPRIV_V2: Capabilities = Capabilities(consts::FUSE_HANDLE_KILLPRIV_V2);
+    /// The filesystem doesn't need open and release requests for files. Open requests are
+    /// answered with ENOSYS without calling `open`, after which the kernel stops sending
+    /// them (ABI 7.23)
+    #[cfg(feature = "abi-7-23")]
+    pub const NO_OPEN: Capabilities = Capabilities(consts::FUSE_NO_OPEN_SUPPORT);
+    /// The filesystem doesn't need opendir and releasedir requests. Opendir requests are
+    /// answered with ENOSYS without calling `opendir`, after which the kernel stops sending
+    /// them (ABI 7.29)
+    #[cfg(feature = "abi-7-29")]
+    pub const NO_OPENDIR: Capabilities = Capabilities(consts::FUSE_NO_OPENDIR_SUPPORT);
+    /// Directories with `FUSE_ATTR_SUBMOUNT` in their attribute flags are mounted as
+    /// separate submounts with their own device number (ABI 7.32)
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    pub const SUBMOUNTS: Capabilities = Capabilities(consts::FUSE_SUBMOUNTS);
+    /// Requests are spliced from the FUSE device through a pipe instead of being read
+    /// directly. Falls back to reading if the pipe can't be made large enough for a
+    /// request (see `/proc/sys/fs/pipe-max-size`) (ABI 7.14)
+    #[cfg(all(feature = "abi-7-14", not(target_os = "macos")))]
+    pub const SPLICE_READ: Capabilities = Capabilities(consts::FUSE_SPLICE_READ);
+    /// Replies may be spliced to the FUSE device (see `ReplyData::splice`) (ABI 7.14)
+    #[cfg(all(feature = "abi-7-14", not(target_os = "macos")))]
+    pub const SPLICE_WRITE: Capabilities = Capabilities(consts::FUSE_SPLICE_WRITE);
+    /// Pages of spliced replies may be moved into the page cache instead of being copied
+    /// (ABI 7.14)
+    #[cfg(all(feature = "abi-7-14", not(target_os = "macos")))]
+    pub const SPLICE_MOVE: Capabilities = Capabilities(consts::FUSE_SPLICE_MOVE);
+
+    /// Returns empty capabilities
+    pub const fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Returns the raw FUSE init flags of the capabilities
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if all of the given capabilities are set
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Capabilities) {
+        self.0 |= rhs.0;
+    }
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
@@ -95,6 +205,14 @@ pub trait Filesystem {
         Ok(())
     }
 
+    /// Optional kernel features the filesystem implements.
+    /// Called on init, before the `init` method. Features the kernel doesn't support are
+    /// not enabled. Independent of capabilities, operations the kernel stops sending after an
+    /// ENOSYS reply (e.g. flush, fsync and the xattr operations) are not called again.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
     /// Clean up filesystem.
     /// Called on filesystem exit.
     fn destroy(&mut self, _req: &Request<'_>) {}
@@ -119,6 +237,8 @@ pub trait Filesystem {
     }
 
     /// Set file attributes.
+    /// If `req.kill_suidgid()` is set, the setuid and setgid bits have to be cleared as well
+    /// (only with the `HANDLE_KILLPRIV_V2` capability).
     fn setattr(
         &mut self,
         _req: &Request<'_>,
@@ -146,6 +266,7 @@ pub trait Filesystem {
 
     /// Create file node.
     /// Create a regular file, character device, block device, fifo or socket node.
+    /// With ABI 7.36, `req.security_context()` carries the label of the new node.
     fn mknod(
         &mut self,
         _req: &Request<'_>,
@@ -159,6 +280,7 @@ pub trait Filesystem {
     }
 
     /// Create a directory.
+    /// With ABI 7.36, `req.security_context()` carries the label of the new directory.
     fn mkdir(
         &mut self,
         _req: &Request<'_>,
@@ -181,6 +303,7 @@ pub trait Filesystem {
     }
 
     /// Create a symbolic link.
+    /// With ABI 7.36, `req.security_context()` carries the label of the new link.
     fn symlink(
         &mut self,
         _req: &Request<'_>,
@@ -222,11 +345,13 @@ pub trait Filesystem {
     /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
     /// etc) in fh, and use this in other all other file operations (read, write, flush,
     /// release, fsync). Filesystem may also implement stateless file I/O and not store
-    /// anything in fh. There are also some flags (direct_io, keep_cache) which the
-    /// filesystem may set, to change the way the file is opened. See fuse_file_info
-    /// structure in <fuse_common.h> for more details.
+    /// anything in fh. There are also some flags (see `OpenReplyFlags`) which the
+    /// filesystem may set, to change the way the file is opened. Flags not supported by
+    /// the kernel's protocol version are dropped. If `req.kill_suidgid()` is set, the file
+    /// is truncated and its setuid and setgid bits have to be cleared (only with the
+    /// `HANDLE_KILLPRIV_V2` capability).
     fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+        reply.opened(0, OpenReplyFlags::empty());
     }
 
     /// Read data.
@@ -253,7 +378,10 @@ pub trait Filesystem {
     /// exception to this is when the file has been opened in 'direct_io' mode, in
     /// which case the return value of the write system call will reflect the return
     /// value of this operation. fh will contain the value set by the open method, or
-    /// will be undefined if the open method didn't set any value.
+    /// will be undefined if the open method didn't set any value. If the filesystem has the
+    /// `HANDLE_KILLPRIV_V2` capability, `FUSE_WRITE_KILL_SUIDGID` in flags asks it to clear
+    /// the setuid and setgid bits. With a buffer pool, `req.write_data()` returns the data
+    /// without copying, to keep it after returning.
     fn write(
         &mut self,
         _req: &Request<'_>,
@@ -331,7 +459,7 @@ pub trait Filesystem {
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+        reply.opened(0, OpenReplyFlags::empty());
     }
 
     /// Read directory.
@@ -444,7 +572,8 @@ pub trait Filesystem {
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
-    /// and open() methods will be called instead.
+    /// and open() methods will be called instead. With ABI 7.36, `req.security_context()`
+    /// carries the label of the new file.
     fn create(
         &mut self,
         _req: &Request<'_>,
@@ -561,11 +690,39 @@ pub fn mount<FS: Filesystem, P: AsRef<Path>>(
     mountpoint: P,
     options: &[&OsStr],
 ) -> io::Result<()> {
-    Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run())
+    Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run()).map(|_| ())
+}
+
+/// Mount the given shared filesystem to the given mountpoint and serve it with the
+/// given number of worker threads, each with its own clone of the filesystem. This function
+/// will not return until the filesystem is unmounted. On Linux, every worker reads requests
+/// from its own clone of the FUSE device fd.
+pub fn mount_multithreaded<FS: SyncFilesystem, P: AsRef<Path>>(
+    filesystem: FS,
+    mountpoint: P,
+    options: &[&OsStr],
+    workers: usize,
+) -> io::Result<()> {
+    Session::new(filesystem, mountpoint.as_ref(), options)
+        .and_then(|mut se| se.run_multithreaded(workers))
+        .map(|_| ())
+}
+
+/// Mount to the given mountpoint and return an evented session without a session loop.
+/// The session can be registered with a mio event loop, and requests received from it
+/// need to be dispatched to a filesystem explicitly.
+pub fn evented<'a, P: AsRef<Path>, O: Into<MountOptions<'a>>>(
+    mountpoint: P,
+    options: O,
+) -> io::Result<EventedSession> {
+    let args = options.into().to_args();
+    let args: Vec<&OsStr> = args.iter().map(|arg| arg.as_os_str()).collect();
+    EventedSession::new(mountpoint.as_ref(), &args)
 }
 
 /// Mount the given filesystem to the given mountpoint. This function will
 /// This function will return the raw fuse descriptor to be handled at your
+/// own (e.g. passed to another process and served with `Session::from_fd`).
 pub unsafe fn raw_mount<FS: Filesystem, P: AsRef<Path>>(
     mountpoint: P,
     options: &[&OsStr],
@@ -578,10 +735,10 @@ pub unsafe fn raw_mount<FS: Filesystem, P: AsRef<Path>>(
 /// and therefore returns immediately. The returned handle should be stored
 /// to reference the mounted filesystem. If it's dropped, the filesystem will
 /// be unmounted.
-pub unsafe fn spawn_mount<'a, FS: Filesystem + Send + 'a, P: AsRef<Path>>(
+pub fn spawn_mount<FS: Filesystem + Send + 'static, P: AsRef<Path>>(
     filesystem: FS,
     mountpoint: P,
     options: &[&OsStr],
-) -> io::Result<BackgroundSession<'a>> {
+) -> io::Result<BackgroundSession<FS>> {
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|se| se.spawn())
 }
@@ -6,6 +6,7 @@
 use std::ffi::OsStr;
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
+use std::slice;
 
 
 /// An iterator that can be used to fetch typed arguments from a byte slice.
@@ -47,6 +48,16 @@ impl<'a> ArgumentIterator<'a> {
         (bytes.as_ptr() as *const T).as_ref()
     }
 
+    /// Fetch a slice of typed arguments. Returns `None` if there's not enough data left. This
+    /// function is unsafe because there is no guarantee that the data actually contains the
+    /// type T.
+    #[allow(dead_code)]
+    pub unsafe fn fetch_slice<T>(&mut self, count: usize) -> Option<&'a [T]> {
+        let len = mem::size_of::<T>().checked_mul(count)?;
+        let bytes = self.fetch_bytes(len)?;
+        Some(slice::from_raw_parts(bytes.as_ptr() as *const T, count))
+    }
+
     /// Fetch a (zero-terminated) string (can be non-utf8). Returns `None` if there's not enough
     /// data left or no zero-termination could be found. This function is unsafe because there is
     /// no guarantee that the data actually contains a string.
@@ -63,14 +74,19 @@ impl<'a> ArgumentIterator<'a> {
 mod tests {
     use super::*;
 
-    const TEST_DATA: [u8; 10] = [0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61];
+    /// Test data, aligned so that it can be read as test arguments
+    #[repr(C, align(8))]
+    struct AlignedData([u8; 10]);
+
+    static TEST_DATA: AlignedData =
+        AlignedData([0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61]);
 
     #[repr(C)]
     struct TestArgument { p1: u8, p2: u8, p3: u16 }
 
     #[test]
     fn all_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         unsafe { it.fetch_str().unwrap() };
         let arg = it.fetch_all();
         assert_eq!(arg, [0x62, 0x61, 0x72, 0x00, 0x62, 0x61]);
@@ -78,7 +94,7 @@ mod tests {
 
     #[test]
     fn bytes_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let arg = it.fetch_bytes(5).unwrap();
         assert_eq!(arg, [0x66, 0x6f, 0x6f, 0x00, 0x62]);
         let arg = it.fetch_bytes(2).unwrap();
@@ -88,7 +104,7 @@ mod tests {
 
     #[test]
     fn generic_argument() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let arg: &TestArgument = unsafe { it.fetch().unwrap() };
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
@@ -100,9 +116,22 @@ mod tests {
         assert_eq!(it.len(), 2);
     }
 
+    #[test]
+    fn slice_argument() {
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let arg: &[TestArgument] = unsafe { it.fetch_slice(2).unwrap() };
+        assert_eq!(arg.len(), 2);
+        assert_eq!(arg[0].p1, 0x66);
+        assert_eq!(arg[1].p3, 0x0072);
+        assert_eq!(it.len(), 2);
+        let arg: Option<&[TestArgument]> = unsafe { it.fetch_slice(1) };
+        assert!(arg.is_none());
+        assert_eq!(it.len(), 2);
+    }
+
     #[test]
     fn string_argument() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let arg = unsafe { it.fetch_str().unwrap() };
         assert_eq!(arg, "foo");
         let arg = unsafe { it.fetch_str().unwrap() };
@@ -112,7 +141,7 @@ mod tests {
 
     #[test]
     fn mixed_arguments() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let arg: &TestArgument = unsafe { it.fetch().unwrap() };
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
@@ -125,7 +154,7 @@ mod tests {
 
     #[test]
     fn out_of_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let _arg = it.fetch_bytes(8).unwrap();
         let arg: Option<&TestArgument> = unsafe { it.fetch() };
         assert!(arg.is_none());
@@ -3,4 +3,6 @@
 mod argument;
 
 mod request;
-pub use request::{Operation, Request, RequestError};
+pub use request::{request_unique, Operation, Request, RequestError};
+#[cfg(feature = "abi-7-38")]
+pub use request::Extension;
@@ -4,6 +4,10 @@
 //! perform.
 
 use fuse_abi::*;
+#[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+use fuse_abi::consts::FUSE_INIT_EXT;
+#[cfg(feature = "abi-7-38")]
+use fuse_abi::consts::{FUSE_EXT_GROUPS, FUSE_MAX_NR_SECCTX};
 use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::{error, fmt, mem};
@@ -122,6 +126,8 @@ pub enum Operation<'a> {
     },
     Init {
         arg: &'a fuse_init_in,
+        #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+        ext: Option<&'a fuse_init_in_ext>,
     },
     OpenDir {
         arg: &'a fuse_open_in,
@@ -158,28 +164,65 @@ pub enum Operation<'a> {
         arg: &'a fuse_bmap_in,
     },
     Destroy,
-    // TODO: FUSE_IOCTL since ABI 7.11
-    // IoCtl {
-    //     arg: &'a fuse_ioctl_in,
-    //     data: &'a [u8],
-    // },
-    // TODO: FUSE_POLL since ABI 7.11
-    // Poll {
-    //     arg: &'a fuse_poll_in,
-    // },
-    // TODO: FUSE_NOTIFY_REPLY since ABI 7.15
-    // NotifyReply {
-    //     data: &'a [u8],
-    // },
-    // TODO: FUSE_BATCH_FORGET since ABI 7.16
-    // BatchForget {
-    //     arg: &'a fuse_forget_in,
-    //     nodes: &'a [fuse_forget_one],
-    // },
-    // TODO: FUSE_FALLOCATE since ABI 7.19
-    // FAllocate {
-    //     arg: &'a fuse_fallocate_in,
-    // },
+    #[cfg(feature = "abi-7-11")]
+    IoCtl {
+        arg: &'a fuse_ioctl_in,
+        data: &'a [u8],
+    },
+    #[cfg(feature = "abi-7-11")]
+    Poll {
+        arg: &'a fuse_poll_in,
+    },
+    #[cfg(feature = "abi-7-15")]
+    NotifyReply {
+        data: &'a [u8],
+    },
+    #[cfg(feature = "abi-7-16")]
+    BatchForget {
+        arg: &'a fuse_batch_forget_in,
+        nodes: &'a [fuse_forget_one],
+    },
+    #[cfg(feature = "abi-7-19")]
+    FAllocate {
+        arg: &'a fuse_fallocate_in,
+    },
+    #[cfg(feature = "abi-7-21")]
+    ReadDirPlus {
+        arg: &'a fuse_read_in,
+    },
+    #[cfg(feature = "abi-7-23")]
+    Rename2 {
+        arg: &'a fuse_rename2_in,
+        name: &'a OsStr,
+        newname: &'a OsStr,
+    },
+    #[cfg(feature = "abi-7-24")]
+    Lseek {
+        arg: &'a fuse_lseek_in,
+    },
+    #[cfg(feature = "abi-7-28")]
+    CopyFileRange {
+        arg: &'a fuse_copy_file_range_in,
+    },
+    #[cfg(feature = "abi-7-31")]
+    SetupMapping {
+        arg: &'a fuse_setupmapping_in,
+    },
+    #[cfg(feature = "abi-7-31")]
+    RemoveMapping {
+        arg: &'a fuse_removemapping_in,
+        mappings: &'a [fuse_removemapping_one],
+    },
+    #[cfg(feature = "abi-7-34")]
+    #[allow(dead_code)]
+    SyncFs {
+        arg: &'a fuse_syncfs_in,
+    },
+    #[cfg(feature = "abi-7-37")]
+    TmpFile {
+        arg: &'a fuse_create_in,
+        name: &'a OsStr,
+    },
     #[cfg(target_os = "macos")]
     SetVolName {
         name: &'a OsStr,
@@ -195,10 +238,11 @@ pub enum Operation<'a> {
 
     #[cfg(target_os = "android")]
     CanonicalPath,
-    // TODO: CUSE_INIT since ABI 7.12
-    // CuseInit {
-    //     arg: &'a fuse_init_in,
-    // },
+
+    #[cfg(feature = "abi-7-12")]
+    CuseInit {
+        arg: &'a cuse_init_in,
+    },
 }
 
 impl<'a> fmt::Display for Operation<'a> {
@@ -227,7 +271,7 @@ impl<'a> fmt::Display for Operation<'a> {
             Operation::ListXAttr { arg } => write!(f, "LISTXATTR size {}", arg.size),
             Operation::RemoveXAttr { name } => write!(f, "REMOVEXATTR name {:?}", name),
             Operation::Flush { arg } => write!(f, "FLUSH fh {}, lock owner {}", arg.fh, arg.lock_owner),
-            Operation::Init { arg } => write!(f, "INIT kernel ABI {}.{}, flags {:#x}, max readahead {}", arg.major, arg.minor, arg.flags, arg.max_readahead),
+            Operation::Init { arg, .. } => write!(f, "INIT kernel ABI {}.{}, flags {:#x}, max readahead {}", arg.major, arg.minor, arg.flags, arg.max_readahead),
             Operation::OpenDir { arg } => write!(f, "OPENDIR flags {:#x}", arg.flags),
             Operation::ReadDir { arg } => write!(f, "READDIR fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
             Operation::ReleaseDir { arg } => write!(f, "RELEASEDIR fh {}, flags {:#x}, release flags {:#x}, lock owner {}", arg.fh, arg.flags, arg.release_flags, arg.lock_owner),
@@ -240,6 +284,32 @@ impl<'a> fmt::Display for Operation<'a> {
             Operation::Interrupt { arg } => write!(f, "INTERRUPT unique {}", arg.unique),
             Operation::BMap { arg } => write!(f, "BMAP blocksize {}, ids {}", arg.blocksize, arg.block),
             Operation::Destroy => write!(f, "DESTROY"),
+            #[cfg(feature = "abi-7-11")]
+            Operation::IoCtl { arg, data } => write!(f, "IOCTL fh {}, cmd {}, data size {}, flags {:#x}", arg.fh, arg.cmd, data.len(), arg.flags),
+            #[cfg(feature = "abi-7-11")]
+            Operation::Poll { arg } => write!(f, "POLL fh {}, kh {}, flags {:#x}", arg.fh, arg.kh, arg.flags),
+            #[cfg(feature = "abi-7-15")]
+            Operation::NotifyReply { data } => write!(f, "NOTIFY_REPLY data size {}", data.len()),
+            #[cfg(feature = "abi-7-16")]
+            Operation::BatchForget { arg, nodes } => write!(f, "BATCH_FORGET count {}, nodes {:?}", arg.count, nodes),
+            #[cfg(feature = "abi-7-19")]
+            Operation::FAllocate { arg } => write!(f, "FALLOCATE fh {}, offset {}, length {}, mode {:#x}", arg.fh, arg.offset, arg.length, arg.mode),
+            #[cfg(feature = "abi-7-21")]
+            Operation::ReadDirPlus { arg } => write!(f, "READDIRPLUS fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
+            #[cfg(feature = "abi-7-23")]
+            Operation::Rename2 { arg, name, newname } => write!(f, "RENAME2 name {:?}, newdir {:#018x}, newname {:?}, flags {:#x}", name, arg.newdir, newname, arg.flags),
+            #[cfg(feature = "abi-7-24")]
+            Operation::Lseek { arg } => write!(f, "LSEEK fh {}, offset {}, whence {}", arg.fh, arg.offset, arg.whence),
+            #[cfg(feature = "abi-7-28")]
+            Operation::CopyFileRange { arg } => write!(f, "COPY_FILE_RANGE fh_in {}, off_in {}, nodeid_out {:#018x}, fh_out {}, off_out {}, len {}, flags {:#x}", arg.fh_in, arg.off_in, arg.nodeid_out, arg.fh_out, arg.off_out, arg.len, arg.flags),
+            #[cfg(feature = "abi-7-31")]
+            Operation::SetupMapping { arg } => write!(f, "SETUPMAPPING fh {}, offset {}, len {}, flags {:#x}, moffset {}", arg.fh, arg.foffset, arg.len, arg.flags, arg.moffset),
+            #[cfg(feature = "abi-7-31")]
+            Operation::RemoveMapping { arg, mappings } => write!(f, "REMOVEMAPPING count {}, first {:?}", arg.count, mappings.first()),
+            #[cfg(feature = "abi-7-34")]
+            Operation::SyncFs { .. } => write!(f, "SYNCFS"),
+            #[cfg(feature = "abi-7-37")]
+            Operation::TmpFile { arg, name } => write!(f, "TMPFILE name {:?}, mode {:#05o}, flags {:#x}", name, arg.mode, arg.flags),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
@@ -250,6 +320,9 @@ impl<'a> fmt::Display for Operation<'a> {
         
             #[cfg(target_os = "android")]
             Operation::CanonicalPath => write!(f, "CANONICALPATH"),
+
+            #[cfg(feature = "abi-7-12")]
+            Operation::CuseInit { arg } => write!(f, "CUSE_INIT kernel ABI {}.{}, flags {:#x}", arg.major, arg.minor, arg.flags),
         }
     }
 }
@@ -315,7 +388,17 @@ impl<'a> Operation<'a> {
                     name: data.fetch_str()?,
                 },
                 fuse_opcode::FUSE_FLUSH => Operation::Flush { arg: data.fetch()? },
-                fuse_opcode::FUSE_INIT => Operation::Init { arg: data.fetch()? },
+                fuse_opcode::FUSE_INIT => {
+                    let arg: &fuse_init_in = data.fetch()?;
+                    Operation::Init {
+                        arg,
+                        #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                        ext: match arg.flags & FUSE_INIT_EXT {
+                            0 => None,
+                            _ => Some(data.fetch()?),
+                        },
+                    }
+                }
                 fuse_opcode::FUSE_OPENDIR => Operation::OpenDir { arg: data.fetch()? },
                 fuse_opcode::FUSE_READDIR => Operation::ReadDir { arg: data.fetch()? },
                 fuse_opcode::FUSE_RELEASEDIR => Operation::ReleaseDir { arg: data.fetch()? },
@@ -331,6 +414,56 @@ impl<'a> Operation<'a> {
                 fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
                 fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
                 fuse_opcode::FUSE_DESTROY => Operation::Destroy,
+                #[cfg(feature = "abi-7-11")]
+                fuse_opcode::FUSE_IOCTL => Operation::IoCtl {
+                    arg: data.fetch()?,
+                    data: data.fetch_all(),
+                },
+                #[cfg(feature = "abi-7-11")]
+                fuse_opcode::FUSE_POLL => Operation::Poll { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-15")]
+                fuse_opcode::FUSE_NOTIFY_REPLY => Operation::NotifyReply {
+                    data: data.fetch_all(),
+                },
+                #[cfg(feature = "abi-7-16")]
+                fuse_opcode::FUSE_BATCH_FORGET => {
+                    let arg: &fuse_batch_forget_in = data.fetch()?;
+                    Operation::BatchForget {
+                        arg,
+                        nodes: data.fetch_slice(arg.count as usize)?,
+                    }
+                }
+                #[cfg(feature = "abi-7-19")]
+                fuse_opcode::FUSE_FALLOCATE => Operation::FAllocate { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-21")]
+                fuse_opcode::FUSE_READDIRPLUS => Operation::ReadDirPlus { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-23")]
+                fuse_opcode::FUSE_RENAME2 => Operation::Rename2 {
+                    arg: data.fetch()?,
+                    name: data.fetch_str()?,
+                    newname: data.fetch_str()?,
+                },
+                #[cfg(feature = "abi-7-24")]
+                fuse_opcode::FUSE_LSEEK => Operation::Lseek { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-28")]
+                fuse_opcode::FUSE_COPY_FILE_RANGE => Operation::CopyFileRange { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-31")]
+                fuse_opcode::FUSE_SETUPMAPPING => Operation::SetupMapping { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-31")]
+                fuse_opcode::FUSE_REMOVEMAPPING => {
+                    let arg: &fuse_removemapping_in = data.fetch()?;
+                    Operation::RemoveMapping {
+                        arg,
+                        mappings: data.fetch_slice(arg.count as usize)?,
+                    }
+                }
+                #[cfg(feature = "abi-7-34")]
+                fuse_opcode::FUSE_SYNCFS => Operation::SyncFs { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-37")]
+                fuse_opcode::FUSE_TMPFILE => Operation::TmpFile {
+                    arg: data.fetch()?,
+                    name: data.fetch_str()?,
+                },
 
                 #[cfg(target_os = "macos")]
                 fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
@@ -347,16 +480,131 @@ impl<'a> Operation<'a> {
 
                 #[cfg(target_os = "android")]
                 fuse_opcode::FUSE_CANONICAL_PATH => Operation::CanonicalPath,
+
+                #[cfg(feature = "abi-7-12")]
+                fuse_opcode::CUSE_INIT => Operation::CuseInit { arg: data.fetch()? },
             })
         }
     }
 }
 
+/// Security context (e.g. SELinux label) of an inode that is about to be created. The kernel
+/// sends it with create, mkdir, mknod and symlink requests if `FUSE_SECURITY_CTX` was
+/// negotiated, so that the filesystem can label the new inode atomically.
+#[cfg(feature = "abi-7-36")]
+#[derive(Clone, Copy, Debug)]
+pub struct SecurityContext<'a> {
+    /// Name of the extended attribute that holds the context (e.g. `security.selinux`)
+    pub name: &'a OsStr,
+    /// Value of the extended attribute
+    pub value: &'a [u8],
+}
+
+#[cfg(feature = "abi-7-36")]
+impl<'a> SecurityContext<'a> {
+    /// Parse the security contexts that follow the arguments of an operation that creates an
+    /// inode. Returns `Some(None)` if the kernel sent no context.
+    fn parse(data: &mut ArgumentIterator<'a>) -> Option<Option<Self>> {
+        let header: &fuse_secctx_header = unsafe { data.fetch() }?;
+        let len = (header.size as usize).checked_sub(mem::size_of::<fuse_secctx_header>())?;
+        Self::parse_contexts(header.nr_secctx, data.fetch_bytes(len)?)
+    }
+
+    /// Parse the given number of security contexts. Only the first context is used since the
+    /// kernel never sends more than one.
+    fn parse_contexts(nr_secctx: u32, data: &'a [u8]) -> Option<Option<Self>> {
+        if nr_secctx == 0 {
+            return Some(None);
+        }
+        let mut data = ArgumentIterator::new(data);
+        unsafe {
+            let ctx: &fuse_secctx = data.fetch()?;
+            let name = data.fetch_str()?;
+            let value = data.fetch_bytes(ctx.size as usize)?;
+            Some(Some(SecurityContext { name, value }))
+        }
+    }
+}
+
+/// Extension the kernel appends to a request (ABI 7.38). Extensions are only sent if they
+/// were negotiated on init.
+#[cfg(feature = "abi-7-38")]
+#[derive(Clone, Copy, Debug)]
+pub enum Extension<'a> {
+    /// Security context of the inode to create (`FUSE_SECURITY_CTX`)
+    SecurityContext(Option<SecurityContext<'a>>),
+    /// Supplementary groups of the process that triggered the request
+    /// (`FUSE_CREATE_SUPP_GROUP`)
+    SupplementaryGroups(&'a [u32]),
+    /// Extension of unknown type
+    Unknown {
+        /// Type of the extension
+        typ: u32,
+        /// Payload of the extension
+        data: &'a [u8],
+    },
+}
+
+#[cfg(feature = "abi-7-38")]
+impl<'a> Extension<'a> {
+    /// Parse all extensions of the given extension area of a request
+    fn parse_all(data: &'a [u8]) -> Option<Vec<Self>> {
+        let mut data = ArgumentIterator::new(data);
+        let mut extensions = Vec::new();
+        while data.len() > 0 {
+            extensions.push(Self::parse(&mut data)?);
+        }
+        Some(extensions)
+    }
+
+    fn parse(data: &mut ArgumentIterator<'a>) -> Option<Self> {
+        let header: &fuse_ext_header = unsafe { data.fetch() }?;
+        let len = (header.size as usize).checked_sub(mem::size_of::<fuse_ext_header>())?;
+        let payload = data.fetch_bytes(len)?;
+        // Extension types up to FUSE_MAX_NR_SECCTX are a fuse_secctx_header where the
+        // type is the number of contexts
+        Some(match header.typ {
+            typ if typ <= FUSE_MAX_NR_SECCTX => {
+                Extension::SecurityContext(SecurityContext::parse_contexts(typ, payload)?)
+            }
+            FUSE_EXT_GROUPS => {
+                let mut payload = ArgumentIterator::new(payload);
+                unsafe {
+                    let groups: &fuse_supp_groups = payload.fetch()?;
+                    Extension::SupplementaryGroups(payload.fetch_slice(groups.nr_groups as usize)?)
+                }
+            }
+            typ => Extension::Unknown { typ, data: payload },
+        })
+    }
+}
+
+/// Returns the unique identifier of a raw request if it at least contains a complete header
+/// and expects a reply. Allows to reply to requests that failed to parse. Forget and interrupt
+/// requests are never replied to, and a unique identifier of 0 is taken for a notification.
+pub fn request_unique(data: &[u8]) -> Option<u64> {
+    let mut data = ArgumentIterator::new(data);
+    let header: &fuse_in_header = unsafe { data.fetch() }?;
+    let no_reply = header.opcode == fuse_opcode::FUSE_FORGET as u32
+        || header.opcode == fuse_opcode::FUSE_INTERRUPT as u32;
+    #[cfg(feature = "abi-7-16")]
+    let no_reply = no_reply || header.opcode == fuse_opcode::FUSE_BATCH_FORGET as u32;
+    match header.unique {
+        0 => None,
+        _ if no_reply => None,
+        unique => Some(unique),
+    }
+}
+
 /// Low-level request of a filesystem operation the kernel driver wants to perform.
 #[derive(Debug)]
 pub struct Request<'a> {
     header: &'a fuse_in_header,
     operation: Operation<'a>,
+    #[cfg(feature = "abi-7-36")]
+    security_context: Option<SecurityContext<'a>>,
+    #[cfg(feature = "abi-7-38")]
+    extensions: Vec<Extension<'a>>,
 }
 
 impl<'a> fmt::Display for Request<'a> {
@@ -387,10 +635,78 @@ impl<'a> TryFrom<&'a [u8]> for Request<'a> {
         if data_len < header.len as usize {
             return Err(RequestError::ShortRead(data_len, header.len as usize));
         }
+        // Split off request extensions, which are at the end of the request
+        #[cfg(feature = "abi-7-38")]
+        let extensions = {
+            let args = data.fetch_all();
+            let ext_len = header.total_extlen as usize * 8;
+            let args_len = args.len().checked_sub(ext_len).ok_or(RequestError::InsufficientData)?;
+            data = ArgumentIterator::new(&args[..args_len]);
+            Extension::parse_all(&args[args_len..]).ok_or(RequestError::InsufficientData)?
+        };
         // Parse/check operation arguments
-        let operation =
-            Operation::parse(&opcode, &mut data).ok_or_else(|| RequestError::InsufficientData)?;
-        Ok(Self { header, operation })
+        let operation = Operation::parse(&opcode, &mut data).ok_or(RequestError::InsufficientData)?;
+        // Operations that create an inode may be followed by a security context. Since
+        // ABI 7.38, it is sent as a request extension instead.
+        #[cfg(feature = "abi-7-36")]
+        let security_context = match operation {
+            Operation::Create { .. }
+            | Operation::MkDir { .. }
+            | Operation::MkNod { .. }
+            | Operation::SymLink { .. }
+                if data.len() > 0 =>
+            {
+                SecurityContext::parse(&mut data).ok_or(RequestError::InsufficientData)?
+            }
+            _ => None,
+        };
+        #[cfg(feature = "abi-7-38")]
+        let security_context = security_context.or_else(|| {
+            extensions.iter().find_map(|ext| match ext {
+                Extension::SecurityContext(ctx) => *ctx,
+                _ => None,
+            })
+        });
+        Ok(Self {
+            header,
+            operation,
+            #[cfg(feature = "abi-7-36")]
+            security_context,
+            #[cfg(feature = "abi-7-38")]
+            extensions,
+        })
+    }
+}
+
+/// Header of the destroy request for filesystems that are destroyed after the session ended
+static DESTROY_HEADER: fuse_in_header = fuse_in_header {
+    len: mem::size_of::<fuse_in_header>() as u32,
+    opcode: fuse_opcode::FUSE_DESTROY as u32,
+    unique: 0,
+    nodeid: 0,
+    uid: 0,
+    gid: 0,
+    pid: 0,
+    #[cfg(not(feature = "abi-7-38"))]
+    padding: 0,
+    #[cfg(feature = "abi-7-38")]
+    total_extlen: 0,
+    #[cfg(feature = "abi-7-38")]
+    padding: 0,
+};
+
+impl Request<'static> {
+    /// Returns a destroy request that wasn't sent by the kernel. The kernel only sends one
+    /// for some kinds of mounts (e.g. fuseblk).
+    pub(crate) fn destroy() -> Self {
+        Self {
+            header: &DESTROY_HEADER,
+            operation: Operation::Destroy,
+            #[cfg(feature = "abi-7-36")]
+            security_context: None,
+            #[cfg(feature = "abi-7-38")]
+            extensions: Vec::new(),
+        }
     }
 }
 
@@ -405,6 +721,12 @@ impl<'a> Request<'a> {
         self.header.unique
     }
 
+    /// Returns the raw opcode of the operation of this request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
     /// Returns the node id of the inode this request is targeted to.
     #[inline]
     pub fn nodeid(&self) -> u64 {
@@ -434,11 +756,41 @@ impl<'a> Request<'a> {
     pub fn operation(&self) -> &Operation<'_> {
         &self.operation
     }
+
+    /// Returns the security context of the inode to create, if the kernel sent one.
+    #[cfg(feature = "abi-7-36")]
+    #[inline]
+    pub fn security_context(&self) -> Option<&SecurityContext<'_>> {
+        self.security_context.as_ref()
+    }
+
+    /// Returns the extensions the kernel appended to this request.
+    #[cfg(feature = "abi-7-38")]
+    #[inline]
+    pub fn extensions(&self) -> &[Extension<'_>] {
+        &self.extensions
+    }
+
+    /// Returns true if the kill-suidgid bit is set in the arguments of a write, setattr, open
+    /// or create request (ABI 7.33)
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub fn kill_suidgid(&self) -> bool {
+        use fuse_abi::consts::{FATTR_KILL_SUIDGID, FUSE_OPEN_KILL_SUIDGID, FUSE_WRITE_KILL_SUIDGID};
+        match self.operation() {
+            Operation::Write { arg, .. } => arg.write_flags & FUSE_WRITE_KILL_SUIDGID != 0,
+            Operation::SetAttr { arg } => arg.valid & FATTR_KILL_SUIDGID != 0,
+            Operation::Open { arg } => arg.open_flags & FUSE_OPEN_KILL_SUIDGID != 0,
+            Operation::Create { arg, .. } => arg.open_flags & FUSE_OPEN_KILL_SUIDGID != 0,
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    use crate::test_util::RawRequest;
 
     #[cfg(target_endian = "big")]
     const INIT_REQUEST: [u8; 56] = [
@@ -462,7 +814,7 @@ mod tests {
         0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // max_readahead, flags
     ];
 
-    #[cfg(target_endian = "big")]
+    #[cfg(all(not(feature = "abi-7-12"), target_endian = "big"))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x08, // len, opcode
         0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
@@ -473,7 +825,7 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
-    #[cfg(target_endian = "little")]
+    #[cfg(all(not(feature = "abi-7-12"), target_endian = "little"))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x38, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
         0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
@@ -484,6 +836,101 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
+    #[cfg(all(feature = "abi-7-12", target_endian = "big"))]
+    const MKNOD_REQUEST: [u8; 64] = [
+        0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x08, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x00, 0x00, 0x01, 0xa4, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ];
+
+    #[cfg(all(feature = "abi-7-12", target_endian = "little"))]
+    const MKNOD_REQUEST: [u8; 64] = [
+        0x40, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0xa4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ];
+
+    #[cfg(all(feature = "abi-7-36", target_endian = "big"))]
+    const SYMLINK_SECCTX_REQUEST: [u8; 88] = [
+        0x00, 0x00, 0x00, 0x58, 0x00, 0x00, 0x00, 0x06, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x05, 0x00, 0x00, // pid, total_extlen, padding
+        0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, // name, link
+        0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x01, // secctx header: size, nr_secctx
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, // secctx: size, padding
+        0x73, 0x65, 0x63, 0x75, 0x72, 0x69, 0x74, 0x79, // secctx name
+        0x2e, 0x73, 0x65, 0x6c, 0x69, 0x6e, 0x75, 0x78, //
+        0x00, 0x6c, 0x61, 0x62, 0x65, 0x6c, 0x00, 0x00, // secctx value, padding
+    ];
+
+    #[cfg(all(feature = "abi-7-36", target_endian = "little"))]
+    const SYMLINK_SECCTX_REQUEST: [u8; 88] = [
+        0x58, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x05, 0x00, 0x00, 0x00, // pid, total_extlen, padding
+        0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, // name, link
+        0x28, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // secctx header: size, nr_secctx
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // secctx: size, padding
+        0x73, 0x65, 0x63, 0x75, 0x72, 0x69, 0x74, 0x79, // secctx name
+        0x2e, 0x73, 0x65, 0x6c, 0x69, 0x6e, 0x75, 0x78, //
+        0x00, 0x6c, 0x61, 0x62, 0x65, 0x6c, 0x00, 0x00, // secctx value, padding
+    ];
+
+    /// Request data, aligned so that arguments can be read in place
+    #[cfg(feature = "abi-7-38")]
+    #[repr(C, align(8))]
+    struct Aligned<T>(T);
+
+    #[cfg(all(feature = "abi-7-38", target_endian = "big"))]
+    const MKDIR_EXTENSIONS_REQUEST: [u8; 108] = [
+        0x00, 0x00, 0x00, 0x6c, 0x00, 0x00, 0x00, 0x09, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x07, 0x00, 0x00, // pid, total_extlen, padding
+        0x00, 0x00, 0x01, 0xed, 0x00, 0x00, 0x00, 0x12, // mode, umask
+        0x66, 0x6f, 0x6f, 0x00, // name
+        0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x01, // secctx header: size, nr_secctx
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, // secctx: size, padding
+        0x73, 0x65, 0x63, 0x75, 0x72, 0x69, 0x74, 0x79, // secctx name
+        0x2e, 0x73, 0x65, 0x6c, 0x69, 0x6e, 0x75, 0x78, //
+        0x00, 0x6c, 0x61, 0x62, 0x65, 0x6c, 0x00, 0x00, // secctx value, padding
+        0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x20, // ext header: size, type
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x03, 0xe8, // nr_groups, groups
+    ];
+
+    #[cfg(all(feature = "abi-7-38", target_endian = "little"))]
+    const MKDIR_EXTENSIONS_REQUEST: [u8; 108] = [
+        0x6c, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x07, 0x00, 0x00, 0x00, // pid, total_extlen, padding
+        0xed, 0x01, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, // mode, umask
+        0x66, 0x6f, 0x6f, 0x00, // name
+        0x28, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // secctx header: size, nr_secctx
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // secctx: size, padding
+        0x73, 0x65, 0x63, 0x75, 0x72, 0x69, 0x74, 0x79, // secctx name
+        0x2e, 0x73, 0x65, 0x6c, 0x69, 0x6e, 0x75, 0x78, //
+        0x00, 0x6c, 0x61, 0x62, 0x65, 0x6c, 0x00, 0x00, // secctx value, padding
+        0x10, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // ext header: size, type
+        0x01, 0x00, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00, // nr_groups, groups
+    ];
+
     #[test]
     fn short_read_header() {
         match Request::try_from(&INIT_REQUEST[..20]) {
@@ -500,6 +947,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn unique_of_invalid_request() {
+        assert_eq!(request_unique(&INIT_REQUEST[..20]), None);
+        assert_eq!(request_unique(&INIT_REQUEST[..48]), Some(0xdead_beef_baad_f00d));
+        // Requests that don't expect a reply
+        let mut forget = INIT_REQUEST;
+        forget[4..8].copy_from_slice(&(fuse_opcode::FUSE_FORGET as u32).to_ne_bytes());
+        assert_eq!(request_unique(&forget[..48]), None);
+        let mut interrupt = INIT_REQUEST;
+        interrupt[4..8].copy_from_slice(&(fuse_opcode::FUSE_INTERRUPT as u32).to_ne_bytes());
+        assert_eq!(request_unique(&interrupt[..48]), None);
+        let mut notify = INIT_REQUEST;
+        notify[8..16].copy_from_slice(&0u64.to_ne_bytes());
+        assert_eq!(request_unique(&notify[..48]), None);
+    }
+
     #[test]
     fn init() {
         let req = Request::try_from(&INIT_REQUEST[..]).unwrap();
@@ -511,7 +974,7 @@ mod tests {
         assert_eq!(req.gid(), 0xc001_cafe);
         assert_eq!(req.pid(), 0xc0de_ba5e);
         match req.operation() {
-            Operation::Init { arg } => {
+            Operation::Init { arg, .. } => {
                 assert_eq!(arg.major, 7);
                 assert_eq!(arg.minor, 8);
                 assert_eq!(arg.max_readahead, 4096);
@@ -523,7 +986,7 @@ mod tests {
     #[test]
     fn mknod() {
         let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
-        assert_eq!(req.header.len, 56);
+        assert_eq!(req.header.len, MKNOD_REQUEST.len() as u32);
         assert_eq!(req.header.opcode, 8);
         assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
         assert_eq!(req.nodeid(), 0x1122_3344_5566_7788);
@@ -533,9 +996,102 @@ mod tests {
         match req.operation() {
             Operation::MkNod { arg, name } => {
                 assert_eq!(arg.mode, 0o644);
+                #[cfg(feature = "abi-7-12")]
+                assert_eq!(arg.umask, 0o022);
                 assert_eq!(*name, "foo.txt");
             }
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[test]
+    #[cfg(feature = "abi-7-36")]
+    fn symlink_security_context() {
+        let req = Request::try_from(&SYMLINK_SECCTX_REQUEST[..]).unwrap();
+        match req.operation() {
+            Operation::SymLink { name, link } => {
+                assert_eq!(*name, "foo");
+                assert_eq!(*link, "bar");
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+        let secctx = req.security_context().unwrap();
+        assert_eq!(secctx.name, "security.selinux");
+        assert_eq!(secctx.value, b"label");
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-38")]
+    fn mkdir_extensions() {
+        let data = Aligned(MKDIR_EXTENSIONS_REQUEST);
+        let req = Request::try_from(&data.0[..]).unwrap();
+        match req.operation() {
+            Operation::MkDir { arg, name } => {
+                assert_eq!(arg.mode, 0o755);
+                assert_eq!(*name, "foo");
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+        assert_eq!(req.extensions().len(), 2);
+        match req.extensions()[1] {
+            Extension::SupplementaryGroups(groups) => assert_eq!(groups, &[1000]),
+            _ => panic!("Unexpected request extension"),
+        }
+        let secctx = req.security_context().unwrap();
+        assert_eq!(secctx.name, "security.selinux");
+        assert_eq!(secctx.value, b"label");
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    fn kill_suidgid() {
+        use fuse_abi::consts::{FATTR_KILL_SUIDGID, FUSE_OPEN_KILL_SUIDGID, FUSE_WRITE_KILL_SUIDGID};
+        for set in [false, true] {
+            // Opcode, argument size, offset of the flags with the kill-suidgid bit, the bit and
+            // the name that follows the argument
+            let requests = [
+                (
+                    fuse_opcode::FUSE_WRITE,
+                    mem::size_of::<fuse_write_in>(),
+                    20,
+                    FUSE_WRITE_KILL_SUIDGID,
+                    &b""[..],
+                ),
+                (
+                    fuse_opcode::FUSE_SETATTR,
+                    mem::size_of::<fuse_setattr_in>(),
+                    0,
+                    FATTR_KILL_SUIDGID,
+                    &b""[..],
+                ),
+                (
+                    fuse_opcode::FUSE_OPEN,
+                    mem::size_of::<fuse_open_in>(),
+                    4,
+                    FUSE_OPEN_KILL_SUIDGID,
+                    &b""[..],
+                ),
+                (
+                    fuse_opcode::FUSE_CREATE,
+                    mem::size_of::<fuse_create_in>(),
+                    12,
+                    FUSE_OPEN_KILL_SUIDGID,
+                    &b"foo\0"[..],
+                ),
+            ];
+            for (opcode, len, offset, flag, name) in requests {
+                let mut arg = vec![0; len];
+                if set {
+                    arg[offset..offset + 4].copy_from_slice(&flag.to_ne_bytes());
+                }
+                arg.extend_from_slice(name);
+                let data = RawRequest::new(opcode, 1, 1, &arg);
+                let req = Request::try_from(data.as_bytes()).unwrap();
+                assert_eq!(req.kill_suidgid(), set, "opcode {}", req.opcode());
+            }
+        }
+        // Other requests never ask to kill the bits
+        let data = RawRequest::new(fuse_opcode::FUSE_READ, 1, 1, &[0xff; 40]);
+        assert!(!Request::try_from(data.as_bytes()).unwrap().kill_suidgid());
+    }
 }
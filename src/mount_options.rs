@@ -0,0 +1,131 @@
+//! Mount options
+//!
+//! Typed options for mounting a filesystem, combined with `+` and turned into the
+//! `-o` arguments the mount call expects.
+
+use std::ffi::OsString;
+use std::ops::Add;
+
+/// Mount option
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MountOpt<'a> {
+    /// Name of the filesystem source, shown e.g. by mount(8) (`fsname`)
+    Name(&'a str),
+    /// Filesystem subtype, shown as `fuse.<subtype>` (`subtype`)
+    Subtype(&'a str),
+    /// Let the kernel check permissions based on the file mode (`default_permissions`)
+    DefaultPermissions,
+    /// Allow all users to access the filesystem (`allow_other`)
+    AllowOther,
+    /// Allow the mounting user and root to access the filesystem (`allow_root`)
+    AllowRoot,
+    /// Unmount the filesystem automatically when the process exits (`auto_unmount`)
+    AutoUnmount,
+    /// Read-only filesystem (`ro`)
+    ReadOnly,
+    /// Read-write filesystem (`rw`)
+    ReadWrite,
+    /// Don't allow execution of binaries (`noexec`)
+    NoExec,
+    /// Ignore setuid and setgid bits (`nosuid`)
+    NoSuid,
+    /// Don't interpret device files (`nodev`)
+    NoDev,
+    /// Don't update access times (`noatime`)
+    NoAtime,
+    /// Synchronous I/O (`sync`)
+    Sync,
+    /// Any other option, passed as is
+    Custom(&'a str),
+}
+
+impl<'a> MountOpt<'a> {
+    /// Returns the option as it's passed to the mount call
+    fn to_option(self) -> String {
+        match self {
+            MountOpt::Name(name) => format!("fsname={}", name),
+            MountOpt::Subtype(subtype) => format!("subtype={}", subtype),
+            MountOpt::DefaultPermissions => "default_permissions".to_string(),
+            MountOpt::AllowOther => "allow_other".to_string(),
+            MountOpt::AllowRoot => "allow_root".to_string(),
+            MountOpt::AutoUnmount => "auto_unmount".to_string(),
+            MountOpt::ReadOnly => "ro".to_string(),
+            MountOpt::ReadWrite => "rw".to_string(),
+            MountOpt::NoExec => "noexec".to_string(),
+            MountOpt::NoSuid => "nosuid".to_string(),
+            MountOpt::NoDev => "nodev".to_string(),
+            MountOpt::NoAtime => "noatime".to_string(),
+            MountOpt::Sync => "sync".to_string(),
+            MountOpt::Custom(option) => option.to_string(),
+        }
+    }
+}
+
+/// A set of mount options
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MountOptions<'a>(Vec<MountOpt<'a>>);
+
+impl<'a> MountOptions<'a> {
+    /// Returns empty mount options
+    pub fn new() -> MountOptions<'a> {
+        MountOptions(Vec::new())
+    }
+
+    /// Returns the options as arguments for the mount call, each option led by `-o`
+    pub(crate) fn to_args(&self) -> Vec<OsString> {
+        self.0
+            .iter()
+            .flat_map(|opt| vec![OsString::from("-o"), OsString::from(opt.to_option())])
+            .collect()
+    }
+}
+
+impl<'a> From<MountOpt<'a>> for MountOptions<'a> {
+    fn from(opt: MountOpt<'a>) -> MountOptions<'a> {
+        MountOptions(vec![opt])
+    }
+}
+
+impl<'a> Add for MountOpt<'a> {
+    type Output = MountOptions<'a>;
+
+    fn add(self, rhs: MountOpt<'a>) -> MountOptions<'a> {
+        MountOptions(vec![self, rhs])
+    }
+}
+
+impl<'a> Add<MountOpt<'a>> for MountOptions<'a> {
+    type Output = MountOptions<'a>;
+
+    fn add(mut self, rhs: MountOpt<'a>) -> MountOptions<'a> {
+        self.0.push(rhs);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MountOpt, MountOptions};
+    use std::ffi::OsString;
+
+    #[test]
+    fn mount_options_args() {
+        let options =
+            MountOpt::Name("test") + MountOpt::DefaultPermissions + MountOpt::Custom("x=y");
+        let args: Vec<OsString> = [
+            "-o",
+            "fsname=test",
+            "-o",
+            "default_permissions",
+            "-o",
+            "x=y",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(options.to_args(), args);
+        assert!(MountOptions::from(MountOpt::ReadOnly)
+            .to_args()
+            .contains(&OsString::from("ro")));
+    }
+}
@@ -6,19 +6,23 @@
 //! data without cloning the data. A reply *must always* be used (by calling either ok() or
 //! error() exactly once).
 
+use fuse_abi::consts::*;
 use fuse_abi::fuse_getxattr_out;
 #[cfg(target_os = "macos")]
 use fuse_abi::fuse_getxtimes_out;
 use fuse_abi::{fuse_attr, fuse_attr_out, fuse_entry_out, fuse_file_lock, fuse_kstatfs};
 use fuse_abi::{fuse_bmap_out, fuse_lk_out, fuse_open_out, fuse_statfs_out, fuse_write_out};
-use fuse_abi::{fuse_dirent, fuse_out_header};
-use libc::{c_int, EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
+use fuse_abi::{fuse_dirent, fuse_out_header, FUSE_KERNEL_MINOR_VERSION};
+use libc::{c_int, c_void, EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
 use log::warn;
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::fmt;
+use std::io;
 use std::marker::PhantomData;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
 use std::{mem, ptr, slice};
 
@@ -28,6 +32,63 @@ use crate::{FileAttr, FileType};
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Send a reply with the given error (0 if successful). The data starts with the
+    /// `fuse_out_header`, which contains the error as well.
+    fn send_reply(&self, err: c_int, data: &[&[u8]]) {
+        let _ = err;
+        self.send(data);
+    }
+
+    /// Send a successful reply with up to `len` bytes read from the given fd at the given
+    /// offset (less at the end of the file). Returns the number of bytes of data sent, or
+    /// the error without sending anything if reading from the fd fails.
+    fn send_fd(&self, unique: u64, fd: RawFd, offset: i64, len: usize) -> io::Result<usize> {
+        send_fd_copy(self, unique, fd, offset, len)
+    }
+}
+
+/// Send a successful reply with data from the given fd by reading it into memory. Returns the
+/// number of bytes of data sent.
+pub(crate) fn send_fd_copy<S: ReplySender + ?Sized>(
+    sender: &S,
+    unique: u64,
+    fd: RawFd,
+    offset: i64,
+    len: usize,
+) -> io::Result<usize> {
+    let mut data: Vec<u8> = Vec::with_capacity(len);
+    while data.len() < len {
+        let rc = unsafe {
+            libc::pread(
+                fd,
+                data.as_mut_ptr().add(data.len()) as *mut c_void,
+                len - data.len(),
+                offset + data.len() as i64,
+            )
+        };
+        match rc {
+            rc if rc < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => break,
+            rc => unsafe { data.set_len(data.len() + rc as usize) },
+        }
+    }
+    let header = fuse_out_header {
+        len: (mem::size_of::<fuse_out_header>() + data.len()) as u32,
+        error: 0,
+        unique,
+    };
+    as_bytes(&header, |headerbytes| {
+        let mut sendbytes = headerbytes.to_vec();
+        sendbytes.push(&data);
+        sender.send(&sendbytes);
+    });
+    Ok(data.len())
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -42,8 +103,15 @@ pub trait Reply {
     fn new<S: ReplySender>(unique: u64, sender: S) -> Self;
 }
 
+/// Reply that sends file attributes
+pub(crate) trait AttrReply: Reply {
+    /// Set the attribute flags the kernel agreed to receive. Other flags of the attributes
+    /// are not sent.
+    fn set_attr_flags(&mut self, attr_flags: u32);
+}
+
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
+pub(crate) fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     let len = mem::size_of::<T>();
     match len {
         0 => f(&[]),
@@ -110,9 +178,10 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
 /// represent a `--x--x--x` permissions
 const INHERIT_EXEC_MASK: u16 = 0b_001_001_000;
 
-/// Returns a fuse_attr from FileAttr
+/// Returns a fuse_attr from FileAttr. Only the given attribute flags are sent (ABI 7.32).
 #[cfg(not(target_os = "macos"))]
-fn fuse_attr_from_attr(attr: &FileAttr, mask: u16, gid: u32) -> fuse_attr {
+#[cfg_attr(not(feature = "abi-7-32"), allow(unused_variables))]
+fn fuse_attr_from_attr(attr: &FileAttr, mask: u16, gid: u32, attr_flags: u32) -> fuse_attr {
     // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
     let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime).unwrap();
     let (mtime_secs, mtime_nanos) = time_from_system_time(&attr.mtime).unwrap();
@@ -141,6 +210,12 @@ fn fuse_attr_from_attr(attr: &FileAttr, mask: u16, gid: u32) -> fuse_attr {
         uid: attr.uid,
         gid,
         rdev: attr.rdev,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,
+        #[cfg(all(feature = "abi-7-9", any(target_os = "macos", not(feature = "abi-7-32"))))]
+        padding: 0,
+        #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+        flags: attr.flags & attr_flags,
     }
 }
 
@@ -184,10 +259,19 @@ impl<T> ReplyRaw<T> {
             let sender = self.sender.take().unwrap();
             let mut sendbytes = headerbytes.to_vec();
             sendbytes.extend(bytes);
-            sender.send(&sendbytes);
+            sender.send_reply(err, &sendbytes);
         });
     }
 
+    /// Reply to a request with data read from the given fd. If reading fails, the sender
+    /// is kept and the error is returned, so that an error reply can be sent instead.
+    fn send_fd(&mut self, fd: RawFd, offset: i64, len: usize) -> io::Result<usize> {
+        let sender = self.sender.take().unwrap();
+        sender
+            .send_fd(self.unique, fd, offset, len)
+            .inspect_err(|_| self.sender = Some(sender))
+    }
+
     /// Reply to a request with the given type
     pub fn ok(mut self, data: &T) {
         as_bytes(data, |bytes| {
@@ -195,6 +279,16 @@ impl<T> ReplyRaw<T> {
         })
     }
 
+    /// Reply to a request with the first `len` bytes of the given type. Used for replies
+    /// that older kernels expect to be smaller than the type of the current ABI version.
+    #[cfg(feature = "abi-7-23")]
+    pub fn ok_truncated(mut self, data: &T, len: usize) {
+        as_bytes(data, |bytes| {
+            let bytes: Vec<&[u8]> = bytes.iter().map(|b| &b[..len.min(b.len())]).collect();
+            self.send(0, &bytes);
+        })
+    }
+
     /// Reply to a request with the given error code
     pub fn error(mut self, err: c_int) {
         self.send(err, &[]);
@@ -263,6 +357,16 @@ impl ReplyData {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request with up to `len` bytes of the given fd at the given offset (less
+    /// at the end of the file). On Linux, the data is spliced from the fd to the FUSE
+    /// device without copying it to userspace if possible. Replies with the error if
+    /// reading from the fd fails.
+    pub fn splice(mut self, fd: RawFd, offset: i64, len: usize) {
+        if let Err(err) = self.reply.send_fd(fd, offset, len) {
+            self.reply.error(err.raw_os_error().unwrap_or(EIO));
+        }
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -275,18 +379,28 @@ impl ReplyData {
 #[derive(Debug)]
 pub struct ReplyEntry {
     reply: ReplyRaw<fuse_entry_out>,
+    attr_flags: u32,
 }
 
 impl Reply for ReplyEntry {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEntry {
         ReplyEntry {
             reply: Reply::new(unique, sender),
+            attr_flags: 0,
         }
     }
 }
 
+impl AttrReply for ReplyEntry {
+    fn set_attr_flags(&mut self, attr_flags: u32) {
+        self.attr_flags = attr_flags;
+    }
+}
+
 impl ReplyEntry {
-    /// Reply to a request with the given entry
+    /// Reply to a request with the given entry. With ABI 7.32, a directory with
+    /// `FUSE_ATTR_SUBMOUNT` in its flags becomes the root of a submount if the filesystem
+    /// has the `SUBMOUNTS` capability. Other flags are not sent.
     pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64, mask: u16, gid: u32) {
         self.reply.ok(&fuse_entry_out {
             nodeid: attr.ino,
@@ -295,7 +409,7 @@ impl ReplyEntry {
             attr_valid: ttl.as_secs(),
             entry_valid_nsec: ttl.subsec_nanos(),
             attr_valid_nsec: ttl.subsec_nanos(),
-            attr: fuse_attr_from_attr(attr, mask, gid),
+            attr: fuse_attr_from_attr(attr, mask, gid, self.attr_flags),
         });
     }
 
@@ -311,24 +425,34 @@ impl ReplyEntry {
 #[derive(Debug)]
 pub struct ReplyAttr {
     reply: ReplyRaw<fuse_attr_out>,
+    attr_flags: u32,
 }
 
 impl Reply for ReplyAttr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyAttr {
         ReplyAttr {
             reply: Reply::new(unique, sender),
+            attr_flags: 0,
         }
     }
 }
 
+impl AttrReply for ReplyAttr {
+    fn set_attr_flags(&mut self, attr_flags: u32) {
+        self.attr_flags = attr_flags;
+    }
+}
+
 impl ReplyAttr {
-    /// Reply to a request with the given attribute
+    /// Reply to a request with the given attribute. With ABI 7.32, `FUSE_ATTR_SUBMOUNT` in
+    /// the flags of the attribute is sent as well if the filesystem has the `SUBMOUNTS`
+    /// capability. Other flags are not sent.
     pub fn attr(self, ttl: &Duration, attr: &FileAttr, mask: u16, gid: u32) {
         self.reply.ok(&fuse_attr_out {
             attr_valid: ttl.as_secs(),
             attr_valid_nsec: ttl.subsec_nanos(),
             dummy: 0,
-            attr: fuse_attr_from_attr(attr, mask, gid),
+            attr: fuse_attr_from_attr(attr, mask, gid, self.attr_flags),
         });
     }
 
@@ -377,28 +501,171 @@ impl ReplyXTimes {
     }
 }
 
+///
+/// Open reply flags
+///
+/// Flags a filesystem can set when replying to an open or create request to change the way
+/// the file is opened. Flags that are not supported by the kernel's protocol version are
+/// never sent to the kernel.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct OpenReplyFlags(u32);
+
+impl OpenReplyFlags {
+    /// Bypass page cache for this open file
+    pub const DIRECT_IO: OpenReplyFlags = OpenReplyFlags(FOPEN_DIRECT_IO);
+    /// Don't invalidate the data cache on open
+    pub const KEEP_CACHE: OpenReplyFlags = OpenReplyFlags(FOPEN_KEEP_CACHE);
+    /// The file is not seekable (ABI 7.10)
+    #[cfg(feature = "abi-7-10")]
+    pub const NONSEEKABLE: OpenReplyFlags = OpenReplyFlags(FOPEN_NONSEEKABLE);
+    /// Allow caching of this directory (ABI 7.28)
+    #[cfg(feature = "abi-7-28")]
+    pub const CACHE_DIR: OpenReplyFlags = OpenReplyFlags(FOPEN_CACHE_DIR);
+    /// The file is stream-like and has no file position at all (ABI 7.31)
+    #[cfg(feature = "abi-7-31")]
+    pub const STREAM: OpenReplyFlags = OpenReplyFlags(FOPEN_STREAM);
+    /// Don't flush the data cache on close (ABI 7.35)
+    #[cfg(feature = "abi-7-35")]
+    pub const NOFLUSH: OpenReplyFlags = OpenReplyFlags(FOPEN_NOFLUSH);
+    /// Allow concurrent direct writes on the same inode (ABI 7.38)
+    #[cfg(feature = "abi-7-38")]
+    pub const PARALLEL_DIRECT_WRITES: OpenReplyFlags =
+        OpenReplyFlags(FOPEN_PARALLEL_DIRECT_WRITES);
+    /// macOS only: Purge cached attributes on open
+    #[cfg(target_os = "macos")]
+    pub const PURGE_ATTR: OpenReplyFlags = OpenReplyFlags(FOPEN_PURGE_ATTR);
+    /// macOS only: Purge the unified buffer cache on open
+    #[cfg(target_os = "macos")]
+    pub const PURGE_UBC: OpenReplyFlags = OpenReplyFlags(FOPEN_PURGE_UBC);
+
+    /// Returns empty flags
+    pub const fn empty() -> OpenReplyFlags {
+        OpenReplyFlags(0)
+    }
+
+    /// Returns the raw value of the flags
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if all of the given flags are set
+    pub fn contains(self, other: OpenReplyFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if no flags are set
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the flags that are known to the given minor version of the FUSE protocol
+    #[cfg_attr(not(feature = "abi-7-10"), allow(unused_variables))]
+    fn supported_by(self, proto_minor: u32) -> OpenReplyFlags {
+        #[allow(unused_mut)]
+        let mut mask = FOPEN_DIRECT_IO | FOPEN_KEEP_CACHE;
+        #[cfg(feature = "abi-7-10")]
+        {
+            if proto_minor >= 10 {
+                mask |= FOPEN_NONSEEKABLE;
+            }
+        }
+        #[cfg(feature = "abi-7-28")]
+        {
+            if proto_minor >= 28 {
+                mask |= FOPEN_CACHE_DIR;
+            }
+        }
+        #[cfg(feature = "abi-7-31")]
+        {
+            if proto_minor >= 31 {
+                mask |= FOPEN_STREAM;
+            }
+        }
+        #[cfg(feature = "abi-7-35")]
+        {
+            if proto_minor >= 35 {
+                mask |= FOPEN_NOFLUSH;
+            }
+        }
+        #[cfg(feature = "abi-7-38")]
+        {
+            if proto_minor >= 38 {
+                mask |= FOPEN_PARALLEL_DIRECT_WRITES;
+            }
+        }
+        #[cfg(target_os = "macos")]
+        {
+            mask |= FOPEN_PURGE_ATTR | FOPEN_PURGE_UBC;
+        }
+        OpenReplyFlags(self.0 & mask)
+    }
+
+    /// Returns the raw flags to send to a kernel speaking the given protocol version
+    fn to_kernel(self, proto_minor: u32) -> u32 {
+        let supported = self.supported_by(proto_minor);
+        if supported != self {
+            warn!(
+                "Open flags {:#x} not supported by FUSE ABI 7.{}, ignoring them",
+                self.0 & !supported.0,
+                proto_minor
+            );
+        }
+        supported.0
+    }
+}
+
+impl BitOr for OpenReplyFlags {
+    type Output = OpenReplyFlags;
+
+    fn bitor(self, rhs: OpenReplyFlags) -> OpenReplyFlags {
+        OpenReplyFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for OpenReplyFlags {
+    fn bitor_assign(&mut self, rhs: OpenReplyFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for OpenReplyFlags {
+    type Output = OpenReplyFlags;
+
+    fn bitand(self, rhs: OpenReplyFlags) -> OpenReplyFlags {
+        OpenReplyFlags(self.0 & rhs.0)
+    }
+}
+
 ///
 /// Open Reply
 ///
 #[derive(Debug)]
 pub struct ReplyOpen {
     reply: ReplyRaw<fuse_open_out>,
+    proto_minor: u32,
 }
 
 impl Reply for ReplyOpen {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyOpen {
         ReplyOpen {
             reply: Reply::new(unique, sender),
+            proto_minor: FUSE_KERNEL_MINOR_VERSION,
         }
     }
 }
 
 impl ReplyOpen {
+    /// Set the minor version of the FUSE protocol the kernel speaks. Open flags unknown to
+    /// that version are not sent.
+    pub(crate) fn set_proto_minor(&mut self, proto_minor: u32) {
+        self.proto_minor = proto_minor;
+    }
+
     /// Reply to a request with the given open result
-    pub fn opened(self, fh: u64, flags: u32) {
+    pub fn opened(self, fh: u64, flags: OpenReplyFlags) {
         self.reply.ok(&fuse_open_out {
             fh: fh,
-            open_flags: flags,
+            open_flags: flags.to_kernel(self.proto_minor),
             padding: 0,
         });
     }
@@ -485,12 +752,7 @@ impl ReplyStatfs {
         });
     }
 
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
-        ReplyCreate {
-            reply: Reply::new(unique, sender),
-        }
-    }
-
+    /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
     }
@@ -502,17 +764,33 @@ impl ReplyStatfs {
 #[derive(Debug)]
 pub struct ReplyCreate {
     reply: ReplyRaw<(fuse_entry_out, fuse_open_out)>,
+    proto_minor: u32,
+    attr_flags: u32,
 }
 
 impl Reply for ReplyCreate {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
         ReplyCreate {
             reply: Reply::new(unique, sender),
+            proto_minor: FUSE_KERNEL_MINOR_VERSION,
+            attr_flags: 0,
         }
     }
 }
 
+impl AttrReply for ReplyCreate {
+    fn set_attr_flags(&mut self, attr_flags: u32) {
+        self.attr_flags = attr_flags;
+    }
+}
+
 impl ReplyCreate {
+    /// Set the minor version of the FUSE protocol the kernel speaks. Open flags unknown to
+    /// that version are not sent.
+    pub(crate) fn set_proto_minor(&mut self, proto_minor: u32) {
+        self.proto_minor = proto_minor;
+    }
+
     /// Reply to a request with the given entry
     pub fn created(
         self,
@@ -520,10 +798,11 @@ impl ReplyCreate {
         attr: &FileAttr,
         generation: u64,
         fh: u64,
-        flags: u32,
+        flags: OpenReplyFlags,
         mask: u16,
         gid: u32,
     ) {
+        let open_flags = flags.to_kernel(self.proto_minor);
         self.reply.ok(&(
             fuse_entry_out {
                 nodeid: attr.ino,
@@ -532,11 +811,11 @@ impl ReplyCreate {
                 attr_valid: ttl.as_secs(),
                 entry_valid_nsec: ttl.subsec_nanos(),
                 attr_valid_nsec: ttl.subsec_nanos(),
-                attr: fuse_attr_from_attr(attr, mask, gid),
+                attr: fuse_attr_from_attr(attr, mask, gid, self.attr_flags),
             },
             fuse_open_out {
                 fh: fh,
-                open_flags: flags,
+                open_flags,
                 padding: 0,
             },
         ));
@@ -710,7 +989,8 @@ mod test {
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
     use super::ReplyXattr;
-    use super::{Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyRaw};
+    use super::{OpenReplyFlags, Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry};
+    use super::{ReplyOpen, ReplyRaw};
     use super::{ReplyBmap, ReplyCreate, ReplyDirectory, ReplyLock, ReplyStatfs, ReplyWrite};
     use crate::{FileAttr, FileType};
     use std::sync::mpsc::{channel, Sender};
@@ -841,6 +1121,37 @@ mod test {
         reply.data(&[0xde, 0xad, 0xbe, 0xef]);
     }
 
+    #[test]
+    fn reply_data_fd() {
+        let path = std::env::temp_dir().join(format!("rust-fuse-reply-{}", std::process::id()));
+        std::fs::write(&path, [0x00, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // The reply is truncated at the end of the file
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0xde, 0xad, 0xbe, 0xef],
+            ],
+        };
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.splice(std::os::unix::io::AsRawFd::as_raw_fd(&file), 1, 16);
+    }
+
+    /// Returns the fields ABI 7.9 and later append to `fuse_attr` on Linux for the
+    /// attributes of the tests below: blksize and padding, or attribute flags since ABI 7.32,
+    /// which aren't sent since the replies weren't told that the kernel accepts them
+    fn attr_extension() -> Vec<u8> {
+        if cfg!(feature = "abi-7-9") {
+            vec![0x00; 8]
+        } else {
+            vec![]
+        }
+    }
+
     #[test]
     fn reply_entry() {
         let sender = AssertSender {
@@ -866,23 +1177,29 @@ mod test {
                     ],
                 ]
             } else {
+                let attr_ext = attr_extension();
+                let len = 0x88 + attr_ext.len() as u8;
                 vec![
                     vec![
-                        0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                        len, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
                         0x00, 0x00, 0x00, 0x00,
                     ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
+                    [
+                        &[
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ][..],
+                        &attr_ext,
+                    ]
+                    .concat(),
                 ]
             },
         };
@@ -905,7 +1222,7 @@ mod test {
             rdev: 0x88,
             flags: 0x99,
         };
-        reply.entry(&ttl, &attr, 0xaa);
+        reply.entry(&ttl, &attr, 0xaa, 0o645, 0x77);
     }
 
     #[test]
@@ -931,21 +1248,27 @@ mod test {
                     ],
                 ]
             } else {
+                let attr_ext = attr_extension();
+                let len = 0x70 + attr_ext.len() as u8;
                 vec![
                     vec![
-                        0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                        len, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
                         0x00, 0x00, 0x00, 0x00,
                     ],
-                    vec![
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
+                    [
+                        &[
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ][..],
+                        &attr_ext,
+                    ]
+                    .concat(),
                 ]
             },
         };
@@ -968,7 +1291,7 @@ mod test {
             rdev: 0x88,
             flags: 0x99,
         };
-        reply.attr(&ttl, &attr);
+        reply.attr(&ttl, &attr, 0o645, 0x77);
     }
 
     #[test]
@@ -1000,13 +1323,34 @@ mod test {
                     0x00, 0x00, 0x00,
                 ],
                 vec![
-                    0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00, 0x00,
+                    0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
                     0x00, 0x00, 0x00,
                 ],
             ],
         };
         let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
-        reply.opened(0x1122, 0x33);
+        reply.opened(0x1122, OpenReplyFlags::DIRECT_IO | OpenReplyFlags::KEEP_CACHE);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-10")]
+    fn reply_open_unsupported_flags() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![
+                    0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+            ],
+        };
+        // FOPEN_NONSEEKABLE must not be sent to a kernel speaking ABI 7.9
+        let mut reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
+        reply.set_proto_minor(9);
+        reply.opened(0x1122, OpenReplyFlags::DIRECT_IO | OpenReplyFlags::NONSEEKABLE);
     }
 
     #[test]
@@ -1069,29 +1413,37 @@ mod test {
                         0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
                         0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
                         0x99, 0x00, 0x00, 0x00, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                     ],
                 ]
             } else {
+                let attr_ext = attr_extension();
+                let len = 0x98 + attr_ext.len() as u8;
                 vec![
                     vec![
-                        0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
+                        len, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
                         0x00, 0x00, 0x00, 0x00,
                     ],
+                    [
+                        &[
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ][..],
+                        &attr_ext,
+                        &[
+                            0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                    .concat(),
                 ]
             },
         };
@@ -1114,7 +1466,42 @@ mod test {
             rdev: 0x88,
             flags: 0x99,
         };
-        reply.created(&ttl, &attr, 0xaa, 0xbb, 0xcc);
+        reply.created(
+            &ttl,
+            &attr,
+            0xaa,
+            0xbb,
+            OpenReplyFlags::DIRECT_IO | OpenReplyFlags::KEEP_CACHE,
+            0o645,
+            0x77,
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    fn attr_submount_flag() {
+        use fuse_abi::consts::FUSE_ATTR_SUBMOUNT;
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr {
+            ino: 0x11,
+            size: 0x22,
+            blocks: 0x33,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 0x55,
+            uid: 0x66,
+            gid: 0x77,
+            rdev: 0x88,
+            flags: FUSE_ATTR_SUBMOUNT | 1 << 31,
+        };
+        let fuse_attr = super::fuse_attr_from_attr(&attr, 0o755, 0x77, FUSE_ATTR_SUBMOUNT);
+        assert_eq!(fuse_attr.flags, FUSE_ATTR_SUBMOUNT);
+        // No flags are sent unless the kernel agreed to receive them
+        assert_eq!(super::fuse_attr_from_attr(&attr, 0o755, 0x77, 0).flags, 0);
     }
 
     #[test]
@@ -6,17 +6,29 @@
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
 use std::convert::TryFrom;
+#[cfg(feature = "abi-7-36")]
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::io::RawFd;
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use libc::{EIO, ENOSYS, EPROTO};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc::{c_int, EIO, ENOSYS, EPROTO};
 use fuse_abi::*;
 use fuse_abi::consts::*;
 use log::{debug, error, warn};
+use serde_derive::{Deserialize, Serialize};
 
+use crate::buffer::{RequestBuffer, WriteData};
 use crate::channel::ChannelSender;
+use crate::deadline::{Deadline, SuppressedHandler};
 use crate::ll;
 use crate::reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory};
-use crate::session::MAX_WRITE_SIZE;
+use crate::reply::{AttrReply, ReplyCreate, ReplyOpen, ReplySender};
+use crate::scheduler::{Deferred, Permit, RequestClass};
+use crate::session::{RequestErrorCounters, MAX_WRITE_SIZE};
+use crate::stats::{self, RequestStats, Stats};
 use crate::Filesystem;
 
 /// We generally support async reads
@@ -30,15 +42,219 @@ const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
+/// Extended init flags (flags2) we support: security contexts on inode creation
+#[cfg(all(feature = "abi-7-36", not(feature = "abi-7-38"), not(target_os = "macos")))]
+const INIT_FLAGS2: u32 = (FUSE_SECURITY_CTX >> 32) as u32;
+
+/// Extended init flags (flags2) we support: security contexts and supplementary groups on
+/// inode creation
+#[cfg(all(feature = "abi-7-38", not(target_os = "macos")))]
+const INIT_FLAGS2: u32 = ((FUSE_SECURITY_CTX | FUSE_CREATE_SUPP_GROUP) >> 32) as u32;
+
+/// Set of operations the filesystem doesn't implement, learned from ENOSYS replies. Only
+/// operations the kernel itself remembers as unimplemented are tracked (see
+/// `cached_by_kernel`), since other operations may fail with ENOSYS temporarily or for some
+/// inodes only.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UnimplementedOps(AtomicU64);
+
+impl UnimplementedOps {
+    /// Remember that the operation with the given opcode is unimplemented, if the kernel
+    /// remembers that as well
+    fn insert(&self, opcode: u32) {
+        if cached_by_kernel(opcode) {
+            self.0.fetch_or(1 << opcode, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns true if the operation with the given opcode is known to be unimplemented
+    pub fn contains(&self, opcode: u32) -> bool {
+        opcode < 64 && self.0.load(Ordering::Relaxed) & (1 << opcode) != 0
+    }
+}
+
+/// Returns true if the kernel stops sending the operation with the given opcode after an
+/// ENOSYS reply to it (and handles it locally instead)
+fn cached_by_kernel(opcode: u32) -> bool {
+    match fuse_opcode::try_from(opcode) {
+        Ok(fuse_opcode::FUSE_FSYNC)
+        | Ok(fuse_opcode::FUSE_SETXATTR)
+        | Ok(fuse_opcode::FUSE_GETXATTR)
+        | Ok(fuse_opcode::FUSE_LISTXATTR)
+        | Ok(fuse_opcode::FUSE_REMOVEXATTR)
+        | Ok(fuse_opcode::FUSE_FLUSH)
+        | Ok(fuse_opcode::FUSE_FSYNCDIR)
+        | Ok(fuse_opcode::FUSE_ACCESS)
+        | Ok(fuse_opcode::FUSE_CREATE)
+        | Ok(fuse_opcode::FUSE_BMAP) => true,
+        #[cfg(feature = "abi-7-11")]
+        Ok(fuse_opcode::FUSE_POLL) => true,
+        #[cfg(feature = "abi-7-19")]
+        Ok(fuse_opcode::FUSE_FALLOCATE) => true,
+        #[cfg(feature = "abi-7-23")]
+        Ok(fuse_opcode::FUSE_RENAME2) => true,
+        #[cfg(feature = "abi-7-24")]
+        Ok(fuse_opcode::FUSE_LSEEK) => true,
+        #[cfg(feature = "abi-7-28")]
+        Ok(fuse_opcode::FUSE_COPY_FILE_RANGE) => true,
+        #[cfg(feature = "abi-7-34")]
+        Ok(fuse_opcode::FUSE_SYNCFS) => true,
+        #[cfg(feature = "abi-7-37")]
+        Ok(fuse_opcode::FUSE_TMPFILE) => true,
+        _ => false,
+    }
+}
+
+/// Returns true if we support the given version of the FUSE protocol (ABI 7.6 or later)
+fn supported_version(major: u32, minor: u32) -> bool {
+    major > 7 || (major == 7 && minor >= 6)
+}
+
+/// Number of requests dispatched to the filesystem whose replies weren't sent or dropped yet,
+/// shared by all workers of a session
+#[derive(Debug, Default)]
+pub(crate) struct PendingReplies {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl PendingReplies {
+    /// Wait until all pending replies were sent or dropped, but at most for the given time.
+    /// Returns the number of replies that are still pending.
+    fn drain(&self, timeout: Duration) -> usize {
+        let (count, _) = self
+            .changed
+            .wait_timeout_while(self.lock(), timeout, |count| *count > 0)
+            .unwrap_or_else(|err| err.into_inner());
+        *count
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.count.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Counts a request as pending until the request and all its reply objects are dropped
+#[derive(Debug)]
+struct PendingReply(Arc<PendingReplies>);
+
+impl PendingReply {
+    fn new(pending: &Arc<PendingReplies>) -> PendingReply {
+        *pending.lock() += 1;
+        PendingReply(pending.clone())
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        *self.0.lock() -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Reply sender of a request. Sends replies to the channel and learns about unimplemented
+/// operations from replies with ENOSYS. Holds the request's slot of the scheduler (if any)
+/// until the reply is sent. Replies after the watchdog replied on the request's deadline are
+/// suppressed. Replies are counted in the statistics of the session and recorded in the
+/// request's span (with the `tracing` feature), which stays open until the reply was sent.
+#[derive(Clone, Debug)]
+struct RequestSender {
+    ch: ChannelSender,
+    opcode: u32,
+    unimplemented: Arc<UnimplementedOps>,
+    permit: Option<Arc<Permit>>,
+    deadline: Option<Arc<Deadline>>,
+    stats: Option<RequestStats>,
+    pending: Option<Arc<PendingReply>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl RequestSender {
+    /// Count the reply with the given error (0 if successful) that read or wrote the given
+    /// number of bytes, and record it in the request's span
+    fn replied(&self, errno: c_int, bytes: Option<u64>) {
+        if let Some(stats) = &self.stats {
+            stats.reply(errno, bytes);
+        }
+        #[cfg(feature = "tracing")]
+        {
+            if errno != 0 {
+                self.span.record("errno", errno);
+            }
+            if let Some(bytes) = bytes {
+                self.span.record("bytes", bytes);
+            }
+        }
+    }
+
+    /// Returns false if the watchdog replied already
+    fn reply(&self) -> bool {
+        match &self.deadline {
+            Some(deadline) if !deadline.reply() => {
+                warn!(
+                    "Suppressing reply to request {} after its deadline passed",
+                    deadline.unique()
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+impl ReplySender for RequestSender {
+    fn send(&self, data: &[&[u8]]) {
+        self.send_reply(stats::reply_errno(data), data);
+    }
+
+    fn send_reply(&self, errno: c_int, data: &[&[u8]]) {
+        if !self.reply() {
+            if let (0, Some(deadline)) = (errno, &self.deadline) {
+                deadline.suppressed(self.opcode, data);
+            }
+            return;
+        }
+        if errno == ENOSYS {
+            self.unimplemented.insert(self.opcode);
+        }
+        let bytes = match errno {
+            0 => stats::reply_bytes(self.opcode, data),
+            _ => None,
+        };
+        self.replied(errno, bytes);
+        ReplySender::send(&self.ch, data);
+    }
+
+    fn send_fd(&self, unique: u64, fd: RawFd, offset: i64, len: usize) -> io::Result<usize> {
+        if !self.reply() {
+            return Ok(0);
+        }
+        let sent = self.ch.send_fd(unique, fd, offset, len)?;
+        self.replied(0, Some(sent as u64));
+        Ok(sent)
+    }
+}
+
 /// Request data structure
 #[derive(Debug)]
 pub struct Request<'a> {
-    /// Channel sender for sending the reply
-    ch: ChannelSender,
+    /// Sender for sending the reply
+    ch: RequestSender,
     /// Request raw data
     data: &'a [u8],
     /// Parsed request
     request: ll::Request<'a>,
+    /// Pooled buffer the request was received into
+    buffer: Option<Arc<RequestBuffer>>,
+    /// When the request was received
+    received: Instant,
+    /// Attribute flags the kernel agreed to receive in replies
+    attr_flags: u32,
+    /// True if the filesystem handles killing the setuid and setgid bits (negotiated
+    /// `FUSE_HANDLE_KILLPRIV_V2`)
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    handle_killpriv: bool,
 }
 
 
@@ -50,31 +266,90 @@ pub trait RequestDispatcher {
     /// Dispatch a fuse Reques on the filesystem and save proto/state into the session store
     ///
     fn dispatch(&mut self, request: &mut Request<'_>, se: &mut super::session::FuseSessionStore);
+
+    ///
+    /// Destroy the filesystem after the session ended if the kernel didn't send a destroy request
+    ///
+    fn destroy_unmounted(&mut self, ch: ChannelSender, se: &mut super::session::FuseSessionStore);
 }
 
 
 impl<T: Filesystem> RequestDispatcher for T {
     fn dispatch(&mut self, request: &mut Request<'_>, se: &mut super::session::FuseSessionStore) {
         debug!("{}", request.request);
+        #[cfg(feature = "tracing")]
+        let _span = request.set_span().entered();
+        request.set_stats(&se.stats);
+        request.set_pending(&se.pending);
+        if let Some(watchdog) = &se.watchdog {
+            watchdog.watch(request);
+        }
+        #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+        {
+            if se.init_flags & FUSE_SUBMOUNTS != 0 && se.proto_minor >= 32 {
+                request.attr_flags = FUSE_ATTR_SUBMOUNT;
+            }
+        }
+        #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+        {
+            request.handle_killpriv = se.init_flags & FUSE_HANDLE_KILLPRIV_V2 != 0;
+        }
         match request.request.operation() {
             // Filesystem initialization
-            ll::Operation::Init { arg } => {
+            ll::Operation::Init {
+                arg,
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                ext,
+            } => {
                 let reply: ReplyRaw<fuse_init_out> = request.reply();
                 // We don't support ABI versions before 7.6
-                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
+                if !supported_version(arg.major, arg.minor) {
                     error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
                     reply.error(EPROTO);
                     return;
                 }
-                // Remember ABI version supported by kernel
+                // Remember ABI version supported by kernel and us. Replies must not use
+                // anything newer than this negotiated version.
                 se.proto_major = arg.major;
-                se.proto_minor = arg.minor;
+                se.proto_minor = arg.minor.min(FUSE_KERNEL_MINOR_VERSION);
                 // Call filesystem init method and give it a chance to return an error
                 let res = self.init(request);
                 if let Err(err) = res {
                     reply.error(err);
                     return;
                 }
+                // Extended flags are only known if the kernel sent the extended init request
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                let flags2 = ext.map_or(0, |ext| ext.flags2 & INIT_FLAGS2);
+                // Exchange requests through io_uring if the session asks for it and the kernel
+                // supports it
+                #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+                let flags2 = match ext {
+                    Some(ext) if se.uring_depth > 0 => {
+                        flags2 | (ext.flags2 & (FUSE_OVER_IO_URING >> 32) as u32)
+                    }
+                    _ => flags2,
+                };
+                #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+                {
+                    se.uring = flags2 & (FUSE_OVER_IO_URING >> 32) as u32 != 0;
+                }
+                // use features given in INIT_FLAGS or by the filesystem and reported as capable
+                #[allow(unused_mut)]
+                let mut flags = arg.flags & (INIT_FLAGS | self.capabilities().bits());
+                se.init_flags = flags;
+                #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                {
+                    if flags2 != 0 {
+                        flags |= FUSE_INIT_EXT;
+                    }
+                }
+                // Use a max write size that fits into the session's buffer, or the smaller one
+                // the session asked for
+                let max_write = match se.max_write {
+                    0 => MAX_WRITE_SIZE as u32,
+                    max_write => max_write,
+                };
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
                 // supports only lower major versions, we replied with an error above.
@@ -82,15 +357,51 @@ impl<T: Filesystem> RequestDispatcher for T {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
                     max_readahead: arg.max_readahead, // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS, // use features given in INIT_FLAGS and reported as capable
+                    flags,
+                    #[cfg(not(feature = "abi-7-13"))]
                     unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32, // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-13")]
+                    max_background: 0,
+                    #[cfg(feature = "abi-7-13")]
+                    congestion_threshold: 0,
+                    max_write,
+                    #[cfg(feature = "abi-7-23")]
+                    time_gran: 0,
+                    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
+                    unused: [0; 9],
+                    #[cfg(feature = "abi-7-28")]
+                    max_pages: 0,
+                    #[cfg(all(feature = "abi-7-28", not(feature = "abi-7-31")))]
+                    padding: 0,
+                    #[cfg(feature = "abi-7-31")]
+                    map_alignment: 0,
+                    #[cfg(all(feature = "abi-7-28", not(feature = "abi-7-36")))]
+                    unused: [0; 8],
+                    #[cfg(all(feature = "abi-7-36", not(target_os = "macos")))]
+                    flags2,
+                    #[cfg(all(feature = "abi-7-36", target_os = "macos"))]
+                    flags2: 0,
+                    #[cfg(all(feature = "abi-7-36", not(feature = "abi-7-40")))]
+                    unused: [0; 7],
+                    #[cfg(feature = "abi-7-40")]
+                    max_stack_depth: 0,
+                    #[cfg(feature = "abi-7-40")]
+                    unused: [0; 6],
                 };
                 debug!(
                     "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
                     init.major, init.minor, init.flags, init.max_readahead, init.max_write
                 );
+                se.max_write = init.max_write;
                 se.initialized = true;
+                // Kernels before ABI 7.23 expect the smaller init reply of ABI 7.22
+                #[cfg(feature = "abi-7-23")]
+                {
+                    if se.proto_minor < 23 {
+                        reply.ok_truncated(&init, FUSE_COMPAT_22_INIT_OUT_SIZE);
+                        return;
+                    }
+                }
                 reply.ok(&init);
             }
             // Any operation is invalid before initialization
@@ -109,6 +420,20 @@ impl<T: Filesystem> RequestDispatcher for T {
                 warn!("Ignoring FUSE operation after destroy: {}", request.request);
                 request.reply::<ReplyEmpty>().error(EIO);
             }
+            // Don't call operations the filesystem and the kernel consider unimplemented
+            _ if se.unimplemented.contains(request.request.opcode()) => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            // The filesystem doesn't need open/opendir and the kernel supports skipping
+            // them if we reply with ENOSYS
+            #[cfg(feature = "abi-7-23")]
+            ll::Operation::Open { .. } if se.init_flags & FUSE_NO_OPEN_SUPPORT != 0 => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-29")]
+            ll::Operation::OpenDir { .. } if se.init_flags & FUSE_NO_OPENDIR_SUPPORT != 0 => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
 
             ll::Operation::Interrupt { .. } => {
                 // TODO: handle FUSE_INTERRUPT
@@ -116,13 +441,13 @@ impl<T: Filesystem> RequestDispatcher for T {
             }
 
             ll::Operation::Lookup { name } => {
-                self.lookup(request, request.request.nodeid(), &name, request.reply());
+                self.lookup(request, request.request.nodeid(), &name, request.attr_reply());
             }
             ll::Operation::Forget { arg } => {
                 self.forget(request, request.request.nodeid(), arg.nlookup); // no reply
             }
             ll::Operation::GetAttr => {
-                self.getattr(request, request.request.nodeid(), request.reply());
+                self.getattr(request, request.request.nodeid(), request.attr_reply());
             }
             ll::Operation::SetAttr { arg } => {
                 let mode = match arg.valid & FATTR_MODE {
@@ -208,7 +533,7 @@ impl<T: Filesystem> RequestDispatcher for T {
                     chgtime,
                     bkuptime,
                     flags,
-                    request.reply(),
+                    request.attr_reply(),
                 );
             }
             ll::Operation::ReadLink => {
@@ -221,7 +546,7 @@ impl<T: Filesystem> RequestDispatcher for T {
                     &name,
                     arg.mode,
                     arg.rdev,
-                    request.reply(),
+                    request.attr_reply(),
                 );
             }
             ll::Operation::MkDir { arg, name } => {
@@ -230,7 +555,7 @@ impl<T: Filesystem> RequestDispatcher for T {
                     request.request.nodeid(),
                     &name,
                     arg.mode,
-                    request.reply(),
+                    request.attr_reply(),
                 );
             }
             ll::Operation::Unlink { name } => {
@@ -245,7 +570,7 @@ impl<T: Filesystem> RequestDispatcher for T {
                     request.request.nodeid(),
                     &name,
                     &Path::new(link),
-                    request.reply(),
+                    request.attr_reply(),
                 );
             }
             ll::Operation::Rename { arg, name, newname } => {
@@ -264,16 +589,13 @@ impl<T: Filesystem> RequestDispatcher for T {
                     arg.oldnodeid,
                     request.request.nodeid(),
                     &name,
-                    request.reply(),
+                    request.attr_reply(),
                 );
             }
             ll::Operation::Open { arg } => {
-                self.open(
-                    request,
-                    request.request.nodeid(),
-                    arg.flags,
-                    request.reply(),
-                );
+                let mut reply: ReplyOpen = request.reply();
+                reply.set_proto_minor(se.proto_minor);
+                self.open(request, request.request.nodeid(), arg.flags, reply);
             }
             ll::Operation::Read { arg } => {
                 self.read(
@@ -335,12 +657,9 @@ impl<T: Filesystem> RequestDispatcher for T {
                 );
             }
             ll::Operation::OpenDir { arg } => {
-                self.opendir(
-                    request,
-                    request.request.nodeid(),
-                    arg.flags,
-                    request.reply(),
-                );
+                let mut reply: ReplyOpen = request.reply();
+                reply.set_proto_minor(se.proto_minor);
+                self.opendir(request, request.request.nodeid(), arg.flags, reply);
             }
             ll::Operation::ReadDir { arg } => {
                 self.readdir(
@@ -348,7 +667,11 @@ impl<T: Filesystem> RequestDispatcher for T {
                     request.request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
-                    ReplyDirectory::new(request.request.unique(), request.ch, arg.size as usize),
+                    ReplyDirectory::new(
+                        request.request.unique(),
+                        request.ch.clone(),
+                        arg.size as usize,
+                    ),
                 );
             }
             ll::Operation::ReleaseDir { arg } => {
@@ -417,13 +740,15 @@ impl<T: Filesystem> RequestDispatcher for T {
                 self.access(request, request.request.nodeid(), arg.mask, request.reply());
             }
             ll::Operation::Create { arg, name } => {
+                let mut reply: ReplyCreate = request.attr_reply();
+                reply.set_proto_minor(se.proto_minor);
                 self.create(
                     request,
                     request.request.nodeid(),
                     &name,
                     arg.mode,
                     arg.flags,
-                    request.reply(),
+                    reply,
                 );
             }
             ll::Operation::GetLk { arg } => {
@@ -476,6 +801,57 @@ impl<T: Filesystem> RequestDispatcher for T {
                     request.reply(),
                 );
             }
+            #[cfg(feature = "abi-7-15")]
+            ll::Operation::NotifyReply { .. } => {
+                // TODO: handle notify replies (retrieve); no reply expected by the kernel
+            }
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    self.forget(request, node.nodeid, node.nlookup); // no reply
+                }
+            }
+            // TODO: Operations of newer ABI versions aren't supported by `Filesystem` yet
+            #[cfg(feature = "abi-7-11")]
+            ll::Operation::IoCtl { .. } | ll::Operation::Poll { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-19")]
+            ll::Operation::FAllocate { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-21")]
+            ll::Operation::ReadDirPlus { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-23")]
+            ll::Operation::Rename2 { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-24")]
+            ll::Operation::Lseek { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-28")]
+            ll::Operation::CopyFileRange { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-31")]
+            ll::Operation::SetupMapping { .. } | ll::Operation::RemoveMapping { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-34")]
+            ll::Operation::SyncFs { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-37")]
+            ll::Operation::TmpFile { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-12")]
+            ll::Operation::CuseInit { .. } => {
+                request.reply::<ReplyEmpty>().error(ENOSYS);
+            }
 
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName { name } => {
@@ -508,27 +884,285 @@ impl<T: Filesystem> RequestDispatcher for T {
             }
         }
     }
+
+    fn destroy_unmounted(&mut self, ch: ChannelSender, se: &mut super::session::FuseSessionStore) {
+        if !se.initialized || se.destroyed {
+            return;
+        }
+        // Let the filesystem finish requests it still replies to (e.g. from its own threads)
+        let pending = se.pending.drain(se.drain_timeout);
+        if pending > 0 {
+            warn!("Destroying filesystem with {} requests still pending", pending);
+        }
+        debug!("Destroying filesystem after unmount");
+        let unimplemented = se.unimplemented.clone();
+        let request = Request::with_sender(ch, &[], ll::Request::destroy(), unimplemented);
+        self.destroy(&request);
+        se.destroyed = true;
+    }
 }
 
 impl<'a> Request<'a> {
-    /// Create a new request from the given data
-    pub fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
-        let request = match ll::Request::try_from(data) {
-            Ok(request) => request,
-            Err(err) => {
-                // FIXME: Reply with ENOSYS?
-                error!("{}", err);
-                return None;
+    /// Create a new request from the given data. Replies with ENOSYS are remembered in the
+    /// given set of unimplemented operations.
+    pub fn new(
+        ch: ChannelSender,
+        data: &'a [u8],
+        unimplemented: Arc<UnimplementedOps>,
+    ) -> Result<Request<'a>, ll::RequestError> {
+        let request = ll::Request::try_from(data)?;
+
+        #[cfg(feature = "abi-7-38")]
+        for ext in request.extensions() {
+            if let ll::Extension::Unknown { typ, data } = ext {
+                warn!("Ignoring unknown request extension {} ({} bytes)", typ, data.len());
             }
+        }
+
+        Ok(Self::with_sender(ch, data, request, unimplemented))
+    }
+
+    /// Create a new request for the given parsed data
+    fn with_sender(
+        ch: ChannelSender,
+        data: &'a [u8],
+        request: ll::Request<'a>,
+        unimplemented: Arc<UnimplementedOps>,
+    ) -> Request<'a> {
+        let ch = RequestSender {
+            ch,
+            opcode: request.opcode(),
+            unimplemented,
+            permit: None,
+            deadline: None,
+            stats: None,
+            pending: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
         };
+        Self {
+            ch,
+            data,
+            request,
+            buffer: None,
+            received: Instant::now(),
+            attr_flags: 0,
+            #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+            handle_killpriv: false,
+        }
+    }
 
-        Some(Self { ch, data, request})
+    /// Reply to a request that failed to parse, so that the process waiting for it doesn't
+    /// hang. Unknown operations are answered with ENOSYS, malformed requests with EIO. Requests
+    /// without a complete header can't be answered, and requests that don't expect a reply
+    /// (e.g. forget) aren't.
+    pub fn reply_invalid(
+        ch: ChannelSender,
+        data: &[u8],
+        err: &ll::RequestError,
+        counters: &mut RequestErrorCounters,
+    ) {
+        let errno = match err {
+            ll::RequestError::UnknownOperation(opcode) => {
+                warn!("Replying ENOSYS to unknown FUSE operation {}", opcode);
+                counters.unknown_operation += 1;
+                ENOSYS
+            }
+            err => {
+                error!("Replying EIO to malformed FUSE request: {}", err);
+                counters.malformed += 1;
+                EIO
+            }
+        };
+        if let Some(unique) = ll::request_unique(data) {
+            let reply: ReplyEmpty = Reply::new(unique, ch);
+            reply.error(errno);
+        }
     }
 
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.ch.clone())
+    }
+
+    /// Create a reply object for this request that sends file attributes with the
+    /// attribute flags the kernel agreed to receive
+    fn attr_reply<T: AttrReply>(&self) -> T {
+        let mut reply: T = self.reply();
+        reply.set_attr_flags(self.attr_flags);
+        reply
+    }
+
+    /// Attach the pooled buffer the request was received into
+    pub(crate) fn set_buffer(&mut self, buffer: Arc<RequestBuffer>) {
+        self.buffer = Some(buffer);
+    }
+
+    /// Attach the slot of the scheduler the request was admitted with. It's released once the
+    /// request was replied to.
+    pub(crate) fn set_permit(&mut self, permit: Permit) {
+        self.ch.permit = Some(Arc::new(permit));
+    }
+
+    /// Count the request in the given statistics of its session, and its reply once it's sent
+    pub(crate) fn set_stats(&mut self, stats: &Arc<Stats>) {
+        stats.request(self.opcode());
+        // Requests without a class aren't replied to or are answered right away
+        #[cfg(feature = "control")]
+        {
+            if self.class().is_some() {
+                stats.dispatched(self, self.received);
+            }
+        }
+        self.ch.stats = Some(RequestStats::new(
+            stats.clone(),
+            self.unique(),
+            self.opcode(),
+            self.received,
+        ));
+    }
+
+    /// Count the request as pending in the given set until it's replied to
+    pub(crate) fn set_pending(&mut self, pending: &Arc<PendingReplies>) {
+        self.ch.pending = Some(Arc::new(PendingReply::new(pending)));
+    }
+
+    /// Create the span of the request, which stays open until the reply was sent and records
+    /// the error or the number of bytes read or written by the reply
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_span(&mut self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "fuse_request",
+            unique = self.unique(),
+            opcode = tracing::field::Empty,
+            nodeid = self.nodeid(),
+            uid = self.uid(),
+            gid = self.gid(),
+            pid = self.pid(),
+            errno = tracing::field::Empty,
+            bytes = tracing::field::Empty,
+        );
+        if let Ok(opcode) = fuse_opcode::try_from(self.opcode()) {
+            span.record("opcode", tracing::field::debug(opcode));
+        }
+        self.ch.span = span.clone();
+        span
+    }
+
+    /// Returns the span of this request, e.g. to instrument work that replies from another
+    /// thread. Filesystem methods are called within the span.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.ch.span
+    }
+
+    /// Returns true if this is an init request with a version of the FUSE protocol we
+    /// support, i.e. the filesystem's init method is called when dispatching it
+    #[cfg(feature = "async")]
+    pub(crate) fn is_supported_init(&self) -> bool {
+        match self.request.operation() {
+            ll::Operation::Init { arg, .. } => supported_version(arg.major, arg.minor),
+            _ => false,
+        }
+    }
+
+    /// Set when the request was received, if it was deferred since
+    pub(crate) fn set_received(&mut self, received: Instant) {
+        self.received = received;
+    }
+
+    /// Set the deadline of the request the given time after it was received, after which the
+    /// watchdog replies on behalf of the filesystem. A later reply of the filesystem is
+    /// reported to the given handler.
+    pub(crate) fn set_deadline(
+        &mut self,
+        timeout: Duration,
+        suppressed: Option<SuppressedHandler>,
+    ) -> Arc<Deadline> {
+        let deadline = Arc::new(Deadline::new(
+            self.unique(),
+            self.nodeid(),
+            self.ch.ch.clone(),
+            self.received + timeout,
+            self.ch.permit.clone(),
+            self.ch.stats.clone(),
+            #[cfg(feature = "tracing")]
+            self.ch.span.clone(),
+            suppressed,
+        ));
+        self.ch.deadline = Some(deadline.clone());
+        deadline
+    }
+
+    /// Returns when the watchdog replies to this request with an error if the filesystem
+    /// didn't reply by then (see `Session::set_deadlines`)
+    pub fn deadline(&self) -> Option<Instant> {
+        self.ch.deadline.as_ref().map(|deadline| deadline.at())
+    }
+
+    /// Returns the time left to reply to this request, zero once its deadline passed
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline().map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the class of the request for scheduling, None for requests that are
+    /// dispatched out-of-band (init, destroy, forget, interrupt and notify reply)
+    pub(crate) fn class(&self) -> Option<RequestClass> {
+        match self.request.operation() {
+            ll::Operation::Init { .. } | ll::Operation::Destroy => None,
+            ll::Operation::Forget { .. } | ll::Operation::Interrupt { .. } => None,
+            #[cfg(feature = "abi-7-15")]
+            ll::Operation::NotifyReply { .. } => None,
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { .. } => None,
+            ll::Operation::Flush { .. }
+            | ll::Operation::Release { .. }
+            | ll::Operation::ReleaseDir { .. } => Some(RequestClass::Release),
+            ll::Operation::Read { .. }
+            | ll::Operation::Write { .. }
+            | ll::Operation::FSync { .. } => Some(RequestClass::Data),
+            #[cfg(feature = "abi-7-19")]
+            ll::Operation::FAllocate { .. } => Some(RequestClass::Data),
+            #[cfg(feature = "abi-7-24")]
+            ll::Operation::Lseek { .. } => Some(RequestClass::Data),
+            #[cfg(feature = "abi-7-28")]
+            ll::Operation::CopyFileRange { .. } => Some(RequestClass::Data),
+            _ => Some(RequestClass::Metadata),
+        }
+    }
+
+    /// Returns the unique identifier of the request an interrupt request interrupts
+    pub(crate) fn interrupted(&self) -> Option<u64> {
+        match self.request.operation() {
+            ll::Operation::Interrupt { arg } => Some(arg.unique),
+            _ => None,
+        }
+    }
+
+    /// Keep the request to dispatch it later
+    pub(crate) fn deferred(&self) -> Deferred {
+        Deferred::new(
+            self.ch.ch.clone(),
+            self.unique(),
+            self.data,
+            self.buffer.as_ref(),
+            self.received,
+        )
+    }
+
+    /// Returns the data of a write request, which can be kept after the request was
+    /// dispatched. Only available if the session receives requests into a buffer pool
+    /// (see `Session::set_buffer_pool`), otherwise the data needs to be copied.
+    pub fn write_data(&self) -> Option<WriteData> {
+        let buffer = self.buffer.as_ref()?;
+        match self.request.operation() {
+            ll::Operation::Write { data, .. } => {
+                let start = data.as_ptr() as usize - buffer.as_ptr() as usize;
+                Some(WriteData::new(buffer.clone(), start..start + data.len()))
+            }
+            _ => None,
+        }
     }
 
     /// Returns the unique identifier of this request
@@ -558,4 +1192,269 @@ impl<'a> Request<'a> {
     pub fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns the opcode of this request
+    #[inline]
+    #[allow(dead_code)]
+    pub(crate) fn opcode(&self) -> u32 {
+        self.request.opcode()
+    }
+
+    /// Returns the node id of the inode this request is targeted to
+    #[inline]
+    pub(crate) fn nodeid(&self) -> u64 {
+        self.request.nodeid()
+    }
+
+    /// Returns the security context (name and value of the extended attribute) the new inode
+    /// should be labeled with. Only set for create, mkdir, mknod and symlink requests and only
+    /// if the kernel sends security contexts (ABI 7.36).
+    #[cfg(feature = "abi-7-36")]
+    #[inline]
+    pub fn security_context(&self) -> Option<(&OsStr, &[u8])> {
+        self.request.security_context().map(|ctx| (ctx.name, ctx.value))
+    }
+
+    /// Returns the supplementary groups of the process that triggered this request. The kernel
+    /// only sends them with create, mkdir, mknod and symlink requests and only the group of
+    /// the parent directory if the process is a member of it, so that the filesystem can
+    /// implement setgid directories correctly (ABI 7.38).
+    #[cfg(feature = "abi-7-38")]
+    pub fn supplementary_groups(&self) -> &[u32] {
+        self.request
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext {
+                ll::Extension::SupplementaryGroups(groups) => Some(*groups),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    /// Returns true if the kernel expects the filesystem to clear the setuid and setgid bits
+    /// of the file this request operates on. Set for write, truncating setattr, open with
+    /// O_TRUNC and create requests if the filesystem has the `HANDLE_KILLPRIV_V2` capability
+    /// (ABI 7.33).
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub fn kill_suidgid(&self) -> bool {
+        self.handle_killpriv && self.request.kill_suidgid()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PendingReplies, PendingReply, Request, UnimplementedOps};
+    use crate::reply::ReplyData;
+    use crate::stats::Stats;
+    use crate::test_util::{channel, RawRequest};
+    use fuse_abi::fuse_opcode;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn unimplemented_ops() {
+        let unimplemented = UnimplementedOps::default();
+        unimplemented.insert(fuse_opcode::FUSE_GETXATTR as u32);
+        unimplemented.insert(fuse_opcode::FUSE_LOOKUP as u32);
+        assert!(unimplemented.contains(fuse_opcode::FUSE_GETXATTR as u32));
+        // The kernel doesn't remember ENOSYS replies to lookups, which may be temporary
+        assert!(!unimplemented.contains(fuse_opcode::FUSE_LOOKUP as u32));
+    }
+
+    #[test]
+    fn drain_pending_replies() {
+        let pending = Arc::new(PendingReplies::default());
+        let reply = Arc::new(PendingReply::new(&pending));
+        let clone = reply.clone();
+        drop(reply);
+        let replier = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(clone);
+        });
+        assert_eq!(pending.drain(Duration::from_secs(5)), 0);
+        replier.join().unwrap();
+
+        let _leaked = PendingReply::new(&pending);
+        assert_eq!(pending.drain(Duration::from_millis(10)), 1);
+    }
+
+    #[test]
+    fn count_bytes_sent_from_fd() {
+        let path = std::env::temp_dir().join(format!("rust-fuse-send-fd-{}", std::process::id()));
+        fs::write(&path, [0x00, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Reading 16 bytes at offset 1 only sends the 4 bytes up to the end of the file
+        let (ch, mut kernel) = channel();
+        let stats = Arc::new(Stats::default());
+        let data = RawRequest::new(fuse_opcode::FUSE_READ, 1, 1, &[0; 40]);
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        let mut req = Request::new(ch.sender(), data.as_bytes(), unimplemented).unwrap();
+        req.set_stats(&stats);
+        let reply: ReplyData = req.reply();
+        reply.splice(file.as_raw_fd(), 1, 16);
+        let mut buffer = [0u8; 20];
+        kernel.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[16..], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(stats.snapshot().bytes_read, 4);
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    fn kill_suidgid_if_negotiated() {
+        use super::RequestDispatcher;
+        use crate::session::FuseSessionStore;
+        use crate::{Filesystem, ReplyWrite};
+        use fuse_abi::consts::{FUSE_HANDLE_KILLPRIV_V2, FUSE_WRITE_KILL_SUIDGID};
+
+        /// Filesystem that remembers whether it was asked to kill the setuid and setgid bits
+        struct KillSuidgid(Option<bool>);
+
+        impl Filesystem for KillSuidgid {
+            fn write(
+                &mut self,
+                req: &Request<'_>,
+                _ino: u64,
+                _fh: u64,
+                _offset: i64,
+                _data: &[u8],
+                _flags: u32,
+                reply: ReplyWrite,
+            ) {
+                self.0 = Some(req.kill_suidgid());
+                reply.written(0);
+            }
+        }
+
+        let (ch, _kernel) = channel();
+        let mut arg = [0; 40];
+        arg[20..24].copy_from_slice(&FUSE_WRITE_KILL_SUIDGID.to_ne_bytes());
+        let data = RawRequest::new(fuse_opcode::FUSE_WRITE, 1, 1, &arg);
+        for init_flags in [0, FUSE_HANDLE_KILLPRIV_V2] {
+            let mut se = FuseSessionStore::new();
+            se.initialized = true;
+            se.init_flags = init_flags;
+            let mut req = Request::new(ch.sender(), data.as_bytes(), se.unimplemented.clone())
+                .unwrap();
+            let mut filesystem = KillSuidgid(None);
+            filesystem.dispatch(&mut req, &mut se);
+            assert_eq!(filesystem.0, Some(init_flags != 0));
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    fn attr_flags_if_negotiated() {
+        use super::RequestDispatcher;
+        use crate::session::FuseSessionStore;
+        use crate::{FileAttr, FileType, Filesystem, ReplyAttr};
+        use fuse_abi::consts::{FUSE_ATTR_SUBMOUNT, FUSE_SUBMOUNTS};
+        use std::time::UNIX_EPOCH;
+
+        /// Filesystem with a submount root that sets unknown attribute flags as well
+        struct Submount;
+
+        impl Filesystem for Submount {
+            fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+                let attr = FileAttr {
+                    ino,
+                    size: 0,
+                    blocks: 0,
+                    atime: UNIX_EPOCH,
+                    mtime: UNIX_EPOCH,
+                    ctime: UNIX_EPOCH,
+                    crtime: UNIX_EPOCH,
+                    kind: FileType::Directory,
+                    perm: 0o755,
+                    nlink: 2,
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                    flags: FUSE_ATTR_SUBMOUNT | 1 << 31,
+                };
+                reply.attr(&Duration::from_secs(1), &attr, 0, 0);
+            }
+        }
+
+        let (ch, mut kernel) = channel();
+        let data = RawRequest::new(fuse_opcode::FUSE_GETATTR, 1, 1, &[0; 16]);
+        for (init_flags, flags) in [(0, 0), (FUSE_SUBMOUNTS, FUSE_ATTR_SUBMOUNT)] {
+            let mut se = FuseSessionStore::new();
+            se.initialized = true;
+            se.proto_minor = 32;
+            se.init_flags = init_flags;
+            let mut req = Request::new(ch.sender(), data.as_bytes(), se.unimplemented.clone())
+                .unwrap();
+            Submount.dispatch(&mut req, &mut se);
+            // The flags are the last field of the attributes
+            let mut reply = [0; 120];
+            kernel.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply[116..], &flags.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn reply_to_invalid_requests() {
+        use crate::session::Session;
+        use crate::{Filesystem, RequestErrorCounters};
+        use fuse_abi::{FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+        use std::io::Write;
+
+        struct Empty;
+
+        impl Filesystem for Empty {}
+
+        let (ch, mut kernel) = channel();
+        let mut se = Session::with_channel(Empty, ch);
+        thread::scope(|scope| {
+            let worker = scope.spawn(|| se.run());
+
+            let init = [
+                FUSE_KERNEL_VERSION.to_ne_bytes(),
+                FUSE_KERNEL_MINOR_VERSION.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+            ]
+            .concat();
+            let init = RawRequest::new(fuse_opcode::FUSE_INIT, 1, 0, &init);
+            kernel.write_all(init.as_bytes()).unwrap();
+            let mut reply = [0; 256];
+            let len = kernel.read(&mut reply).unwrap();
+            assert!(len > 16);
+
+            // Unknown operation
+            let mut unknown = RawRequest::new(fuse_opcode::FUSE_GETATTR, 2, 1, &[0; 16])
+                .as_bytes()
+                .to_vec();
+            unknown[4..8].copy_from_slice(&9999u32.to_ne_bytes());
+            kernel.write_all(&unknown).unwrap();
+            // Request that is shorter than its header says
+            let short = RawRequest::new(fuse_opcode::FUSE_GETATTR, 3, 1, &[0; 16]);
+            kernel.write_all(&short.as_bytes()[..48]).unwrap();
+            // Forget without its argument, which must not be replied to
+            let forget = RawRequest::new(fuse_opcode::FUSE_FORGET, 4, 1, &[]);
+            kernel.write_all(forget.as_bytes()).unwrap();
+            // The session goes on serving requests
+            let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 5, 1, &[0; 16]);
+            kernel.write_all(getattr.as_bytes()).unwrap();
+
+            for (unique, errno) in [(2u64, libc::ENOSYS), (3, libc::EIO), (5, libc::ENOSYS)] {
+                let mut reply = [0; 16];
+                kernel.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply[4..8], &(-errno).to_ne_bytes());
+                assert_eq!(&reply[8..16], &unique.to_ne_bytes());
+            }
+
+            // Closing the device ends the session
+            drop(kernel);
+            worker.join().unwrap().unwrap();
+        });
+        let errors = RequestErrorCounters { unknown_operation: 1, malformed: 2 };
+        assert_eq!(se.request_errors(), errors);
+    }
 }
@@ -0,0 +1,514 @@
+//! Hardening of a mounted session (Linux only)
+//!
+//! Mounting a filesystem usually needs root, but serving it doesn't. Once mounted, a session
+//! can enter a sandbox (see `Session::enter_sandbox`): it drops to an unprivileged user,
+//! changes its root directory to the backing directory of the filesystem, sets
+//! `no_new_privs` and installs a seccomp filter that only allows the system calls the
+//! session loop needs and the ones the filesystem asks for. The steps can't be undone.
+
+use libc::{self, c_int, c_long, gid_t, uid_t};
+use log::info;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Offset of the system call number in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+/// Offset of the audit architecture in `struct seccomp_data`
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// Classic BPF instructions (linux/filter.h)
+/// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+/// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// BPF_JMP | BPF_JGE | BPF_K
+#[cfg(target_arch = "x86_64")]
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+/// Max number of instructions of a program
+const BPF_MAXINSNS: usize = 4096;
+
+// Seccomp filter actions and flags (linux/seccomp.h)
+const SECCOMP_SET_MODE_FILTER: c_int = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: c_int = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// Audit architecture of the system calls the filter allows (linux/audit.h)
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = 0xc000_00f3;
+
+/// First system call number of the x32 ABI, which shares the audit architecture of x86_64
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls of session loops, replies sent from other threads, the watchdog and control
+/// threads and the Rust standard library
+const SESSION_SYSCALLS: &[c_long] = &[
+    // Requests and replies
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_splice,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_close,
+    // Unix sockets of handovers and control connections
+    libc::SYS_accept4,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads, synchronization and time
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_prctl,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    // Signals and exit
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// System calls of the session loop that newer architectures don't have
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[c_long] = &[
+    libc::SYS_poll,
+    libc::SYS_pipe,
+    libc::SYS_epoll_wait,
+    libc::SYS_accept,
+    libc::SYS_newfstatat,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[c_long] = &[libc::SYS_newfstatat];
+
+/// Instruction of a classic BPF program (`struct sock_filter`)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// Classic BPF program (`struct sock_fprog`)
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+/// Directory to change the root directory to
+#[derive(Clone, Debug)]
+enum Root {
+    Chroot(PathBuf),
+    PivotRoot(PathBuf),
+}
+
+/// Restrictions a session puts itself under after mounting (see `Session::enter_sandbox`)
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    /// User and group to switch to
+    user: Option<(uid_t, gid_t)>,
+    /// Supplementary groups to keep when switching the user
+    groups: Vec<gid_t>,
+    /// Directory to change the root directory to
+    root: Option<Root>,
+    /// True if system calls are filtered
+    filter: bool,
+    /// System calls the filesystem needs
+    syscalls: Vec<c_long>,
+    /// True if other system calls kill the process instead of failing with EPERM
+    kill: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+impl Sandbox {
+    /// Create a sandbox that sets `no_new_privs` and only allows the system calls the session
+    /// loop needs. Other system calls fail with EPERM. The user and the root directory are
+    /// kept unless set.
+    pub fn new() -> Sandbox {
+        Sandbox {
+            user: None,
+            groups: Vec::new(),
+            root: None,
+            filter: true,
+            syscalls: Vec::new(),
+            kill: false,
+        }
+    }
+
+    /// Switch to the given user and group. Supplementary groups are dropped unless set with
+    /// `set_groups`. Switching to a user other than root drops all capabilities.
+    pub fn set_user(&mut self, uid: uid_t, gid: gid_t) {
+        self.user = Some((uid, gid));
+    }
+
+    /// Keep the given supplementary groups when switching the user
+    pub fn set_groups(&mut self, groups: &[gid_t]) {
+        self.groups = groups.to_vec();
+    }
+
+    /// Change the root directory to the given directory with `chroot`. Paths the filesystem
+    /// accesses are resolved within the directory afterwards.
+    pub fn set_chroot(&mut self, dir: &Path) {
+        self.root = Some(Root::Chroot(dir.to_path_buf()));
+    }
+
+    /// Change the root directory to the given directory with `pivot_root` in a new mount
+    /// namespace, so that the old root isn't reachable at all (unlike with `chroot`). The
+    /// new mount namespace only applies to the thread entering the sandbox and threads it
+    /// starts afterwards.
+    pub fn set_pivot_root(&mut self, dir: &Path) {
+        self.root = Some(Root::PivotRoot(dir.to_path_buf()));
+    }
+
+    /// Allow the given system calls (`libc::SYS_*`) in addition to the ones of the session
+    /// loop, e.g. the ones the filesystem needs to access its backing files. Some features
+    /// need further system calls:
+    ///
+    /// - `AsyncSession` (`async` feature): `eventfd2` for the tokio runtime
+    /// - `PanicPolicy::Abort`: `openat` to find the connection in the mount table and abort it
+    /// - `PanicPolicy::Unmount`, and `PanicPolicy::Abort` if aborting fails: `openat` and
+    ///   `umount2`, which needs root
+    /// - `Session::run_multithreaded`: `openat` and `ioctl` to clone the FUSE device fd
+    pub fn allow_syscalls(&mut self, syscalls: &[c_long]) {
+        self.syscalls.extend_from_slice(syscalls);
+    }
+
+    /// Filter system calls (the default) or allow all of them
+    pub fn set_syscall_filter(&mut self, filter: bool) {
+        self.filter = filter;
+    }
+
+    /// Kill the process on system calls the filter doesn't allow instead of failing them
+    /// with EPERM
+    pub fn set_kill_on_violation(&mut self, kill: bool) {
+        self.kill = kill;
+    }
+
+    /// Enter the sandbox. Steps are taken in order: changing the root directory, switching
+    /// the user, setting `no_new_privs` and installing the system call filter for all
+    /// threads of the process. Stops at the first step that fails.
+    pub(crate) fn enter(&self) -> io::Result<()> {
+        match &self.root {
+            Some(Root::Chroot(dir)) => {
+                chroot(dir)?;
+                info!("Changed root directory to {}", dir.display());
+            }
+            Some(Root::PivotRoot(dir)) => {
+                pivot_root(dir)?;
+                info!("Pivoted root directory to {}", dir.display());
+            }
+            None => (),
+        }
+        if let Some((uid, gid)) = self.user {
+            // Groups need to be changed first while the process is still privileged
+            check(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) })?;
+            check(unsafe { libc::setresgid(gid, gid, gid) })?;
+            check(unsafe { libc::setresuid(uid, uid, uid) })?;
+            info!("Switched to uid {} and gid {}", uid, gid);
+        }
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        if self.filter {
+            let mut syscalls = SESSION_SYSCALLS.to_vec();
+            syscalls.extend_from_slice(LEGACY_SYSCALLS);
+            syscalls.extend_from_slice(&self.syscalls);
+            let denied = if self.kill {
+                SECCOMP_RET_KILL_PROCESS
+            } else {
+                SECCOMP_RET_ERRNO | libc::EPERM as u32
+            };
+            install(&filter(&syscalls, denied)?)?;
+            info!("Installed filter allowing {} system calls", syscalls.len());
+        }
+        Ok(())
+    }
+}
+
+/// Fail with the last OS error if the given return code of a libc function is negative
+fn check<T: Default + PartialOrd>(rc: T) -> io::Result<T> {
+    if rc < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc)
+    }
+}
+
+/// Convert the given path to a C string
+fn cstr(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Change the root directory of the process to the given directory
+fn chroot(dir: &Path) -> io::Result<()> {
+    let dir = cstr(dir)?;
+    check(unsafe { libc::chroot(dir.as_ptr()) })?;
+    let root = cstr(Path::new("/"))?;
+    check(unsafe { libc::chdir(root.as_ptr()) })?;
+    Ok(())
+}
+
+/// Change the root directory to the given directory in a new mount namespace and detach the
+/// old root
+fn pivot_root(dir: &Path) -> io::Result<()> {
+    let dir = cstr(dir)?;
+    let root = cstr(Path::new("/"))?;
+    let current = cstr(Path::new("."))?;
+    check(unsafe { libc::unshare(libc::CLONE_NEWNS) })?;
+    // Keep the changes below from propagating to the original mount namespace
+    let flags = libc::MS_REC | libc::MS_PRIVATE;
+    check(unsafe { libc::mount(ptr::null(), root.as_ptr(), ptr::null(), flags, ptr::null()) })?;
+    // The new root needs to be a mount point
+    let flags = libc::MS_BIND | libc::MS_REC;
+    check(unsafe { libc::mount(dir.as_ptr(), dir.as_ptr(), ptr::null(), flags, ptr::null()) })?;
+    check(unsafe { libc::chdir(dir.as_ptr()) })?;
+    // Stacks the old root on top of the new one, so that it can be detached right away
+    check(unsafe { libc::syscall(libc::SYS_pivot_root, current.as_ptr(), current.as_ptr()) })?;
+    check(unsafe { libc::umount2(current.as_ptr(), libc::MNT_DETACH) })?;
+    check(unsafe { libc::chdir(root.as_ptr()) })?;
+    Ok(())
+}
+
+/// Build a seccomp filter that allows the given system calls of the native architecture and
+/// returns `denied` for others. System calls of other architectures kill the process.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn filter(syscalls: &[c_long], denied: u32) -> io::Result<Vec<SockFilter>> {
+    let stmt = |code, k| SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    // Jumps over the next instruction if the condition is true (jt) or false (jf)
+    let jump = |code, k, jt, jf| SockFilter { code, jt, jf, k };
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        filter.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+        filter.push(stmt(BPF_RET_K, denied));
+    }
+    for &syscall in syscalls {
+        filter.push(jump(BPF_JMP_JEQ_K, syscall as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET_K, denied));
+    if filter.len() > BPF_MAXINSNS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many system calls to allow",
+        ));
+    }
+    Ok(filter)
+}
+
+/// System calls can only be filtered on some architectures
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn filter(_syscalls: &[c_long], _denied: u32) -> io::Result<Vec<SockFilter>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "system call filter unsupported on this architecture",
+    ))
+}
+
+/// Install the given seccomp filter for all threads of the process
+fn install(filter: &[SockFilter]) -> io::Result<()> {
+    let prog = SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+    let flags = SECCOMP_FILTER_FLAG_TSYNC;
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            flags,
+            &prog as *const SockFprog,
+        )
+    };
+    // Returns the id of a thread that couldn't be synchronized
+    if rc > 0 {
+        return Err(io::Error::other(format!(
+            "failed to install filter for thread {}",
+            rc
+        )));
+    }
+    check(rc)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{filter, install, SockFilter, BPF_RET_K, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO};
+    use libc::c_long;
+    use std::io;
+    #[cfg(feature = "control")]
+    use {
+        super::Sandbox,
+        crate::session::Session,
+        crate::test_util::channel,
+        crate::{ControlClient, Filesystem},
+        std::fs,
+        std::os::unix::net::UnixListener,
+    };
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn filters_syscalls() {
+        let allowed: &[c_long] = &[libc::SYS_getpid, libc::SYS_exit_group];
+        let filter = filter(allowed, SECCOMP_RET_ERRNO | libc::EPERM as u32).unwrap();
+        let allow = SockFilter {
+            code: BPF_RET_K,
+            jt: 0,
+            jf: 0,
+            k: SECCOMP_RET_ALLOW,
+        };
+        assert_eq!(filter.iter().filter(|insn| **insn == allow).count(), 2);
+
+        // Install the filter in a child process, since it can't be removed again
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let status = if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+                1
+            } else if install(&filter).is_err() {
+                2
+            } else if unsafe { libc::syscall(libc::SYS_getpid) } <= 0 {
+                3
+            } else if unsafe { libc::syscall(libc::SYS_getppid) } >= 0 {
+                4
+            } else if io::Error::last_os_error().raw_os_error() != Some(libc::EPERM) {
+                5
+            } else {
+                0
+            };
+            unsafe { libc::syscall(libc::SYS_exit_group, status) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[cfg(all(
+        feature = "control",
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    ))]
+    #[test]
+    fn serves_control_socket_in_sandbox() {
+        struct NullFilesystem;
+        impl Filesystem for NullFilesystem {}
+
+        let file = format!("rust-fuse-sandbox-{}", std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (ch, kernel) = channel();
+
+        // Serve the control socket in a sandboxed child process, since the sandbox can't be
+        // left again. The session ends once the fake kernel is closed.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            drop(kernel);
+            let mut se = Session::with_channel(NullFilesystem, ch);
+            let status = if se.enter_sandbox(&Sandbox::new()).is_err() {
+                1
+            } else if se.serve_control(listener).is_err() {
+                2
+            } else if se.run().is_err() {
+                3
+            } else if se.enter_sandbox(&Sandbox::new()).is_ok() {
+                // The control and session threads ran outside of the sandbox
+                4
+            } else {
+                0
+            };
+            unsafe { libc::_exit(status) };
+        }
+        drop(ch);
+        drop(listener);
+
+        let mut client = ControlClient::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(client.stats().unwrap().bytes_read, 0);
+        assert_eq!(client.in_flight().unwrap(), Vec::new());
+        drop(kernel);
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
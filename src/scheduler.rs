@@ -0,0 +1,606 @@
+//! Request scheduling
+//!
+//! By default, a session dispatches every request as soon as it's received. If the filesystem
+//! replies asynchronously (e.g. from a thread pool or an async runtime), the number of requests
+//! in flight and the memory they hold is unbounded, and a slow backend delays all kinds of
+//! requests alike. With a scheduler, requests are split into classes (see `RequestClass`) and
+//! every class can be limited to a number of requests in flight, i.e. dispatched but not
+//! replied to yet. A request of a class at its limit is deferred to the lane of its class until
+//! a request of the class is replied to. Lanes are served in order of priority, so that e.g.
+//! closing files isn't starved by lookups. Forget, interrupt and notify reply requests have no
+//! reply that could be limited and are dispatched out-of-band, i.e. right away. An interrupt of
+//! a deferred request answers it with EINTR instead of dispatching it. If too many requests are
+//! deferred, the session pauses: it keeps reading requests from the kernel only to receive
+//! interrupts and forgets, which the kernel hands out before other requests. Other requests
+//! received while paused are deferred as well, up to as many again, before the session stops
+//! reading until one is replied to.
+
+use libc::{self, c_void, EINTR};
+use log::debug;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::buffer::RequestBuffer;
+use crate::channel::ChannelSender;
+use crate::reply::{Reply, ReplyEmpty};
+use crate::request::{Request, RequestDispatcher};
+use crate::session::FuseSessionStore;
+use crate::unwind;
+
+/// Class of requests that share a limit of requests in flight. Deferred requests of classes
+/// listed first are dispatched first.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RequestClass {
+    /// Closing files: flush, release and releasedir
+    Release,
+    /// Operations on inodes and directories, e.g. lookup, getattr, open, readdir and create
+    Metadata,
+    /// Operations on file contents: read, write, fsync, fallocate, lseek and copy_file_range
+    Data,
+}
+
+impl RequestClass {
+    /// All classes in order of priority
+    const ALL: [RequestClass; 3] = [
+        RequestClass::Release,
+        RequestClass::Metadata,
+        RequestClass::Data,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Data of a deferred request
+enum DeferredData {
+    /// Copy of a request received into the session's single buffer
+    Owned(Vec<u8>),
+    /// Pooled buffer the request was received into
+    Pooled(Arc<RequestBuffer>),
+}
+
+/// A request deferred until its class has room
+pub(crate) struct Deferred {
+    /// Sender for sending the reply
+    ch: ChannelSender,
+    /// Unique identifier of the request
+    unique: u64,
+    /// Request raw data
+    data: DeferredData,
+    /// When the request was received
+    received: Instant,
+}
+
+impl Deferred {
+    /// Defer the request with the given data. The data is copied unless it was received
+    /// into a pooled buffer.
+    pub(crate) fn new(
+        ch: ChannelSender,
+        unique: u64,
+        data: &[u8],
+        buffer: Option<&Arc<RequestBuffer>>,
+        received: Instant,
+    ) -> Deferred {
+        let data = match buffer {
+            Some(buffer) => DeferredData::Pooled(buffer.clone()),
+            None => DeferredData::Owned(data.to_vec()),
+        };
+        Deferred { ch, unique, data, received }
+    }
+
+    /// Dispatch the deferred request to the given filesystem
+    fn dispatch<FS: RequestDispatcher>(
+        self,
+        permit: Permit,
+        filesystem: &mut FS,
+        store: &mut FuseSessionStore,
+    ) {
+        let (data, buffer) = match &self.data {
+            DeferredData::Owned(data) => (&data[..], None),
+            DeferredData::Pooled(buffer) => (&buffer[..], Some(buffer)),
+        };
+        // The request was parsed successfully before it was deferred
+        if let Ok(mut req) = Request::new(self.ch.clone(), data, store.unimplemented.clone()) {
+            if let Some(buffer) = buffer {
+                req.set_buffer(buffer.clone());
+            }
+            req.set_permit(permit);
+            req.set_received(self.received);
+            unwind::dispatch(filesystem, &mut req, store);
+        }
+    }
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Deferred({})", self.unique)
+    }
+}
+
+/// Requests in flight and deferred requests of a scheduler
+#[derive(Debug)]
+struct SchedulerState {
+    /// Max number of requests in flight per class
+    limits: [usize; 3],
+    /// Number of requests in flight per class
+    in_flight: [usize; 3],
+    /// Deferred requests per class
+    lanes: [VecDeque<Deferred>; 3],
+}
+
+impl SchedulerState {
+    /// Return the number of deferred requests
+    fn queued(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns true if a deferred request can be dispatched
+    fn ready(&self) -> bool {
+        RequestClass::ALL.iter().any(|class| {
+            let i = class.index();
+            !self.lanes[i].is_empty() && self.in_flight[i] < self.limits[i]
+        })
+    }
+}
+
+/// State of a scheduler shared with its permits
+#[derive(Debug)]
+struct SchedulerInner {
+    /// Requests of the scheduler
+    state: Mutex<SchedulerState>,
+    /// Notified whenever a deferred request may have become ready
+    ready: Condvar,
+    /// Number of deferred requests at which sessions pause
+    max_queued: usize,
+    /// Pipe that is readable while a deferred request may have become ready, so that session
+    /// loops waiting for requests from the kernel can wait for it as well
+    wake: WakePipe,
+}
+
+impl SchedulerInner {
+    fn lock(&self) -> MutexGuard<'_, SchedulerState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Wake up session loops to dispatch deferred requests
+    fn wake(&self) {
+        self.ready.notify_all();
+        self.wake.signal();
+    }
+}
+
+/// Scheduler of requests (see `Session::set_scheduler`)
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    inner: Arc<SchedulerInner>,
+}
+
+impl Scheduler {
+    /// Create a scheduler that defers up to the given number of requests (at least one)
+    /// before sessions pause, and up to twice as many before they stop reading requests (see
+    /// the module documentation). Classes aren't limited until a limit is set.
+    /// Deferred requests are copied, unless the session receives requests into a buffer pool
+    /// (see `Session::set_buffer_pool`).
+    pub fn new(max_queued: usize) -> io::Result<Scheduler> {
+        Ok(Scheduler {
+            inner: Arc::new(SchedulerInner {
+                state: Mutex::new(SchedulerState {
+                    limits: [usize::MAX; 3],
+                    in_flight: [0; 3],
+                    lanes: Default::default(),
+                }),
+                ready: Condvar::new(),
+                max_queued: max_queued.max(1),
+                wake: WakePipe::new()?,
+            }),
+        })
+    }
+
+    /// Limit the number of requests of the given class in flight (at least one). Can be
+    /// changed while sessions are running.
+    pub fn set_limit(&self, class: RequestClass, limit: usize) {
+        self.inner.lock().limits[class.index()] = limit.max(1);
+        self.inner.wake();
+    }
+
+    /// Return the max number of requests of the given class in flight
+    pub fn limit(&self, class: RequestClass) -> usize {
+        self.inner.lock().limits[class.index()]
+    }
+
+    /// Return the number of requests of the given class in flight
+    pub fn in_flight(&self, class: RequestClass) -> usize {
+        self.inner.lock().in_flight[class.index()]
+    }
+
+    /// Return the number of deferred requests
+    pub fn queued(&self) -> usize {
+        self.inner.lock().queued()
+    }
+
+    /// Returns true if sessions should only read requests to receive interrupts and forgets
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    pub(crate) fn is_paused(&self) -> bool {
+        self.inner.lock().queued() >= self.inner.max_queued
+    }
+
+    /// Returns true if sessions should stop reading requests until one is replied to
+    pub(crate) fn is_full(&self) -> bool {
+        self.inner.lock().queued() >= self.inner.max_queued * 2
+    }
+
+    /// Decide whether the given request is dispatched now. Returns false if it was deferred
+    /// or, for an interrupt of a deferred request, answered already.
+    pub(crate) fn admit(&self, req: &mut Request<'_>) -> bool {
+        if let Some(unique) = req.interrupted() {
+            return !self.interrupt(unique);
+        }
+        let class = match req.class() {
+            Some(class) => class,
+            None => return true,
+        };
+        match self.acquire(class) {
+            Some(permit) => {
+                req.set_permit(permit);
+                true
+            }
+            None => {
+                debug!("Deferring {:?} request {}", class, req.unique());
+                self.inner.lock().lanes[class.index()].push_back(req.deferred());
+                false
+            }
+        }
+    }
+
+    /// Take a slot of the given class if it has room and no deferred requests
+    fn acquire(&self, class: RequestClass) -> Option<Permit> {
+        let mut state = self.inner.lock();
+        let i = class.index();
+        if !state.lanes[i].is_empty() || state.in_flight[i] >= state.limits[i] {
+            return None;
+        }
+        state.in_flight[i] += 1;
+        Some(Permit::new(self.inner.clone(), class))
+    }
+
+    /// Answer the deferred request with the given unique identifier with EINTR. Returns false
+    /// if no such request is deferred.
+    fn interrupt(&self, unique: u64) -> bool {
+        let deferred = {
+            let mut state = self.inner.lock();
+            state.lanes.iter_mut().find_map(|lane| {
+                let index = lane.iter().position(|deferred| deferred.unique == unique)?;
+                lane.remove(index)
+            })
+        };
+        match deferred {
+            Some(deferred) => {
+                debug!("Interrupted deferred request {}", unique);
+                let reply: ReplyEmpty = Reply::new(unique, deferred.ch);
+                reply.error(EINTR);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the next deferred request whose class has room
+    fn next_ready(&self) -> Option<(Deferred, Permit)> {
+        let mut state = self.inner.lock();
+        let class = RequestClass::ALL.iter().copied().find(|class| {
+            let i = class.index();
+            !state.lanes[i].is_empty() && state.in_flight[i] < state.limits[i]
+        })?;
+        let deferred = state.lanes[class.index()].pop_front()?;
+        state.in_flight[class.index()] += 1;
+        Some((deferred, Permit::new(self.inner.clone(), class)))
+    }
+
+    /// Dispatch deferred requests to the given filesystem as long as their classes have room
+    pub(crate) fn dispatch_ready<FS: RequestDispatcher>(
+        &self,
+        filesystem: &mut FS,
+        store: &mut FuseSessionStore,
+    ) {
+        self.inner.wake.clear();
+        while let Some((deferred, permit)) = self.next_ready() {
+            deferred.dispatch(permit, filesystem, store);
+        }
+    }
+
+    /// Wait until a deferred request can be dispatched or sessions can read requests again
+    pub(crate) fn wait_ready(&self) {
+        let state = self.inner.lock();
+        let _state = self
+            .inner
+            .ready
+            .wait_while(state, |state| {
+                state.queued() >= self.inner.max_queued * 2 && !state.ready()
+            })
+            .unwrap_or_else(|err| err.into_inner());
+    }
+
+    /// Return a file descriptor that is readable while a deferred request may have become
+    /// ready
+    pub(crate) fn wake_fd(&self) -> RawFd {
+        self.inner.wake.read
+    }
+
+    /// Drop all deferred requests after the session ended
+    pub(crate) fn clear(&self) {
+        let lanes = mem::take(&mut self.inner.lock().lanes);
+        drop(lanes);
+    }
+}
+
+/// Slot of a request in flight, released when the request is replied to
+pub(crate) struct Permit {
+    inner: Arc<SchedulerInner>,
+    class: RequestClass,
+    /// True once the slot was released
+    released: AtomicBool,
+}
+
+impl Permit {
+    fn new(inner: Arc<SchedulerInner>, class: RequestClass) -> Permit {
+        Permit {
+            inner,
+            class,
+            released: AtomicBool::new(false),
+        }
+    }
+
+    /// Release the slot, e.g. because the request was replied to on behalf of the
+    /// filesystem. Dropping the permit releases it as well.
+    pub(crate) fn release(&self) {
+        if self.released.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let mut state = self.inner.lock();
+        let i = self.class.index();
+        state.in_flight[i] -= 1;
+        let waiting = !state.lanes[i].is_empty();
+        drop(state);
+        if waiting {
+            self.inner.wake();
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Permit({:?})", self.class)
+    }
+}
+
+/// Non-blocking pipe to wake up session loops
+#[derive(Debug)]
+struct WakePipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl WakePipe {
+    fn new() -> io::Result<WakePipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = WakePipe {
+            read: fds[0],
+            write: fds[1],
+        };
+        for &fd in &fds {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+                || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(pipe)
+    }
+
+    /// Make the pipe readable. If the pipe is full, it's readable already.
+    fn signal(&self) {
+        let byte = 1u8;
+        unsafe { libc::write(self.write, &byte as *const u8 as *const c_void, 1) };
+    }
+
+    /// Make the pipe unreadable
+    fn clear(&self) {
+        let mut buffer = [0u8; 64];
+        loop {
+            let rc =
+                unsafe { libc::read(self.read, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+            if rc == 0 || rc < 0 && io::Error::last_os_error().raw_os_error() != Some(EINTR) {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for WakePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RequestClass, Scheduler};
+    use crate::request::{Request, UnimplementedOps};
+    use crate::session::Session;
+    use crate::test_util::{channel, RawRequest};
+    use crate::{Filesystem, ReplyAttr};
+    use fuse_abi::{fuse_opcode, FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+    use std::io::{Read, Write};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn is_readable(scheduler: &Scheduler) -> bool {
+        let mut fds = [libc::pollfd {
+            fd: scheduler.wake_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        unsafe { libc::poll(fds.as_mut_ptr(), 1, 0) == 1 }
+    }
+
+    #[test]
+    fn limits_requests_in_flight() {
+        let (ch, _kernel) = channel();
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        let scheduler = Scheduler::new(4).unwrap();
+        scheduler.set_limit(RequestClass::Metadata, 1);
+
+        // GETATTR is dispatched while the class has room, deferred otherwise
+        let first = RawRequest::new(fuse_opcode::FUSE_GETATTR, 1, 1, &[]);
+        let mut first = Request::new(ch.sender(), first.as_bytes(), unimplemented.clone()).unwrap();
+        assert!(scheduler.admit(&mut first));
+        assert_eq!(scheduler.in_flight(RequestClass::Metadata), 1);
+        let second = RawRequest::new(fuse_opcode::FUSE_GETATTR, 2, 1, &[]);
+        let mut second =
+            Request::new(ch.sender(), second.as_bytes(), unimplemented.clone()).unwrap();
+        assert!(!scheduler.admit(&mut second));
+        assert_eq!(scheduler.queued(), 1);
+
+        // FORGET is dispatched out-of-band
+        let forget = RawRequest::new(fuse_opcode::FUSE_FORGET, 3, 1, &1u64.to_ne_bytes());
+        let mut forget =
+            Request::new(ch.sender(), forget.as_bytes(), unimplemented.clone()).unwrap();
+        assert!(scheduler.admit(&mut forget));
+        assert_eq!(scheduler.in_flight(RequestClass::Metadata), 1);
+
+        // Replying to the first request makes room for the deferred one
+        scheduler.inner.wake.clear();
+        assert!(!is_readable(&scheduler));
+        drop(first);
+        assert!(is_readable(&scheduler));
+        let (deferred, _permit) = scheduler.next_ready().unwrap();
+        assert_eq!(deferred.unique, 2);
+        assert_eq!(scheduler.in_flight(RequestClass::Metadata), 1);
+        assert!(scheduler.next_ready().is_none());
+    }
+
+    #[test]
+    fn interrupts_deferred_request() {
+        let (ch, mut kernel) = channel();
+        let unimplemented = Arc::new(UnimplementedOps::default());
+        let scheduler = Scheduler::new(4).unwrap();
+        scheduler.set_limit(RequestClass::Data, 1);
+
+        // Two FSYNC requests, the second is deferred
+        let fsync = RawRequest::new(fuse_opcode::FUSE_FSYNC, 1, 1, &[0; 16]);
+        let mut fsync = Request::new(ch.sender(), fsync.as_bytes(), unimplemented.clone()).unwrap();
+        assert!(scheduler.admit(&mut fsync));
+        let deferred = RawRequest::new(fuse_opcode::FUSE_FSYNC, 2, 1, &[0; 16]);
+        let mut deferred =
+            Request::new(ch.sender(), deferred.as_bytes(), unimplemented.clone()).unwrap();
+        assert!(!scheduler.admit(&mut deferred));
+
+        // Interrupting the deferred request answers it with EINTR right away
+        let interrupt = RawRequest::new(fuse_opcode::FUSE_INTERRUPT, 3, 0, &2u64.to_ne_bytes());
+        let mut interrupt = Request::new(ch.sender(), interrupt.as_bytes(), unimplemented).unwrap();
+        assert!(!scheduler.admit(&mut interrupt));
+        assert_eq!(scheduler.queued(), 0);
+        let mut reply = [0; 16];
+        kernel.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[0..4], &16u32.to_ne_bytes());
+        assert_eq!(&reply[4..8], &(-libc::EINTR).to_ne_bytes());
+        assert_eq!(&reply[8..16], &2u64.to_ne_bytes());
+    }
+
+    /// Filesystem that keeps getattr replies of inode 1 and reports forgets
+    struct HoldingFilesystem {
+        held: Mutex<Vec<ReplyAttr>>,
+        forgets: Mutex<Sender<u64>>,
+    }
+
+    impl Filesystem for &HoldingFilesystem {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match ino {
+                1 => self.held.lock().unwrap().push(reply),
+                _ => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn forget(&mut self, _req: &Request<'_>, ino: u64, _nlookup: u64) {
+            self.forgets.lock().unwrap().send(ino).unwrap();
+        }
+    }
+
+    #[test]
+    fn receives_forget_while_paused() {
+        let (ch, mut kernel) = channel();
+        let (forgets, forgotten) = mpsc::channel();
+        let filesystem = HoldingFilesystem {
+            held: Mutex::new(Vec::new()),
+            forgets: Mutex::new(forgets),
+        };
+        let scheduler = Scheduler::new(1).unwrap();
+        scheduler.set_limit(RequestClass::Metadata, 1);
+        let mut se = Session::with_channel(&filesystem, ch);
+        se.set_scheduler(scheduler.clone());
+        thread::scope(|scope| {
+            let worker = scope.spawn(move || se.run());
+
+            let init = [
+                FUSE_KERNEL_VERSION.to_ne_bytes(),
+                FUSE_KERNEL_MINOR_VERSION.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+            ]
+            .concat();
+            let init = RawRequest::new(fuse_opcode::FUSE_INIT, 1, 0, &init);
+            kernel.write_all(init.as_bytes()).unwrap();
+            let mut reply = [0; 256];
+            let len = kernel.read(&mut reply).unwrap();
+            assert!(len > 16);
+
+            // The first GETATTR is held by the filesystem, the second one is deferred, which
+            // pauses the session
+            let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 2, 1, &[0; 16]);
+            kernel.write_all(getattr.as_bytes()).unwrap();
+            let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 3, 2, &[0; 16]);
+            kernel.write_all(getattr.as_bytes()).unwrap();
+
+            // A paused session still receives forgets
+            let forget = RawRequest::new(fuse_opcode::FUSE_FORGET, 4, 5, &1u64.to_ne_bytes());
+            kernel.write_all(forget.as_bytes()).unwrap();
+            let timeout = Duration::from_secs(5);
+            assert_eq!(forgotten.recv_timeout(timeout), Ok(5));
+            assert_eq!(scheduler.queued(), 1);
+
+            // Answering the held request dispatches the deferred one
+            filesystem.held.lock().unwrap().clear();
+            for unique in 2..4u64 {
+                let mut reply = [0; 16];
+                kernel.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply[8..16], &unique.to_ne_bytes());
+            }
+
+            drop(kernel);
+            worker.join().unwrap().unwrap();
+        });
+    }
+}
@@ -8,14 +8,46 @@
 use std::io;
 use std::ffi::OsStr;
 use std::fmt;
+use std::fs;
 use std::path::{PathBuf, Path};
-use thread_scoped::{scoped, JoinGuard};
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::{error, info};
+use std::os::unix::io::RawFd;
+use std::iter;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "control")]
+use std::sync::Weak;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use libc::{EAGAIN, EINTR, ENODEV, ENOENT, ENOTCONN};
+#[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+use fuse_abi::consts::FUSE_SPLICE_READ;
+use log::{error, info, warn};
+#[cfg(feature = "handover")]
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use std::os::unix::io::AsRawFd;
+#[cfg(any(feature = "handover", feature = "control"))]
+use std::os::unix::net::UnixListener;
 
-use crate::channel::{self, Channel};
-use crate::request::{Request, RequestDispatcher};
-use crate::Filesystem;
+use crate::buffer::{BufferPool, RequestBuffer};
+use crate::channel::{self, Channel, ChannelSender};
+#[cfg(feature = "control")]
+use crate::control::{self, InFlightRequest};
+use crate::deadline::{Deadlines, Watchdog};
+#[cfg(feature = "handover")]
+use crate::handover;
+use crate::request::{PendingReplies, Request, RequestDispatcher, UnimplementedOps};
+use crate::scheduler::Scheduler;
+use crate::stats::{SessionStats, Stats};
+#[cfg(target_os = "linux")]
+use crate::sandbox::Sandbox;
+#[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+use crate::splice;
+#[cfg(target_os = "linux")]
+use crate::splice::Pipe;
+#[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+use crate::uring::{self, Completion, Ring};
+use crate::unwind::{self, PanicHandler, PanicPolicy};
+use crate::{Filesystem, SyncFilesystem};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
@@ -24,9 +56,27 @@ pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
 /// Size of the buffer for reading a request from the kernel. Since the kernel may send
 /// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+pub(crate) const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
 
-#[derive(Clone, Debug)]
+/// How long to wait for replies to requests in flight before destroying the filesystem after
+/// the session ended, unless set with `Session::set_drain_timeout`
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Max number of pages of data the kernel sends in a request, since we don't negotiate
+/// `FUSE_MAX_PAGES`
+#[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+pub(crate) const KERNEL_MAX_PAGES: usize = 32;
+
+/// Counters of requests that failed to parse
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RequestErrorCounters {
+    /// Requests with an unknown opcode (answered with ENOSYS)
+    pub unknown_operation: u64,
+    /// Requests that are short or have malformed arguments (answered with EIO if possible)
+    pub malformed: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FuseSessionStore {
     /// FUSE protocol major version
     pub proto_major: u32,
@@ -36,8 +86,312 @@ pub struct FuseSessionStore {
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// Init flags negotiated with the kernel
+    pub init_flags: u32,
+    /// Max size of write requests, negotiated with the kernel on init (0 before init for
+    /// the default of `MAX_WRITE_SIZE`)
+    pub max_write: u32,
+    /// How long to wait for pending replies before destroying the filesystem after the
+    /// session ended
+    pub drain_timeout: Duration,
+    /// Operations the filesystem doesn't implement
+    pub unimplemented: Arc<UnimplementedOps>,
+    /// Requests that failed to parse
+    pub request_errors: RequestErrorCounters,
+    /// Number of io_uring entries per queue to ask the kernel for FUSE over io_uring with
+    /// (0 to not ask for it)
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    pub uring_depth: usize,
+    /// True if the kernel agreed to exchange requests and replies through io_uring
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    pub uring: bool,
+    /// Watchdog that replies to requests past their deadline
+    #[serde(skip)]
+    pub(crate) watchdog: Option<Watchdog>,
+    /// Carries out the panic policy after a filesystem method panicked
+    #[serde(skip)]
+    pub(crate) panics: Option<PanicHandler>,
+    /// Statistics of requests, shared by all workers of the session
+    #[serde(skip)]
+    pub(crate) stats: Arc<Stats>,
+    /// Requests the filesystem didn't reply to yet, shared by all workers of the session
+    #[serde(skip)]
+    pub(crate) pending: Arc<PendingReplies>,
 }
 
+impl FuseSessionStore {
+    /// Create the state of a session that wasn't initialized yet
+    pub(crate) fn new() -> FuseSessionStore {
+        FuseSessionStore {
+            proto_major: 0,
+            proto_minor: 0,
+            initialized: false,
+            destroyed: false,
+            init_flags: 0,
+            max_write: 0,
+            drain_timeout: DRAIN_TIMEOUT,
+            unimplemented: Arc::new(UnimplementedOps::default()),
+            request_errors: RequestErrorCounters::default(),
+            #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+            uring_depth: 0,
+            #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+            uring: false,
+            watchdog: None,
+            panics: None,
+            stats: Arc::new(Stats::default()),
+            pending: Arc::new(PendingReplies::default()),
+        }
+    }
+}
+
+/// Progress of a session, shared with its handles
+#[derive(Debug, Default)]
+struct SessionStatus {
+    /// True while the session loop is running
+    running: bool,
+    /// True once the session loop ended
+    ended: bool,
+    /// True once the filesystem is initialized
+    initialized: bool,
+    /// True once the filesystem is destroyed
+    destroyed: bool,
+    /// True if the filesystem is unmounted through a handle
+    shutdown: bool,
+    /// True once the session loop, the control socket or the signal thread started
+    threads: bool,
+}
+
+/// State of a session shared with its handles
+#[derive(Debug)]
+struct SessionState {
+    /// Path of the mounted filesystem
+    mountpoint: PathBuf,
+    /// Progress of the session
+    status: Mutex<SessionStatus>,
+    /// Notified whenever the status changes
+    changed: Condvar,
+}
+
+impl SessionState {
+    fn new(mountpoint: &Path) -> SessionState {
+        SessionState {
+            mountpoint: mountpoint.to_path_buf(),
+            status: Mutex::new(SessionStatus::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Return the current status
+    fn lock(&self) -> MutexGuard<'_, SessionStatus> {
+        self.status.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Change the status and notify waiters
+    fn update<F: FnOnce(&mut SessionStatus)>(&self, f: F) {
+        f(&mut self.lock());
+        self.changed.notify_all();
+    }
+
+    /// Wait while the given condition is true and return the status
+    fn wait_while<F>(&self, condition: F) -> MutexGuard<'_, SessionStatus>
+    where
+        F: FnMut(&mut SessionStatus) -> bool,
+    {
+        self.changed
+            .wait_while(self.lock(), condition)
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Mark the session loop as running until the returned guard is dropped
+    fn start(&self) -> RunningGuard<'_> {
+        self.update(|status| {
+            status.running = true;
+            status.threads = true;
+        });
+        RunningGuard(self)
+    }
+}
+
+/// Marks the session loop as ended when dropped, even if it panicked
+struct RunningGuard<'a>(&'a SessionState);
+
+impl<'a> Drop for RunningGuard<'a> {
+    fn drop(&mut self) {
+        self.0.update(|status| {
+            status.running = false;
+            status.ended = true;
+        });
+    }
+}
+
+/// Handle to a session
+///
+/// A handle can be cloned and sent to other threads to wait for the session to become
+/// ready or to shut it down.
+#[derive(Clone, Debug)]
+pub struct SessionHandle {
+    state: Arc<SessionState>,
+    /// Statistics of requests
+    stats: Arc<Stats>,
+}
+
+impl SessionHandle {
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        &self.state.mountpoint
+    }
+
+    /// Return a snapshot of the session's statistics (see `Session::stats`)
+    pub fn stats(&self) -> SessionStats {
+        self.stats.snapshot()
+    }
+
+    /// Return the requests the filesystem didn't reply to yet, oldest first
+    #[cfg(feature = "control")]
+    pub(crate) fn in_flight(&self) -> Vec<InFlightRequest> {
+        self.stats.in_flight()
+    }
+
+    /// Return a handle that doesn't keep the session's state alive
+    #[cfg(feature = "control")]
+    pub(crate) fn downgrade(&self) -> WeakSessionHandle {
+        WeakSessionHandle {
+            state: Arc::downgrade(&self.state),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Shut down the session by unmounting the filesystem. If the session loop is running,
+    /// waits until it processed the remaining requests, stopped its workers, waited for the
+    /// replies the filesystem still sends (see `Session::set_drain_timeout`), destroyed the
+    /// filesystem and returned. Fails if the filesystem can't be unmounted (e.g. because it's busy), in
+    /// which case the session keeps running. Must not be called from a method of the
+    /// session's filesystem, since it would wait for itself.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.unmount()?;
+        let _status = self.state.wait_while(|status| status.running);
+        Ok(())
+    }
+
+    /// Block until the filesystem is initialized and therefore usable. Fails if the session
+    /// loop ended before the kernel initialized the filesystem.
+    pub fn wait_ready(&self) -> io::Result<()> {
+        let status = self.state.wait_while(|status| !status.initialized && !status.ended);
+        if status.initialized {
+            Ok(())
+        } else {
+            Err(io::Error::other("session ended before the filesystem was initialized"))
+        }
+    }
+
+    /// Unmount the filesystem without waiting for the session loop to end
+    fn unmount(&self) -> io::Result<()> {
+        info!("Unmounting {}", self.state.mountpoint.display());
+        self.state.update(|status| status.shutdown = true);
+        channel::unmount(&self.state.mountpoint)
+            .inspect_err(|_| self.state.update(|status| status.shutdown = false))
+    }
+
+    /// Shut down the session when the process receives SIGINT or SIGTERM. Spawns a thread
+    /// that waits for the signals, so that e.g. Ctrl-C unmounts the filesystem instead of
+    /// leaving a stale mountpoint behind. If unmounting fails, the session keeps running
+    /// and the next signal tries again.
+    #[cfg(feature = "signals")]
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = self.clone();
+        thread::Builder::new().name("fuse-signals".to_string()).spawn(move || {
+            for signal in signals.forever() {
+                info!("Received signal {}, shutting down", signal);
+                match handle.shutdown() {
+                    Ok(()) => break,
+                    Err(err) => {
+                        error!("Failed to unmount {}: {}", handle.mountpoint().display(), err)
+                    }
+                }
+            }
+        })?;
+        self.state.update(|status| status.threads = true);
+        Ok(())
+    }
+}
+
+/// Handle to a session that doesn't keep its state alive
+#[cfg(feature = "control")]
+#[derive(Clone, Debug)]
+pub(crate) struct WeakSessionHandle {
+    state: Weak<SessionState>,
+    /// Statistics of requests
+    stats: Arc<Stats>,
+}
+
+#[cfg(feature = "control")]
+impl WeakSessionHandle {
+    /// Return a handle to the session, None if it was dropped or its session loop ended
+    pub(crate) fn upgrade(&self) -> Option<SessionHandle> {
+        let state = self.state.upgrade()?;
+        if state.lock().ended {
+            return None;
+        }
+        Some(SessionHandle {
+            state,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+/// Reason a session loop ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionExit {
+    /// The filesystem was unmounted through a `SessionHandle` or `BackgroundSession`
+    Shutdown,
+    /// The filesystem was unmounted by someone else
+    Unmounted,
+    /// The connection was aborted (e.g. through `/sys/fs/fuse/connections`), the mountpoint
+    /// is left disconnected until it's unmounted
+    Aborted,
+    /// The session was handed over to another process, which serves the mount now
+    HandedOver,
+}
+
+/// Connection parameters negotiated with the kernel on init
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// FUSE protocol major version
+    pub proto_major: u32,
+    /// FUSE protocol minor version
+    pub proto_minor: u32,
+    /// Init flags enabled for the connection (`FUSE_*` init flags)
+    pub init_flags: u32,
+    /// Max size of write requests
+    pub max_write: u32,
+}
+
+/// Session lifecycle event
+#[derive(Debug)]
+pub enum SessionEvent<'a> {
+    /// The kernel initialized the filesystem, which is usable from now on
+    Initialized(&'a ConnectionInfo),
+    /// The filesystem was destroyed
+    Destroyed,
+    /// The kernel closed the connection, because the filesystem was unmounted or the
+    /// connection was aborted
+    Unmounted,
+    /// Receiving from the kernel failed, the session loop ends
+    Error(&'a io::Error),
+}
+
+/// Callback for session lifecycle events
+struct EventHandler(Box<dyn FnMut(&SessionEvent<'_>) + Send>);
+
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "EventHandler")
+    }
+}
 
 /// The session data structure
 #[derive(Debug)]
@@ -46,24 +400,56 @@ pub struct Session<FS: RequestDispatcher> {
     /// Filesystem operation implementations
     ch: Channel,
     store: FuseSessionStore,
+    /// State shared with session handles
+    state: Arc<SessionState>,
+    /// Callback for lifecycle events
+    events: Option<EventHandler>,
+    /// Pool of buffers to receive requests into
+    pool: Option<BufferPool>,
+    /// Scheduler of requests
+    scheduler: Option<Scheduler>,
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
         info!("Mounting {}", mountpoint.display());
-        Channel::new(mountpoint, options).map(|ch| {
-            Session {
-                filesystem: filesystem,
-                ch: ch,
-                store: FuseSessionStore {
-                    proto_major: 0,
-                    proto_minor: 0,
-                    initialized: false,
-                    destroyed: false,
-                }
-            }
-        })
+        Channel::new(mountpoint, options).map(|ch| Session::with_channel(filesystem, ch))
+    }
+
+    /// Create a new session for a filesystem that was mounted elsewhere, e.g. by a
+    /// privileged helper that passed the FUSE device fd over a unix socket (`SCM_RIGHTS`).
+    /// The mountpoint is used to unmount, either on drop if `unmount_on_drop` is set or
+    /// through a session handle. The kernel must not have initialized the filesystem
+    /// yet, i.e. no other process may have served requests from the fd.
+    ///
+    /// # Safety
+    ///
+    /// The fd needs to be an open FUSE device fd of a mounted filesystem. The session takes
+    /// ownership of it and closes it when dropped, so it must not be used or closed
+    /// elsewhere.
+    pub unsafe fn from_fd(
+        fd: RawFd,
+        filesystem: FS,
+        mountpoint: &Path,
+        unmount_on_drop: bool,
+    ) -> Session<FS> {
+        info!("Serving {} from fd {}", mountpoint.display(), fd);
+        Session::with_channel(filesystem, Channel::from_fd(fd, mountpoint, unmount_on_drop))
+    }
+
+    /// Create a new session on the given channel
+    pub(crate) fn with_channel(filesystem: FS, ch: Channel) -> Session<FS> {
+        let state = Arc::new(SessionState::new(ch.mountpoint()));
+        Session {
+            filesystem,
+            ch,
+            store: FuseSessionStore::new(),
+            state,
+            events: None,
+            pool: None,
+            scheduler: None,
+        }
     }
 
     /// Return path of the mounted filesystem
@@ -71,87 +457,1014 @@ impl<FS: Filesystem> Session<FS> {
         &self.ch.mountpoint()
     }
 
+    /// Return the counters of requests that failed to parse
+    pub fn request_errors(&self) -> RequestErrorCounters {
+        self.store.request_errors
+    }
+
+    /// Return a snapshot of the statistics of requests dispatched so far: requests and
+    /// errors per operation, replies by error, bytes read and written and the latency from
+    /// receiving requests to sending their replies. Replies sent asynchronously from other
+    /// threads are included.
+    pub fn stats(&self) -> SessionStats {
+        self.store.stats.snapshot()
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
-    /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
-    pub fn run(&mut self) -> io::Result<()> {
-        // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
-                    // Dispatch request
-                    Some(mut req) => self.filesystem.dispatch(&mut req, &mut self.store),
-                    // Quit loop on illegal request
-                    None => break,
-                },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
+    /// having multiple buffers (which take up much memory, unless a buffer pool is set),
+    /// but the filesystem methods may run concurrent by spawning threads. When the
+    /// filesystem is unmounted, the filesystem is destroyed (unless the kernel sent a
+    /// destroy request already).
+    /// Returns why the session ended, or the error receiving from the kernel failed with.
+    pub fn run(&mut self) -> io::Result<SessionExit> {
+        let state = self.state.clone();
+        let _running = state.start();
+        let mut buffers = Buffers::new(self.pool.as_ref(), self.scheduler.as_ref());
+        let res = loop {
+            #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+            let initialized = self.store.initialized;
+            match buffers.process_next(&self.ch, &mut self.filesystem, &mut self.store) {
+                Ok(true) => self.update_progress(),
+                Ok(false) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+            // Switch to io_uring after init if the kernel agreed to it
+            #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+            {
+                if !initialized {
+                    let scheduler = self.scheduler.as_ref();
+                    if let Some(ring) = uring_ring(&self.ch, &self.store, scheduler, 0, 1) {
+                        break self.run_uring(ring, &mut buffers);
+                    }
                 }
             }
+        };
+        self.finish(res)
+    }
+
+    /// Run the session loop on the given io_uring until the filesystem is unmounted
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    fn run_uring(&mut self, mut ring: Ring, buffers: &mut Buffers) -> io::Result<()> {
+        while buffers.process_uring(&mut ring, &self.ch, &mut self.filesystem, &mut self.store)? {
+            self.update_progress();
         }
         Ok(())
     }
+
+    /// Run the session loop like `run`, but also accept connections on the given unix socket
+    /// to hand the session over to another process (see `Handover`). On a connection, the
+    /// session stops reading requests and sends the FUSE device fd, the session state and
+    /// the filesystem state returned by `state` to the connected process. Returns
+    /// `SessionExit::HandedOver` once the other process took over; the filesystem is neither
+    /// destroyed nor unmounted then. Replies to requests received before the handover need
+    /// to be sent before the session is dropped. If the handover fails, the session keeps
+    /// serving the mount. Requests are always read from the FUSE device, since io_uring
+    /// entries can't be handed over. Requests deferred by the scheduler are dispatched before
+    /// a handover connection is accepted.
+    #[cfg(feature = "handover")]
+    pub fn run_with_handover<S, F>(
+        &mut self,
+        listener: &UnixListener,
+        mut state: F,
+    ) -> io::Result<SessionExit>
+    where
+        S: Serialize,
+        F: FnMut(&mut FS) -> S,
+    {
+        #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+        {
+            self.store.uring_depth = 0;
+        }
+        let session_state = self.state.clone();
+        let _running = session_state.start();
+        let mut buffers = Buffers::new(self.pool.as_ref(), self.scheduler.as_ref());
+        let res = loop {
+            // Deferred requests can't be handed over, so only accept a handover connection
+            // once they were dispatched
+            let queued = self.scheduler.as_ref().map_or(0, Scheduler::queued);
+            let listener_fd = if queued == 0 { listener.as_raw_fd() } else { -1 };
+            let wake_fd = self.scheduler.as_ref().map_or(-1, Scheduler::wake_fd);
+            let mut fds = [
+                libc::pollfd { fd: self.ch.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: listener_fd, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: wake_fd, events: libc::POLLIN, revents: 0 },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(EINTR) {
+                    continue;
+                }
+                break Err(err);
+            }
+            if fds[1].revents != 0 {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        info!("Handing over {}", self.ch.mountpoint().display());
+                        let fs_state = state(&mut self.filesystem);
+                        match handover::send(&mut stream, &self.ch, &self.store, &fs_state) {
+                            Ok(()) => {
+                                // The other process serves the mount from now on
+                                self.ch.set_unmount_on_drop(false);
+                                return Ok(SessionExit::HandedOver);
+                            }
+                            Err(err) => warn!("Handover failed, keep serving: {}", err),
+                        }
+                    }
+                    Err(err) => warn!("Failed to accept handover connection: {}", err),
+                }
+            }
+            if fds[2].revents != 0 {
+                buffers.process_deferred(&mut self.filesystem, &mut self.store);
+            }
+            if fds[0].revents != 0 {
+                match buffers.process_next(&self.ch, &mut self.filesystem, &mut self.store) {
+                    Ok(true) => self.update_progress(),
+                    Ok(false) => break Ok(()),
+                    Err(err) => break Err(err),
+                }
+            }
+        };
+        self.finish(res)
+    }
+
+    /// Create a session that resumes serving a mount handed over by another process
+    #[cfg(feature = "handover")]
+    pub(crate) fn resume(filesystem: FS, ch: Channel, store: FuseSessionStore) -> Session<FS> {
+        let session = Session { store, ..Session::with_channel(filesystem, ch) };
+        session.state.update(|status| {
+            status.initialized = session.store.initialized;
+            status.destroyed = session.store.destroyed;
+        });
+        session
+    }
+}
+
+impl<FS: SyncFilesystem> Session<FS> {
+    /// Run the session loop with the given number of worker threads (at least one). Every
+    /// worker has its own request buffer (or takes buffers from the session's buffer pool
+    /// if set), its own clone of the filesystem and, on Linux, its own clone of the FUSE
+    /// device fd, and dispatches requests concurrently. Requests are processed in the
+    /// calling thread until the filesystem is initialized, so that all workers start with
+    /// the negotiated protocol version (see `SyncFilesystem` for the clones `init` and
+    /// `destroy` are called on). Returns after the filesystem was unmounted and all workers
+    /// stopped.
+    pub fn run_multithreaded(&mut self, workers: usize) -> io::Result<SessionExit> {
+        let state = self.state.clone();
+        let _running = state.start();
+        let mut buffers = Buffers::new(self.pool.as_ref(), self.scheduler.as_ref());
+        while !self.store.initialized {
+            match buffers.process_next(&self.ch, &mut self.filesystem, &mut self.store) {
+                Ok(true) => (),
+                Ok(false) => return self.finish(Ok(())),
+                Err(err) => return self.finish(Err(err)),
+            }
+        }
+        self.update_progress();
+        drop(buffers);
+
+        // The calling thread is one of the workers and keeps using the session's channel.
+        // Other workers read from cloned channels, or share the session's channel if
+        // cloning isn't possible.
+        let mut clones = Vec::new();
+        for _ in 1..workers.max(1) {
+            match self.ch.clone_fd() {
+                Ok(ch) => clones.push(ch),
+                Err(err) => {
+                    warn!("Failed to clone FUSE channel, sharing it between workers: {}", err);
+                    break;
+                }
+            }
+        }
+        let channels: Vec<&Channel> = iter::once(&self.ch)
+            .chain(clones.iter())
+            .chain(iter::repeat(&self.ch))
+            .take(workers.max(1))
+            .collect();
+
+        let filesystem = &self.filesystem;
+        let pool = self.pool.as_ref();
+        let scheduler = self.scheduler.as_ref();
+        let mut store = self.store.clone();
+        store.request_errors = RequestErrorCounters::default();
+        let results: Vec<io::Result<FuseSessionStore>> = thread::scope(|scope| {
+            #[cfg_attr(
+                not(all(target_os = "linux", feature = "uring", feature = "abi-7-42")),
+                allow(clippy::unused_enumerate_index)
+            )]
+            let handles: Vec<_> = channels
+                .into_iter()
+                .enumerate()
+                .map(|(_worker, ch)| {
+                    let mut store = store.clone();
+                    let mut filesystem = filesystem.clone();
+                    scope.spawn(move || {
+                        let mut buffers = Buffers::new(pool, scheduler);
+                        // Every worker serves its share of the io_uring queues if the kernel
+                        // agreed to exchange requests through io_uring
+                        #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+                        {
+                            let ring = uring_ring(ch, &store, scheduler, _worker, workers);
+                            if let Some(mut ring) = ring {
+                                while buffers.process_uring(
+                                    &mut ring,
+                                    ch,
+                                    &mut filesystem,
+                                    &mut store,
+                                )? {}
+                                return Ok(store);
+                            }
+                        }
+                        while buffers.process_next(ch, &mut filesystem, &mut store)? {}
+                        Ok(store)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(io::Error::other("session worker panicked"))
+                    })
+                })
+                .collect()
+        });
+
+        // Merge what the workers saw back into the session's state
+        let mut result = Ok(());
+        for worker in results {
+            match worker {
+                Ok(worker) => {
+                    self.store.destroyed |= worker.destroyed;
+                    self.store.request_errors.unknown_operation +=
+                        worker.request_errors.unknown_operation;
+                    self.store.request_errors.malformed += worker.request_errors.malformed;
+                }
+                Err(err) => {
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        self.finish(result)
+    }
+}
+
+/// Buffers a session loop receives requests into
+struct Buffers {
+    /// Where the buffers come from
+    source: BufferSource,
+    /// Pipe to splice requests through
+    #[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+    pipe: SplicePipe,
+    /// Scheduler of requests
+    scheduler: Option<Scheduler>,
+}
+
+/// Source of request buffers
+enum BufferSource {
+    /// A single buffer, reused immediately after dispatching to conserve memory and
+    /// allocations
+    Single(Vec<u8>),
+    /// A buffer taken from the pool for every request (see `Session::set_buffer_pool`)
+    Pool(BufferPool),
+}
+
+/// Pipe to splice requests through, used once the kernel agreed to splice reads
+#[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+enum SplicePipe {
+    /// Not needed so far
+    Unused,
+    /// Requests are spliced through this pipe
+    Active(Pipe),
+    /// Creating the pipe failed, requests are read instead
+    Failed,
 }
 
-impl<'a, FS: Filesystem + Send + 'a> Session<FS> {
+impl Buffers {
+    fn new(pool: Option<&BufferPool>, scheduler: Option<&Scheduler>) -> Buffers {
+        let source = match pool {
+            Some(pool) => BufferSource::Pool(pool.clone()),
+            None => BufferSource::Single(Vec::with_capacity(BUFFER_SIZE)),
+        };
+        Buffers {
+            source,
+            #[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+            pipe: SplicePipe::Unused,
+            scheduler: scheduler.cloned(),
+        }
+    }
+
+    /// Receive the next request from the given channel and dispatch it to the filesystem.
+    /// With a scheduler, requests it deferred are dispatched first if possible, and the next
+    /// request is only received if not too many requests are deferred. Returns false if the
+    /// filesystem was unmounted.
+    fn process_next<FS: RequestDispatcher>(
+        &mut self,
+        ch: &Channel,
+        filesystem: &mut FS,
+        store: &mut FuseSessionStore,
+    ) -> io::Result<bool> {
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.dispatch_ready(filesystem, store);
+            if !wait_receivable(ch, scheduler)? {
+                return Ok(true);
+            }
+        }
+        let scheduler = self.scheduler.as_ref();
+        #[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+        let pipe = splice_pipe(&mut self.pipe, store);
+        #[cfg(not(all(target_os = "linux", feature = "abi-7-14")))]
+        let pipe = None;
+        match &mut self.source {
+            BufferSource::Single(buffer) => {
+                if !receive(ch, pipe, buffer)? {
+                    return Ok(false);
+                }
+                if !buffer.is_empty() {
+                    dispatch(ch.sender(), buffer, None, filesystem, store, scheduler);
+                }
+            }
+            BufferSource::Pool(pool) => {
+                let mut buffer = pool.get();
+                if !receive(ch, pipe, buffer.as_mut_vec())? {
+                    return Ok(false);
+                }
+                if !buffer.is_empty() {
+                    let buffer = Arc::new(buffer);
+                    dispatch(ch.sender(), &buffer, Some(&buffer), filesystem, store, scheduler);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Dispatch requests the scheduler deferred as far as their classes have room
+    #[cfg(feature = "handover")]
+    fn process_deferred<FS: RequestDispatcher>(
+        &mut self,
+        filesystem: &mut FS,
+        store: &mut FuseSessionStore,
+    ) {
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.dispatch_ready(filesystem, store);
+        }
+    }
+
+    /// Process the next completion of the given io_uring: dispatch a request received
+    /// through it, or receive and dispatch a request from the FUSE device. Requests the
+    /// scheduler deferred are dispatched first if possible. While the scheduler is paused,
+    /// requests received through the io_uring are held back, but interrupts and forgets are
+    /// still received from the FUSE device. Returns false once the filesystem was unmounted
+    /// and the kernel released all entries.
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    fn process_uring<FS: RequestDispatcher>(
+        &mut self,
+        ring: &mut Ring,
+        ch: &Channel,
+        filesystem: &mut FS,
+        store: &mut FuseSessionStore,
+    ) -> io::Result<bool> {
+        let paused = match &self.scheduler {
+            Some(scheduler) => {
+                scheduler.dispatch_ready(filesystem, store);
+                scheduler.is_paused()
+            }
+            None => false,
+        };
+        let index = match ring.next(!paused)? {
+            Some(Completion::Request(index)) => index,
+            Some(Completion::Device) => {
+                if !self.process_next(ch, filesystem, store)? {
+                    ring.close_device();
+                }
+                return Ok(true);
+            }
+            Some(Completion::Wake) => return Ok(true),
+            None => return Ok(false),
+        };
+        let scheduler = self.scheduler.as_ref();
+        let sender = ch.uring_sender(ring.entry(index));
+        match &mut self.source {
+            BufferSource::Single(buffer) => {
+                ring.receive(index, buffer);
+                dispatch(sender, buffer, None, filesystem, store, scheduler);
+            }
+            BufferSource::Pool(pool) => {
+                let mut buffer = pool.get();
+                ring.receive(index, buffer.as_mut_vec());
+                let buffer = Arc::new(buffer);
+                dispatch(sender, &buffer, Some(&buffer), filesystem, store, scheduler);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Set up an io_uring for the queues the given one of a number of workers serves if the
+/// kernel agreed to exchange requests through io_uring. The io_uring also waits for deferred
+/// requests of the given scheduler to become ready. Returns None if requests are read from
+/// the FUSE device instead.
+#[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+fn uring_ring(
+    ch: &Channel,
+    store: &FuseSessionStore,
+    scheduler: Option<&Scheduler>,
+    worker: usize,
+    workers: usize,
+) -> Option<Ring> {
+    if !store.uring {
+        return None;
+    }
+    let queues = uring::queues(worker, workers);
+    match Ring::new(ch.as_raw_fd(), &queues, store.uring_depth, store.max_write) {
+        Ok(mut ring) => {
+            if let Some(scheduler) = scheduler {
+                ring.set_wake(scheduler.wake_fd());
+            }
+            Some(ring)
+        }
+        Err(err) => {
+            warn!("Failed to set up io_uring, reading requests from the FUSE device: {}", err);
+            None
+        }
+    }
+}
+
+/// Requests can't be spliced on other systems
+#[cfg(not(target_os = "linux"))]
+enum Pipe {}
+
+/// Return the pipe to splice requests through if the kernel agreed to splice reads
+#[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+fn splice_pipe<'a>(pipe: &'a mut SplicePipe, store: &FuseSessionStore) -> Option<&'a Pipe> {
+    if store.init_flags & FUSE_SPLICE_READ == 0 {
+        return None;
+    }
+    if let SplicePipe::Unused = pipe {
+        // The kernel sends at most `KERNEL_MAX_PAGES` pages of data per request, even if
+        // max_write is larger
+        let max_write = (store.max_write as usize).min(KERNEL_MAX_PAGES * splice::page_size());
+        let capacity = splice::pipe_capacity(max_write);
+        let max_capacity = splice::max_pipe_size();
+        *pipe = if capacity > max_capacity {
+            warn!(
+                "Pipe for splicing requests would exceed the max pipe size ({} > {} bytes), \
+                 reading them",
+                capacity, max_capacity
+            );
+            SplicePipe::Failed
+        } else {
+            match Pipe::new(capacity) {
+                Ok(new) => SplicePipe::Active(new),
+                Err(err) => {
+                    warn!("Failed to create pipe for splicing requests, reading them: {}", err);
+                    SplicePipe::Failed
+                }
+            }
+        };
+    }
+    match pipe {
+        SplicePipe::Active(pipe) => Some(pipe),
+        _ => None,
+    }
+}
+
+/// Receive the next request from the given channel into the given buffer, spliced through
+/// the given pipe if any. Returns false if the filesystem was unmounted, leaves the buffer
+/// empty if receiving should be retried.
+fn receive(ch: &Channel, pipe: Option<&Pipe>, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    // Read the next request from the given channel to kernel driver
+    // The kernel driver makes sure that we get exactly one request per read
+    let res = match pipe {
+        #[cfg(target_os = "linux")]
+        Some(pipe) => ch.receive_splice(pipe, buffer),
+        _ => ch.receive(buffer),
+    };
+    match res {
+        // The other end of an fd that isn't a FUSE device (e.g. a socket) was closed
+        Ok(()) if buffer.is_empty() => Ok(false),
+        Ok(()) => Ok(true),
+        Err(err) => {
+            buffer.clear();
+            match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => Ok(true),
+                // Interrupted system call, retry
+                Some(EINTR) => Ok(true),
+                // Explicitly try again
+                Some(EAGAIN) => Ok(true),
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => Ok(false),
+                // Unhandled error
+                _ => Err(err),
+            }
+        }
+    }
+}
+
+/// Wait until the next request can be received from the given channel. While the scheduler
+/// is paused, requests are still received, since the kernel hands out interrupts and forgets
+/// first. Once it's full, waits for a deferred request to become ready instead. While it
+/// deferred some, also stops waiting once one may have become ready. Returns false if deferred
+/// requests should be dispatched first.
+fn wait_receivable(ch: &Channel, scheduler: &Scheduler) -> io::Result<bool> {
+    if scheduler.is_full() {
+        scheduler.wait_ready();
+        return Ok(false);
+    }
+    if scheduler.queued() == 0 {
+        return Ok(true);
+    }
+    let mut fds = [
+        libc::pollfd { fd: ch.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: scheduler.wake_fd(), events: libc::POLLIN, revents: 0 },
+    ];
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(EINTR) => Ok(false),
+            _ => Err(err),
+        };
+    }
+    Ok(fds[1].revents == 0)
+}
+
+/// Dispatch a received request to the filesystem, or let the given scheduler defer it. The
+/// request buffer is attached to the request if it's pooled, so that the filesystem can keep
+/// its data.
+fn dispatch<FS: RequestDispatcher>(
+    ch: ChannelSender,
+    data: &[u8],
+    buffer: Option<&Arc<RequestBuffer>>,
+    filesystem: &mut FS,
+    store: &mut FuseSessionStore,
+    scheduler: Option<&Scheduler>,
+) {
+    match Request::new(ch.clone(), data, store.unimplemented.clone()) {
+        // Dispatch request
+        Ok(mut req) => {
+            if let Some(buffer) = buffer {
+                req.set_buffer(buffer.clone());
+            }
+            let admitted = match scheduler {
+                Some(scheduler) => scheduler.admit(&mut req),
+                None => true,
+            };
+            if admitted {
+                unwind::dispatch(filesystem, &mut req, store)
+            }
+        }
+        // Answer illegal request and go on with the next one
+        Err(err) => Request::reply_invalid(ch, data, &err, &mut store.request_errors),
+    }
+}
+
+impl<FS: Filesystem + Send + 'static> Session<FS> {
     /// Run the session loop in a background thread
-    pub unsafe fn spawn(self) -> io::Result<BackgroundSession<'a>> {
+    pub fn spawn(self) -> io::Result<BackgroundSession<FS>> {
         BackgroundSession::new(self)
     }
 }
 
+impl<FS: RequestDispatcher> Session<FS> {
+    /// Return a handle that can wait for the session to become ready or shut it down from
+    /// another thread
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            state: self.state.clone(),
+            stats: self.store.stats.clone(),
+        }
+    }
+
+    /// Call the given function on lifecycle events of the session. Events are reported
+    /// by the thread running the session loop.
+    pub fn on_event<F: FnMut(&SessionEvent<'_>) + Send + 'static>(&mut self, handler: F) {
+        self.events = Some(EventHandler(Box::new(handler)));
+    }
+
+    /// Receive requests into buffers taken from the given pool instead of a single buffer,
+    /// so that the filesystem can keep the data of write requests (see
+    /// `Request::write_data`). Session loops wait for a buffer to be returned to the pool
+    /// if all are in use.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.pool = Some(pool);
+    }
+
+    /// Schedule requests with the given scheduler, which limits the number of requests in
+    /// flight per class of requests and dispatches forget, interrupt and notify reply
+    /// requests out-of-band (see `Scheduler`). Every session needs its own scheduler.
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = Some(scheduler);
+    }
+
+    /// Let a watchdog reply with an error to requests the filesystem didn't reply to within
+    /// the given deadlines (see `Deadlines`). Later replies of the filesystem are suppressed
+    /// (see `Deadlines::on_suppressed`).
+    pub fn set_deadlines(&mut self, deadlines: Deadlines) {
+        self.store.watchdog = Some(Watchdog::new(deadlines));
+    }
+
+    /// Choose what the session does after a filesystem method panicked. Panics are always
+    /// caught and logged, and replies the filesystem didn't send are answered with EIO. By
+    /// default, the session keeps serving requests (see `PanicPolicy`).
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.store.panics = Some(PanicHandler::new(policy, self.ch.mountpoint()));
+    }
+
+    /// Harden the process once the filesystem is mounted: change the root directory, switch
+    /// to an unprivileged user, set `no_new_privs` and install a system call filter (see
+    /// `Sandbox`). Fails if the session loop or other threads were started already (e.g. by
+    /// `run`, `serve_control` or `shutdown_on_signals`). Afterwards, the session
+    /// can't unmount the filesystem itself anymore. It needs to be unmounted from outside
+    /// (e.g. with `fusermount -u`), which ends the session loop. `run_multithreaded` opens
+    /// `/dev/fuse` to clone the FUSE device fd, which needs the device within the new root
+    /// and the `openat` and `ioctl` system calls to be allowed (see
+    /// `Sandbox::allow_syscalls` for other features that need further system calls).
+    #[cfg(target_os = "linux")]
+    pub fn enter_sandbox(&self, sandbox: &Sandbox) -> io::Result<()> {
+        if self.state.lock().threads {
+            return Err(io::Error::other("session threads started before entering the sandbox"));
+        }
+        sandbox.enter()
+    }
+
+    /// Serve the control socket on the given listener in a background thread until the
+    /// session loop ended or the session is dropped (see `ControlClient`). Clients can list
+    /// the requests in flight, get the session's statistics, change the log level, ask the
+    /// kernel to invalidate cached inodes and entries (with the `abi-7-12` feature) and
+    /// unmount the filesystem. Requests in flight are tracked from now on. The listener's
+    /// socket file isn't removed.
+    #[cfg(feature = "control")]
+    pub fn serve_control(&mut self, listener: UnixListener) -> io::Result<()> {
+        #[cfg(feature = "abi-7-12")]
+        let ch = {
+            let fd = unsafe { libc::fcntl(self.ch.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            unsafe { Channel::from_fd(fd, self.ch.mountpoint(), false) }
+        };
+        self.store.stats.track_in_flight();
+        control::serve(
+            listener,
+            self.handle().downgrade(),
+            #[cfg(feature = "abi-7-12")]
+            ch,
+        )?;
+        self.state.update(|status| status.threads = true);
+        Ok(())
+    }
+
+    /// Ask the kernel to send write requests with at most the given number of bytes of data
+    /// (between 4k and `MAX_WRITE_SIZE`, which is the default). A smaller size reduces the
+    /// memory of buffers that need to hold the largest request, like the entries of
+    /// io_uring queues. Needs to be set before the filesystem is initialized.
+    pub fn set_max_write(&mut self, max_write: usize) {
+        self.store.max_write = max_write.clamp(4096, MAX_WRITE_SIZE) as u32;
+    }
+
+    /// Wait at most the given time for the filesystem to reply to requests in flight (e.g.
+    /// from its own threads) before destroying it after the session loop ended, 5 seconds by
+    /// default. Replies that are still pending after the timeout or are held forever (e.g.
+    /// leaked) are logged. With a timeout of zero, the filesystem is destroyed right away.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.store.drain_timeout = timeout;
+    }
+
+    /// Exchange requests and replies with the kernel through io_uring instead of reading and
+    /// writing the FUSE device if the kernel supports it (Linux 6.14 and later, with the fuse
+    /// module's `enable_uring` parameter set). Every CPU gets a queue of `depth` entries (at
+    /// least one) with a buffer for the largest request each (see `set_max_write`). Needs
+    /// to be set before the filesystem is initialized. The session keeps using the FUSE
+    /// device if the kernel doesn't agree to it or setting up io_uring fails. Requires the
+    /// `abi-7-42` feature besides the `uring` feature.
+    #[cfg(all(target_os = "linux", feature = "uring", feature = "abi-7-42"))]
+    pub fn set_io_uring(&mut self, depth: usize) {
+        self.store.uring_depth = depth.max(1);
+    }
+
+    /// Return the filesystem of the session. Drops the channel, which unmounts the
+    /// filesystem if it's still mounted.
+    pub fn into_filesystem(self) -> FS {
+        self.filesystem
+    }
+
+    /// Report an event to the event handler
+    fn notify(&mut self, event: SessionEvent<'_>) {
+        if let Some(EventHandler(handler)) = &mut self.events {
+            handler(&event);
+        }
+    }
+
+    /// Report the filesystem's initialization and destruction once they happened
+    fn update_progress(&mut self) {
+        let (initialized, destroyed) = {
+            let status = self.state.lock();
+            (status.initialized, status.destroyed)
+        };
+        if self.store.initialized && !initialized {
+            self.state.update(|status| status.initialized = true);
+            let info = ConnectionInfo {
+                proto_major: self.store.proto_major,
+                proto_minor: self.store.proto_minor,
+                init_flags: self.store.init_flags,
+                max_write: self.store.max_write,
+            };
+            self.notify(SessionEvent::Initialized(&info));
+        }
+        if self.store.destroyed && !destroyed {
+            self.state.update(|status| status.destroyed = true);
+            self.notify(SessionEvent::Destroyed);
+        }
+    }
+
+    /// Destroy the filesystem after the session loop ended and return why it ended
+    fn finish(&mut self, res: io::Result<()>) -> io::Result<SessionExit> {
+        let exit = match res {
+            Ok(()) => {
+                self.notify(SessionEvent::Unmounted);
+                if self.state.lock().shutdown {
+                    Ok(SessionExit::Shutdown)
+                } else if is_disconnected(self.ch.mountpoint()) {
+                    Ok(SessionExit::Aborted)
+                } else {
+                    Ok(SessionExit::Unmounted)
+                }
+            }
+            Err(err) => {
+                self.notify(SessionEvent::Error(&err));
+                Err(err)
+            }
+        };
+        // Requests that are still deferred can't be replied to anymore
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.clear();
+        }
+        self.filesystem.destroy_unmounted(self.ch.sender(), &mut self.store);
+        self.update_progress();
+        exit
+    }
+}
+
+/// Returns true if the given path is a FUSE mountpoint whose connection was aborted
+fn is_disconnected(mountpoint: &Path) -> bool {
+    match fs::metadata(mountpoint) {
+        Err(err) => err.raw_os_error() == Some(ENOTCONN),
+        Ok(_) => false,
+    }
+}
+
 /// The background session data structure
-pub struct BackgroundSession<'a> {
+pub struct BackgroundSession<FS> {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
-    /// Thread guard of the background session
-    pub guard: JoinGuard<'a, io::Result<()>>,
+    /// Thread running the session loop, returns the filesystem when the loop ended
+    guard: Option<JoinHandle<(FS, io::Result<SessionExit>)>>,
+    /// Handle of the session
+    handle: SessionHandle,
+    /// True if the filesystem was unmounted already
+    unmounted: bool,
 }
 
-impl<'a> BackgroundSession<'a> {
+impl<FS: Filesystem + Send + 'static> BackgroundSession<FS> {
     /// Create a new background session for the given session by running its
     /// session loop in a background thread. If the returned handle is dropped,
     /// the filesystem is unmounted and the given session ends.
-    pub unsafe fn new<FS: Filesystem + Send + 'a>(se: Session<FS>) -> io::Result<BackgroundSession<'a>> {
+    pub fn new(se: Session<FS>) -> io::Result<BackgroundSession<FS>> {
         let mountpoint = se.mountpoint().to_path_buf();
-        let guard = scoped(move || {
+        let handle = se.handle();
+        let guard = thread::Builder::new().name("fuse-session".to_string()).spawn(move || {
             let mut se = se;
-            se.run()
-        });
-        Ok(BackgroundSession { mountpoint, guard })
+            let res = se.run();
+            (se.into_filesystem(), res)
+        })?;
+        Ok(BackgroundSession { mountpoint, guard: Some(guard), handle, unmounted: false })
+    }
+}
+
+impl<FS> BackgroundSession<FS> {
+    /// Return a handle of the session
+    pub fn handle(&self) -> &SessionHandle {
+        &self.handle
+    }
+
+    /// Return a snapshot of the session's statistics (see `Session::stats`)
+    pub fn stats(&self) -> SessionStats {
+        self.handle.stats()
+    }
+
+    /// Block until the filesystem is initialized and therefore usable (see
+    /// `SessionHandle::wait_ready`)
+    pub fn wait_ready(&self) -> io::Result<()> {
+        self.handle.wait_ready()
+    }
+
+    /// Unmount the filesystem, which ends the session loop. Unlike dropping the
+    /// background session, this returns the error if unmounting fails (e.g. because
+    /// the filesystem is busy), in which case the session keeps running.
+    pub fn unmount(&mut self) -> io::Result<()> {
+        if !self.unmounted {
+            self.handle.unmount()?;
+            self.unmounted = true;
+        }
+        Ok(())
+    }
+
+    /// Wait for the session loop to end and return why it ended. Blocks until the
+    /// filesystem is unmounted, either by `unmount` or externally.
+    pub fn join(mut self) -> io::Result<SessionExit> {
+        self.wait().1
+    }
+
+    /// Wait for the session loop to end and return the filesystem together with why the
+    /// session loop ended. The filesystem is returned even if the session loop failed and
+    /// is only missing if the session thread panicked. Blocks until the filesystem is
+    /// unmounted, either by `unmount` or externally.
+    pub fn into_filesystem(mut self) -> (Option<FS>, io::Result<SessionExit>) {
+        self.wait()
+    }
+
+    /// Wait for the session thread to end and return the filesystem and the result of
+    /// the session loop
+    fn wait(&mut self) -> (Option<FS>, io::Result<SessionExit>) {
+        // The session loop only ends after unmount, so don't try again on drop
+        self.unmounted = true;
+        let guard = self.guard.take().expect("background session joined twice");
+        match guard.join() {
+            Ok((filesystem, res)) => (Some(filesystem), res),
+            Err(_) => (None, Err(io::Error::other("session thread panicked"))),
+        }
     }
 }
 
-impl<'a> Drop for BackgroundSession<'a> {
+impl<FS> Drop for BackgroundSession<FS> {
     fn drop(&mut self) {
-        info!("Unmounting {}", self.mountpoint.display());
+        if self.unmounted {
+            return;
+        }
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
-        match channel::unmount(&self.mountpoint) {
-            Ok(()) => (),
+        match self.unmount() {
+            Ok(()) => {
+                if let Some(guard) = self.guard.take() {
+                    let _ = guard.join();
+                }
+            }
+            // Don't wait for a session that is still mounted
             Err(err) => error!("Failed to unmount {}: {}", self.mountpoint.display(), err),
         }
     }
 }
 
-// replace with #[derive(Debug)] if Debug ever gets implemented for
-// thread_scoped::JoinGuard
-impl<'a> fmt::Debug for BackgroundSession<'a> {
+impl<FS> fmt::Debug for BackgroundSession<FS> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "BackgroundSession {{ mountpoint: {:?}, guard: JoinGuard<()> }}", self.mountpoint)
+        f.debug_struct("BackgroundSession")
+            .field("mountpoint", &self.mountpoint)
+            .field("unmounted", &self.unmounted)
+            .finish()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{Session, SessionExit, DRAIN_TIMEOUT};
+    use crate::channel::Channel;
+    use crate::test_util::{channel_at, RawRequest};
+    use crate::{Filesystem, ReplyAttr, Request};
+    use fuse_abi::{fuse_opcode, FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+    use std::ffi::CString;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Filesystem that holds on to the replies of getattr requests and tells when it's
+    /// destroyed
+    struct HoldingFilesystem {
+        replies: Vec<ReplyAttr>,
+        destroyed: Arc<AtomicBool>,
+    }
+
+    impl Filesystem for HoldingFilesystem {
+        fn destroy(&mut self, _req: &Request<'_>) {
+            self.destroyed.store(true, Ordering::SeqCst);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+            self.replies.push(reply);
+        }
+    }
+
+    /// Returns true if the given directory is the root of a mount
+    fn is_mountpoint(dir: &Path) -> bool {
+        let parent = dir.parent().unwrap();
+        fs::metadata(dir).unwrap().dev() != fs::metadata(parent).unwrap().dev()
+    }
+
+    /// Mount a tmpfs on the given directory in a new mount namespace, so that the session
+    /// can unmount it, and shut the session down through its handle. Returns the number of
+    /// the step that failed.
+    fn shut_down_mounted(ch: Channel, mut kernel: UnixStream, dir: &Path) -> Result<(), i32> {
+        let mut flags = libc::CLONE_NEWNS;
+        if unsafe { libc::geteuid() } != 0 {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        let root = CString::new("/").unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let target = CString::new(dir.as_os_str().as_bytes()).unwrap();
+        let private = libc::MS_REC | libc::MS_PRIVATE;
+        if unsafe { libc::unshare(flags) } != 0
+            || unsafe { libc::mount(ptr::null(), root.as_ptr(), ptr::null(), private, ptr::null()) }
+                != 0
+            || unsafe {
+                libc::mount(tmpfs.as_ptr(), target.as_ptr(), tmpfs.as_ptr(), 0, ptr::null())
+            } != 0
+        {
+            return Err(1);
+        }
+
+        let destroyed = Arc::new(AtomicBool::new(false));
+        let filesystem = HoldingFilesystem {
+            replies: Vec::new(),
+            destroyed: destroyed.clone(),
+        };
+        let mut se = Session::with_channel(filesystem, ch);
+        se.set_drain_timeout(Duration::from_millis(10));
+        let handle = se.handle();
+        let session = thread::spawn(move || se.run());
+
+        let init = [
+            FUSE_KERNEL_VERSION.to_ne_bytes(),
+            FUSE_KERNEL_MINOR_VERSION.to_ne_bytes(),
+            0u32.to_ne_bytes(),
+            0u32.to_ne_bytes(),
+        ]
+        .concat();
+        let init = RawRequest::new(fuse_opcode::FUSE_INIT, 1, 0, &init);
+        kernel.write_all(init.as_bytes()).map_err(|_| 2)?;
+        let mut reply = [0; 256];
+        kernel.read(&mut reply).map_err(|_| 2)?;
+        handle.wait_ready().map_err(|_| 2)?;
+        // The filesystem never replies to this request
+        let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, 2, 1, &[0; 16]);
+        kernel.write_all(getattr.as_bytes()).map_err(|_| 2)?;
+
+        let start = Instant::now();
+        let shutdown = thread::spawn(move || handle.shutdown());
+        // Like the kernel, end the connection once the filesystem was unmounted
+        while is_mountpoint(dir) {
+            if start.elapsed() > DRAIN_TIMEOUT {
+                return Err(3);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(kernel);
+        if shutdown.join().unwrap().is_err() {
+            return Err(4);
+        }
+        if session.join().unwrap().ok() != Some(SessionExit::Shutdown) {
+            return Err(5);
+        }
+        // The filesystem is destroyed without waiting long for the reply it holds
+        if !destroyed.load(Ordering::SeqCst) || start.elapsed() >= DRAIN_TIMEOUT {
+            return Err(6);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_destroys_filesystem() {
+        let file = format!("rust-fuse-shutdown-{}", std::process::id());
+        let dir = std::env::temp_dir().join(file);
+        fs::create_dir(&dir).unwrap();
+        let (ch, kernel) = channel_at(&dir);
+
+        // Mount in a child process, since its mount namespace can't be left again
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let status = match shut_down_mounted(ch, kernel, &dir) {
+                Ok(()) => 0,
+                Err(step) => step,
+            };
+            unsafe { libc::_exit(status) };
+        }
+        drop(ch);
+        drop(kernel);
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        fs::remove_dir(&dir).unwrap();
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
     }
 }
@@ -0,0 +1,282 @@
+//! Splice-based I/O with the FUSE device (Linux only)
+//!
+//! Requests can be spliced from the FUSE device into a pipe and read from there, and replies
+//! with data from a file descriptor can be spliced to the FUSE device through pipes, so that
+//! the data is moved between the kernel buffers without copying it to userspace.
+
+use libc::{self, c_int, c_void, loff_t};
+use log::error;
+use std::cell::RefCell;
+#[cfg(feature = "abi-7-14")]
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use fuse_abi::fuse_out_header;
+
+/// A pipe with a capacity large enough for a request or reply
+#[derive(Debug)]
+pub(crate) struct Pipe {
+    read: RawFd,
+    write: RawFd,
+    capacity: usize,
+}
+
+impl Pipe {
+    /// Create a pipe that can hold at least the given number of bytes. Fails if the pipe
+    /// can't be made that large (see `/proc/sys/fs/pipe-max-size`).
+    pub(crate) fn new(capacity: usize) -> io::Result<Pipe> {
+        let mut fds: [c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pipe = Pipe {
+            read: fds[0],
+            write: fds[1],
+            capacity: 0,
+        };
+        pipe.reserve(capacity)?;
+        Ok(pipe)
+    }
+
+    /// Grow the pipe to hold at least the given number of bytes
+    fn reserve(&mut self, capacity: usize) -> io::Result<()> {
+        if self.capacity < capacity {
+            let rc = unsafe { libc::fcntl(self.write, libc::F_SETPIPE_SZ, capacity as c_int) };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.capacity = rc as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// Default of `/proc/sys/fs/pipe-max-size`
+#[cfg(feature = "abi-7-14")]
+const DEFAULT_MAX_PIPE_SIZE: usize = 1024 * 1024;
+
+/// Return the largest capacity an unprivileged process can give a pipe
+#[cfg(feature = "abi-7-14")]
+pub(crate) fn max_pipe_size() -> usize {
+    fs::read_to_string("/proc/sys/fs/pipe-max-size")
+        .ok()
+        .and_then(|size| size.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_PIPE_SIZE)
+}
+
+/// Return the size of a memory page
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Return the capacity a pipe needs to hold a request or reply with the given number of
+/// bytes of data. A pipe holds a number of pages rather than bytes. The header takes up a
+/// page of its own and data at an unaligned offset may span an additional page.
+pub(crate) fn pipe_capacity(len: usize) -> usize {
+    let page_size = page_size();
+    (len / page_size + 3) * page_size
+}
+
+/// Splice up to `len` bytes from one fd to another, retrying if interrupted
+fn splice(
+    from: RawFd,
+    offset: Option<&mut loff_t>,
+    to: RawFd,
+    len: usize,
+    flags: libc::c_uint,
+) -> io::Result<usize> {
+    let offset = offset.map_or(ptr::null_mut(), |offset| offset as *mut loff_t);
+    loop {
+        let rc = unsafe { libc::splice(from, offset, to, ptr::null_mut(), len, flags) };
+        if rc >= 0 {
+            return Ok(rc as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Receive a request from the FUSE device by splicing it into the given pipe and reading
+/// it from there into the given buffer
+pub(crate) fn receive(dev: RawFd, pipe: &Pipe, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let len = splice(
+        dev,
+        None,
+        pipe.write,
+        buffer.capacity(),
+        libc::SPLICE_F_MOVE,
+    )?;
+    let mut pos = 0;
+    while pos < len {
+        let rc = unsafe {
+            libc::read(
+                pipe.read,
+                buffer.as_mut_ptr().add(pos) as *mut c_void,
+                len - pos,
+            )
+        };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        pos += rc as usize;
+    }
+    unsafe {
+        buffer.set_len(len);
+    }
+    Ok(())
+}
+
+thread_local! {
+    /// Pipe for splicing replies
+    static REPLY_PIPE: RefCell<Option<Pipe>> = const { RefCell::new(None) };
+}
+
+/// Send a successful reply with up to `len` bytes of the given fd at the given offset to
+/// the FUSE device by splicing the data through a pipe. Returns the number of bytes of data
+/// sent, or None without sending anything if the fd isn't a regular file, doesn't support
+/// splicing or the pipe can't hold the data, in which case the data needs to be copied.
+/// Returns an error without sending anything if reading from the fd fails.
+pub(crate) fn send_reply(
+    dev: RawFd,
+    unique: u64,
+    fd: RawFd,
+    offset: i64,
+    len: usize,
+) -> io::Result<Option<usize>> {
+    // The header needs to be in the pipe before the data, so the length of the data needs
+    // to be known up front
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return Ok(None);
+    }
+    let len = len.min((stat.st_size - offset).max(0) as usize);
+    let header_len = mem::size_of::<fuse_out_header>();
+    let header = fuse_out_header {
+        len: (header_len + len) as u32,
+        error: 0,
+        unique,
+    };
+    let capacity = pipe_capacity(len);
+    REPLY_PIPE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let pipe = match cell.take() {
+            Some(mut pipe) => match pipe.reserve(capacity) {
+                Ok(()) => pipe,
+                Err(_) => {
+                    *cell = Some(pipe);
+                    return Ok(None);
+                }
+            },
+            None => match Pipe::new(capacity) {
+                Ok(pipe) => pipe,
+                Err(_) => return Ok(None),
+            },
+        };
+        // Whatever is left in the pipe on failure can't be taken back, so the pipe is only
+        // kept for the next reply if everything was sent
+        if !fill(&pipe, &header, fd, offset, len)? {
+            return Ok(None);
+        }
+        let sent = match splice(pipe.read, None, dev, header_len + len, libc::SPLICE_F_MOVE) {
+            Ok(sent) if sent == header_len + len => {
+                *cell = Some(pipe);
+                len
+            }
+            Ok(sent) => {
+                error!("Failed to send FUSE reply: short splice to FUSE device");
+                sent.saturating_sub(header_len)
+            }
+            Err(err) => {
+                error!("Failed to send FUSE reply: {}", err);
+                0
+            }
+        };
+        Ok(Some(sent))
+    })
+}
+
+/// Write the header to the pipe and splice the data behind it. Returns false if the fd
+/// doesn't support splicing or the file was truncated meanwhile.
+fn fill(
+    pipe: &Pipe,
+    header: &fuse_out_header,
+    fd: RawFd,
+    offset: i64,
+    len: usize,
+) -> io::Result<bool> {
+    let header_len = mem::size_of::<fuse_out_header>();
+    let rc = unsafe { libc::write(pipe.write, header as *const _ as *const c_void, header_len) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut offset = offset as loff_t;
+    let mut spliced = 0;
+    while spliced < len {
+        match splice(
+            fd,
+            Some(&mut offset),
+            pipe.write,
+            len - spliced,
+            libc::SPLICE_F_MOVE,
+        ) {
+            Ok(0) => return Ok(false),
+            Ok(n) => spliced += n,
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) && spliced == 0 => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::send_reply;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn splice_reply() {
+        let path = std::env::temp_dir().join(format!("rust-fuse-splice-{}", std::process::id()));
+        fs::write(&path, [0x00, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // A socket stands in for the FUSE device
+        let (dev, mut peer) = UnixStream::pair().unwrap();
+        let sent = send_reply(dev.as_raw_fd(), 0xdeadbeef, file.as_raw_fd(), 1, 16).unwrap();
+        assert_eq!(sent, Some(4));
+        let mut buffer = [0u8; 20];
+        peer.read_exact(&mut buffer).unwrap();
+        assert_eq!(
+            buffer,
+            [
+                0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00,
+                0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+            ]
+        );
+    }
+}
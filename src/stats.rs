@@ -0,0 +1,438 @@
+//! Session statistics
+//!
+//! Sessions count the requests they dispatch per opcode, the errors they reply with, the
+//! bytes read and written and the time from receiving requests to sending their replies.
+//! Counters are atomic, so that replies sent from other threads are counted as well.
+//! `Session::stats` returns a snapshot of them.
+
+use fuse_abi::fuse_opcode;
+use libc::c_int;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(feature = "control")]
+use std::collections::HashMap;
+#[cfg(feature = "control")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "control")]
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "control")]
+use crate::control::InFlightRequest;
+#[cfg(feature = "control")]
+use crate::request::Request;
+
+/// Only opcodes below this are counted, which covers all regular operations
+const OPCODES: usize = 64;
+
+/// Only errors below this are counted by errno, which covers all errors of common systems
+const ERRNOS: usize = 256;
+
+/// Number of buckets of latency histograms
+const BUCKETS: usize = 32;
+
+/// Statistics of a session
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionStats {
+    /// Statistics of the operations requested so far, by opcode
+    pub operations: BTreeMap<u32, OperationStats>,
+    /// Number of replies with an error, by errno
+    pub errors: BTreeMap<c_int, u64>,
+    /// Number of bytes replied to read requests
+    pub bytes_read: u64,
+    /// Number of bytes the filesystem wrote for write requests
+    pub bytes_written: u64,
+}
+
+impl SessionStats {
+    /// Return the statistics of the operation with the given opcode, None if it wasn't
+    /// requested so far
+    pub fn operation(&self, opcode: fuse_opcode) -> Option<&OperationStats> {
+        self.operations.get(&(opcode as u32))
+    }
+}
+
+/// Statistics of an operation
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperationStats {
+    /// Number of requests dispatched to the filesystem
+    pub requests: u64,
+    /// Number of replies with an error
+    pub errors: u64,
+    /// Time from receiving requests to sending their replies
+    pub latency: LatencyHistogram,
+}
+
+/// Histogram of latencies with buckets growing by powers of two. Bucket `i` counts latencies
+/// below 2^i microseconds that don't fit into the previous bucket; the last bucket counts
+/// all longer latencies.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// Number of latencies per bucket
+    pub buckets: Vec<u64>,
+    /// Sum of all latencies
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Return the number of latencies
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Return the mean latency, None if there are no latencies
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(Duration::from_nanos(
+                (self.total.as_nanos() / u128::from(count)) as u64,
+            )),
+        }
+    }
+
+    /// Return an upper bound of the given percentile (0 to 100) of latencies, which is the
+    /// upper bound of the bucket it falls into. Returns None if there are no latencies, and
+    /// `Duration::MAX` if the percentile falls into the last bucket.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                return Some(match i {
+                    i if i + 1 == BUCKETS => Duration::MAX,
+                    i => Duration::from_micros(1 << i),
+                });
+            }
+        }
+        Some(Duration::MAX)
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count())
+            .field("mean", &self.mean())
+            .finish()
+    }
+}
+
+/// Counters of an operation
+#[derive(Default)]
+struct OperationCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency: [AtomicU64; BUCKETS],
+    /// Sum of all latencies in nanoseconds
+    total: AtomicU64,
+}
+
+/// Request the filesystem didn't reply to yet
+#[cfg(feature = "control")]
+struct InFlight {
+    opcode: u32,
+    nodeid: u64,
+    pid: u32,
+    /// When the request was received
+    received: Instant,
+}
+
+/// Counters of a session, shared by its reply senders
+pub(crate) struct Stats {
+    operations: Vec<OperationCounters>,
+    errors: Vec<AtomicU64>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// True while requests in flight are tracked
+    #[cfg(feature = "control")]
+    tracked: AtomicBool,
+    /// Requests in flight by unique identifier
+    #[cfg(feature = "control")]
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            operations: (0..OPCODES).map(|_| OperationCounters::default()).collect(),
+            errors: (0..ERRNOS).map(|_| AtomicU64::new(0)).collect(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            #[cfg(feature = "control")]
+            tracked: AtomicBool::new(false),
+            #[cfg(feature = "control")]
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a request with the given opcode
+    pub(crate) fn request(&self, opcode: u32) {
+        if let Some(counters) = self.operations.get(opcode as usize) {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a reply to a request with the given opcode
+    fn reply(&self, opcode: u32, errno: c_int, latency: Duration) {
+        if let Some(counters) = self.operations.get(opcode as usize) {
+            if errno != 0 {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let micros = latency.as_micros();
+            // Index of the smallest power of two greater than the latency
+            let bucket = (128 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+            counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+            let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+            counters.total.fetch_add(nanos, Ordering::Relaxed);
+        }
+        if errno != 0 {
+            if let Some(counter) = self.errors.get(errno as usize) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Track requests in flight from now on (see `in_flight`)
+    #[cfg(feature = "control")]
+    pub(crate) fn track_in_flight(&self) {
+        self.tracked.store(true, Ordering::Relaxed);
+    }
+
+    /// Track the given request until it's replied to if requests in flight are tracked
+    #[cfg(feature = "control")]
+    pub(crate) fn dispatched(&self, req: &Request<'_>, received: Instant) {
+        if self.tracked.load(Ordering::Relaxed) {
+            let request = InFlight {
+                opcode: req.opcode(),
+                nodeid: req.nodeid(),
+                pid: req.pid(),
+                received,
+            };
+            self.lock_in_flight().insert(req.unique(), request);
+        }
+    }
+
+    /// Return the requests the filesystem didn't reply to yet, oldest first. Only requests
+    /// dispatched since requests in flight are tracked are included.
+    #[cfg(feature = "control")]
+    pub(crate) fn in_flight(&self) -> Vec<InFlightRequest> {
+        let now = Instant::now();
+        let mut requests: Vec<InFlightRequest> = self
+            .lock_in_flight()
+            .iter()
+            .map(|(unique, request)| InFlightRequest {
+                unique: *unique,
+                opcode: request.opcode,
+                nodeid: request.nodeid,
+                pid: request.pid,
+                age: now.saturating_duration_since(request.received),
+            })
+            .collect();
+        requests.sort_by_key(|request| std::cmp::Reverse(request.age));
+        requests
+    }
+
+    #[cfg(feature = "control")]
+    fn lock_in_flight(&self) -> MutexGuard<'_, HashMap<u64, InFlight>> {
+        self.in_flight.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Return a snapshot of the counters
+    pub(crate) fn snapshot(&self) -> SessionStats {
+        let mut operations = BTreeMap::new();
+        for (opcode, counters) in self.operations.iter().enumerate() {
+            let requests = counters.requests.load(Ordering::Relaxed);
+            let buckets: Vec<u64> = counters
+                .latency
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect();
+            if requests == 0 && buckets.iter().all(|bucket| *bucket == 0) {
+                continue;
+            }
+            let stats = OperationStats {
+                requests,
+                errors: counters.errors.load(Ordering::Relaxed),
+                latency: LatencyHistogram {
+                    buckets,
+                    total: Duration::from_nanos(counters.total.load(Ordering::Relaxed)),
+                },
+            };
+            operations.insert(opcode as u32, stats);
+        }
+        let errors = self
+            .errors
+            .iter()
+            .enumerate()
+            .map(|(errno, counter)| (errno as c_int, counter.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        SessionStats {
+            operations,
+            errors,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Stats")
+    }
+}
+
+/// Return the error of the reply with the given data, which starts with the
+/// `fuse_out_header`, 0 if it's successful
+pub(crate) fn reply_errno(data: &[&[u8]]) -> c_int {
+    data.first()
+        .and_then(|header| header.get(4..8))
+        .map_or(0, |error| {
+            -i32::from_ne_bytes([error[0], error[1], error[2], error[3]])
+        })
+}
+
+/// Return the number of bytes read or written according to the given successful reply to a
+/// read or write request, None for replies to other requests
+pub(crate) fn reply_bytes(opcode: u32, data: &[&[u8]]) -> Option<u64> {
+    let payload = &data[data.len().min(1)..];
+    if opcode == fuse_opcode::FUSE_READ as u32 {
+        Some(payload.iter().map(|part| part.len() as u64).sum())
+    } else if opcode == fuse_opcode::FUSE_WRITE as u32 {
+        // The payload is a fuse_write_out, which starts with the size written
+        let size = payload.first().and_then(|part| part.get(0..4))?;
+        Some(u64::from(u32::from_ne_bytes([
+            size[0], size[1], size[2], size[3],
+        ])))
+    } else {
+        None
+    }
+}
+
+/// Counts the reply to a request in the statistics of its session
+#[derive(Clone, Debug)]
+pub(crate) struct RequestStats {
+    stats: Arc<Stats>,
+    /// Unique identifier of the request, to stop tracking it once it's replied to
+    #[cfg(feature = "control")]
+    unique: u64,
+    opcode: u32,
+    /// When the request was received
+    received: Instant,
+}
+
+impl RequestStats {
+    pub(crate) fn new(
+        stats: Arc<Stats>,
+        unique: u64,
+        opcode: u32,
+        received: Instant,
+    ) -> RequestStats {
+        #[cfg(not(feature = "control"))]
+        let _ = unique;
+        RequestStats {
+            stats,
+            #[cfg(feature = "control")]
+            unique,
+            opcode,
+            received,
+        }
+    }
+
+    /// Count a reply with the given error (0 if successful) that read or wrote the given
+    /// number of bytes
+    pub(crate) fn reply(&self, errno: c_int, bytes: Option<u64>) {
+        if let Some(bytes) = bytes {
+            let counter = if self.opcode == fuse_opcode::FUSE_WRITE as u32 {
+                &self.stats.bytes_written
+            } else {
+                &self.stats.bytes_read
+            };
+            counter.fetch_add(bytes, Ordering::Relaxed);
+        }
+        self.stats
+            .reply(self.opcode, errno, self.received.elapsed());
+        #[cfg(feature = "control")]
+        {
+            if self.stats.tracked.load(Ordering::Relaxed) {
+                self.stats.lock_in_flight().remove(&self.unique);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reply_bytes, reply_errno, LatencyHistogram, RequestStats, Stats};
+    use fuse_abi::fuse_opcode;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn counts_replies() {
+        let stats = Arc::new(Stats::default());
+        let read = fuse_opcode::FUSE_READ as u32;
+        let write = fuse_opcode::FUSE_WRITE as u32;
+        let received = Instant::now() - Duration::from_millis(3);
+        stats.request(read);
+        stats.request(read);
+        stats.request(write);
+        let header = [16u32.to_ne_bytes(), 0i32.to_ne_bytes()].concat();
+        let reply: &[&[u8]] = &[&header, &[0; 100]];
+        assert_eq!(reply_errno(reply), 0);
+        assert_eq!(reply_bytes(read, reply), Some(100));
+        RequestStats::new(stats.clone(), 1, read, received).reply(0, reply_bytes(read, reply));
+        let header = [16u32.to_ne_bytes(), (-libc::EIO).to_ne_bytes()].concat();
+        assert_eq!(reply_errno(&[&header]), libc::EIO);
+        RequestStats::new(stats.clone(), 2, read, received).reply(libc::EIO, None);
+        let written = [42u32.to_ne_bytes(), 0u32.to_ne_bytes()].concat();
+        let reply: &[&[u8]] = &[&[0; 16], &written];
+        assert_eq!(reply_bytes(write, reply), Some(42));
+        RequestStats::new(stats.clone(), 3, write, received).reply(0, Some(42));
+
+        let snapshot = stats.snapshot();
+        let reads = snapshot.operation(fuse_opcode::FUSE_READ).unwrap();
+        assert_eq!(reads.requests, 2);
+        assert_eq!(reads.errors, 1);
+        assert_eq!(reads.latency.count(), 2);
+        assert!(reads.latency.mean().unwrap() >= Duration::from_millis(3));
+        // 3ms fall into the bucket of latencies up to 4096µs
+        assert_eq!(reads.latency.buckets[12], 2);
+        assert_eq!(
+            snapshot.operation(fuse_opcode::FUSE_WRITE).unwrap().errors,
+            0
+        );
+        assert!(snapshot.operation(fuse_opcode::FUSE_LOOKUP).is_none());
+        assert_eq!(snapshot.errors.get(&libc::EIO), Some(&1));
+        assert_eq!(snapshot.bytes_read, 100);
+        assert_eq!(snapshot.bytes_written, 42);
+    }
+
+    #[test]
+    fn percentile() {
+        let mut buckets = vec![0; 32];
+        buckets[1] = 90;
+        buckets[10] = 10;
+        let histogram = LatencyHistogram {
+            buckets,
+            total: Duration::from_millis(10),
+        };
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(2)));
+        assert_eq!(
+            histogram.percentile(99.0),
+            Some(Duration::from_micros(1024))
+        );
+        assert_eq!(LatencyHistogram::default().percentile(50.0), None);
+    }
+}
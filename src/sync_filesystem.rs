@@ -0,0 +1,98 @@
+//! Shared filesystem trait
+//!
+//! Filesystems that multiple session worker threads can dispatch requests to at the same
+//! time (see `Session::run_multithreaded`).
+
+use crate::Filesystem;
+
+/// Shared filesystem trait.
+///
+/// Implemented for every `Filesystem` that can be cloned and sent to other threads. Every
+/// worker of a multithreaded session dispatches requests to its own clone, so clones need to
+/// share the state of the filesystem. Usually, `Filesystem` is implemented for a shared
+/// reference to a `Sync` filesystem (`impl Filesystem for &MyFilesystem`), whose methods use
+/// interior mutability (e.g. a `Mutex` or atomics) for any state they change. A filesystem
+/// that keeps its state in an `Arc` works as well.
+///
+/// State a clone keeps for itself isn't shared with the other clones. Especially, `init` is
+/// only called on the session's filesystem before the workers are cloned from it, and
+/// `destroy` is only called on the clone of the worker that receives the destroy request
+/// (or on the session's filesystem if the kernel doesn't send one). Anything they set up or
+/// tear down needs to be shared by all clones.
+pub trait SyncFilesystem: Filesystem + Clone + Send {}
+
+impl<T: Filesystem + Clone + Send> SyncFilesystem for T {}
+
+#[cfg(test)]
+mod test {
+    use crate::session::Session;
+    use crate::test_util::{channel, RawRequest};
+    use crate::{Filesystem, ReplyAttr, Request};
+    use fuse_abi::{fuse_opcode, FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+    use std::io::{Read, Write};
+    use std::sync::{Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Filesystem that answers getattr requests once two of them are in progress at the
+    /// same time
+    #[derive(Default)]
+    struct PairingFilesystem {
+        in_progress: Mutex<usize>,
+        paired: Condvar,
+    }
+
+    impl Filesystem for &PairingFilesystem {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+            let mut in_progress = self.in_progress.lock().unwrap();
+            *in_progress += 1;
+            self.paired.notify_all();
+            let timeout = Duration::from_secs(5);
+            let (in_progress, _) = self
+                .paired
+                .wait_timeout_while(in_progress, timeout, |n| *n < 2)
+                .unwrap();
+            let paired = *in_progress >= 2;
+            reply.error(if paired { libc::ENOENT } else { libc::EIO });
+        }
+    }
+
+    #[test]
+    fn dispatches_concurrently() {
+        let (ch, mut kernel) = channel();
+        let filesystem = PairingFilesystem::default();
+        let mut se = Session::with_channel(&filesystem, ch);
+        thread::scope(|scope| {
+            let worker = scope.spawn(move || se.run_multithreaded(2));
+
+            let init = [
+                FUSE_KERNEL_VERSION.to_ne_bytes(),
+                FUSE_KERNEL_MINOR_VERSION.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+                0u32.to_ne_bytes(),
+            ]
+            .concat();
+            let init = RawRequest::new(fuse_opcode::FUSE_INIT, 1, 0, &init);
+            kernel.write_all(init.as_bytes()).unwrap();
+            let mut reply = [0; 256];
+            let len = kernel.read(&mut reply).unwrap();
+            assert!(len > 16);
+            assert_eq!(&reply[4..8], &0i32.to_ne_bytes());
+
+            // Both requests are answered only if two workers dispatch them at the same time
+            for unique in 2..4 {
+                let getattr = RawRequest::new(fuse_opcode::FUSE_GETATTR, unique, 1, &[0; 16]);
+                kernel.write_all(getattr.as_bytes()).unwrap();
+            }
+            for _ in 2..4 {
+                let mut reply = [0; 16];
+                kernel.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply[4..8], &(-libc::ENOENT).to_ne_bytes());
+            }
+
+            // Closing the device ends the session
+            drop(kernel);
+            worker.join().unwrap().unwrap();
+        });
+    }
+}
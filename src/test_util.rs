@@ -0,0 +1,71 @@
+//! Helpers for unit tests
+//!
+//! Tests exchange requests and replies with a fake kernel through a socket pair instead of
+//! the FUSE device.
+
+use fuse_abi::fuse_opcode;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::channel::Channel;
+
+/// Size of `fuse_in_header`
+const HEADER_SIZE: usize = 40;
+
+/// Create a channel to a fake kernel. Returns the channel and the kernel's end, which
+/// receives replies and can send requests. On Linux, every read of the channel returns
+/// exactly one request like the FUSE device does. Dropping the channel only closes its fd,
+/// since it doesn't unmount.
+pub(crate) fn channel() -> (Channel, UnixStream) {
+    channel_at(Path::new("/mnt"))
+}
+
+/// Create a channel to a fake kernel like `channel` for a filesystem mounted at the given
+/// mountpoint
+pub(crate) fn channel_at(mountpoint: &Path) -> (Channel, UnixStream) {
+    let (device, kernel) = socket_pair();
+    let ch = unsafe { Channel::from_fd(device, mountpoint, false) };
+    (ch, unsafe { UnixStream::from_raw_fd(kernel) })
+}
+
+#[cfg(target_os = "linux")]
+fn socket_pair() -> (RawFd, RawFd) {
+    let mut fds = [0; 2];
+    let typ = libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC;
+    let rc = unsafe { libc::socketpair(libc::AF_UNIX, typ, 0, fds.as_mut_ptr()) };
+    assert_eq!(rc, 0, "socketpair failed");
+    (fds[0], fds[1])
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_pair() -> (RawFd, RawFd) {
+    use std::os::unix::io::IntoRawFd;
+    let (device, kernel) = UnixStream::pair().unwrap();
+    (device.into_raw_fd(), kernel.into_raw_fd())
+}
+
+/// Raw request, aligned so that arguments can be read in place
+#[repr(C, align(8))]
+pub(crate) struct RawRequest([u8; 128]);
+
+impl RawRequest {
+    /// Build a request with the given opcode, unique identifier and node id, followed by the
+    /// given argument
+    pub(crate) fn new(opcode: fuse_opcode, unique: u64, nodeid: u64, arg: &[u8]) -> RawRequest {
+        let len = HEADER_SIZE + arg.len();
+        let mut data = RawRequest([0; 128]);
+        data.0[0..4].copy_from_slice(&(len as u32).to_ne_bytes());
+        data.0[4..8].copy_from_slice(&(opcode as u32).to_ne_bytes());
+        data.0[8..16].copy_from_slice(&unique.to_ne_bytes());
+        data.0[16..24].copy_from_slice(&nodeid.to_ne_bytes());
+        data.0[HEADER_SIZE..len].copy_from_slice(arg);
+        data
+    }
+
+    /// Returns the bytes of the request
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let len = u32::from_ne_bytes([self.0[0], self.0[1], self.0[2], self.0[3]]);
+        &self.0[..len as usize]
+    }
+}
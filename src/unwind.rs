@@ -0,0 +1,262 @@
+//! Panics in filesystem methods
+//!
+//! A panic in a filesystem method would unwind through the session loop and end it, leaving
+//! the mountpoint disconnected until it's unmounted. Instead, panics are caught when a
+//! request is dispatched. Replies the panicking method didn't send are answered with EIO
+//! when they're dropped while unwinding, and the session's `PanicPolicy` decides whether the
+//! session keeps serving requests.
+
+use fuse_abi::fuse_opcode;
+use log::{error, warn};
+use std::any::Any;
+use std::convert::TryFrom;
+#[cfg(target_os = "linux")]
+use std::ffi::OsString;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStringExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::channel;
+use crate::request::{Request, RequestDispatcher};
+use crate::session::FuseSessionStore;
+
+/// What a session does after a filesystem method panicked (see `Session::set_panic_policy`)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PanicPolicy {
+    /// Keep serving requests (the default)
+    #[default]
+    Continue,
+    /// Abort the connection, so that the session loop ends and the mountpoint is left
+    /// disconnected until it's unmounted. Aborting needs access to the fusectl filesystem
+    /// (`/sys/fs/fuse/connections`), which usually requires root. The filesystem is
+    /// unmounted instead if the connection can't be aborted.
+    Abort,
+    /// Unmount the filesystem, so that the session loop ends after the remaining requests
+    Unmount,
+}
+
+/// Carries out the panic policy of a session
+#[derive(Clone, Debug)]
+pub(crate) struct PanicHandler {
+    /// What to do after a panic
+    policy: PanicPolicy,
+    /// Path of the mounted filesystem
+    mountpoint: PathBuf,
+}
+
+impl PanicHandler {
+    pub(crate) fn new(policy: PanicPolicy, mountpoint: &Path) -> PanicHandler {
+        PanicHandler {
+            policy,
+            mountpoint: mountpoint.to_path_buf(),
+        }
+    }
+
+    /// Carry out the policy after a filesystem method panicked
+    fn handle(&self) {
+        match self.policy {
+            PanicPolicy::Continue => (),
+            PanicPolicy::Abort => match abort(&self.mountpoint) {
+                Ok(()) => warn!("Aborted connection of {}", self.mountpoint.display()),
+                Err(err) => {
+                    error!(
+                        "Failed to abort connection of {}, unmounting: {}",
+                        self.mountpoint.display(),
+                        err
+                    );
+                    self.unmount();
+                }
+            },
+            PanicPolicy::Unmount => self.unmount(),
+        }
+    }
+
+    /// Unmount the filesystem in a separate thread, since unmounting may access the
+    /// mountpoint, which needs the session loop to answer
+    fn unmount(&self) {
+        let mountpoint = self.mountpoint.clone();
+        let res = thread::Builder::new()
+            .name("fuse-unmount".to_string())
+            .spawn(move || {
+                warn!("Unmounting {}", mountpoint.display());
+                if let Err(err) = channel::unmount(&mountpoint) {
+                    error!("Failed to unmount {}: {}", mountpoint.display(), err);
+                }
+            });
+        if let Err(err) = res {
+            error!("Failed to start unmount thread: {}", err);
+        }
+    }
+}
+
+/// Dispatch the given request to the given filesystem. If a filesystem method panics, the
+/// panic is logged and handled according to the session's panic policy.
+pub(crate) fn dispatch<FS: RequestDispatcher>(
+    filesystem: &mut FS,
+    req: &mut Request<'_>,
+    store: &mut FuseSessionStore,
+) {
+    let res = panic::catch_unwind(AssertUnwindSafe(|| filesystem.dispatch(req, store)));
+    if let Err(payload) = res {
+        let opcode = match fuse_opcode::try_from(req.opcode()) {
+            Ok(opcode) => format!("{:?}", opcode),
+            Err(_) => req.opcode().to_string(),
+        };
+        error!(
+            "Filesystem panicked on FUSE({}) {} ino {:#018x}: {}",
+            req.unique(),
+            opcode,
+            req.nodeid(),
+            message(&*payload)
+        );
+        if let Some(handler) = &store.panics {
+            handler.handle();
+        }
+    }
+}
+
+/// Return the message of a panic
+fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// Abort the FUSE connection of the filesystem mounted at the given path
+#[cfg(target_os = "linux")]
+fn abort(mountpoint: &Path) -> io::Result<()> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let connection = connection(&mountinfo, mountpoint).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "no FUSE filesystem mounted there")
+    })?;
+    fs::write(
+        format!("/sys/fs/fuse/connections/{}/abort", connection),
+        "1",
+    )
+}
+
+/// Connections can only be aborted on Linux
+#[cfg(not(target_os = "linux"))]
+fn abort(_mountpoint: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unsupported on this system",
+    ))
+}
+
+/// Find the connection number of the FUSE filesystem mounted at the given path in the given
+/// mount table (`/proc/self/mountinfo`), which is the minor device number of the mount. The
+/// mountpoint itself isn't accessed, since that needs the session loop to answer.
+#[cfg(target_os = "linux")]
+fn connection(mountinfo: &str, mountpoint: &Path) -> Option<u32> {
+    // Fields: mount id, parent id, major:minor, root, mount point, options, optional
+    // fields, separator, filesystem type, source, super options. Later mounts on the
+    // same path hide earlier ones.
+    let mut connection = None;
+    for line in mountinfo.lines() {
+        let mut fields = line.split(' ');
+        let device = fields.nth(2);
+        let path = fields.nth(1);
+        let typ = fields.skip_while(|field| *field != "-").nth(1);
+        if let (Some(device), Some(path), Some(typ)) = (device, path, typ) {
+            let fuse = typ == "fuse" || typ == "fuseblk" || typ.starts_with("fuse.");
+            if fuse && Path::new(&unescape(path)) == mountpoint {
+                connection = device
+                    .split(':')
+                    .nth(1)
+                    .and_then(|minor| minor.parse().ok());
+            }
+        }
+    }
+    connection
+}
+
+/// Unescape a path of the mount table, which has spaces, tabs, newlines and backslashes
+/// escaped as octal numbers
+#[cfg(target_os = "linux")]
+fn unescape(path: &str) -> OsString {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let code = tail
+            .get(..3)
+            .and_then(|code| std::str::from_utf8(code).ok());
+        match code.map(|code| u8::from_str_radix(code, 8)) {
+            Some(Ok(code)) if byte == b'\\' => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    OsString::from_vec(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::dispatch;
+    use crate::request::Request;
+    use crate::session::FuseSessionStore;
+    use crate::test_util::{channel, RawRequest};
+    use crate::{Filesystem, ReplyAttr};
+    use fuse_abi::fuse_opcode;
+    use std::io::Read;
+    use std::path::Path;
+
+    struct PanickingFilesystem;
+
+    impl Filesystem for PanickingFilesystem {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, _reply: ReplyAttr) {
+            panic!("getattr failed");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connection() {
+        let mountinfo = "\
+            22 1 0:21 / /proc rw,nosuid - proc proc rw\n\
+            60 22 0:52 / /mnt/my\\040fs rw,nosuid shared:1 - fuse.myfs myfs rw,user_id=0\n\
+            61 22 0:53 / /mnt/other rw,nosuid - fuse other rw,user_id=0\n";
+        assert_eq!(
+            super::connection(mountinfo, Path::new("/mnt/my fs")),
+            Some(52)
+        );
+        assert_eq!(
+            super::connection(mountinfo, Path::new("/mnt/other")),
+            Some(53)
+        );
+        assert_eq!(super::connection(mountinfo, Path::new("/proc")), None);
+        assert_eq!(super::connection(mountinfo, Path::new("/mnt")), None);
+    }
+
+    #[test]
+    fn replies_after_panic() {
+        let (ch, mut kernel) = channel();
+        let mut store = FuseSessionStore::new();
+        store.initialized = true;
+
+        let data = RawRequest::new(fuse_opcode::FUSE_GETATTR, 7, 1, &[]);
+        let unimplemented = store.unimplemented.clone();
+        let mut req = Request::new(ch.sender(), data.as_bytes(), unimplemented).unwrap();
+        dispatch(&mut PanickingFilesystem, &mut req, &mut store);
+
+        let mut reply = [0; 16];
+        kernel.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[0..4], &16u32.to_ne_bytes());
+        assert_eq!(&reply[4..8], &(-libc::EIO).to_ne_bytes());
+        assert_eq!(&reply[8..16], &7u64.to_ne_bytes());
+    }
+}
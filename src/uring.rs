@@ -0,0 +1,490 @@
+//! FUSE over io_uring (Linux only, `uring` feature)
+//!
+//! Since Linux 6.14, the kernel can pass requests to userspace and take replies through
+//! io_uring instead of the FUSE device, which saves a read and a writev syscall per request.
+//! Every possible CPU has a queue of entries, each consisting of a header and a payload
+//! buffer that are registered with the kernel once the filesystem is initialized. The kernel
+//! writes a request to an entry of the queue of the CPU the requesting process runs on. The
+//! reply is written to the same buffers and committed, which also hands the entry back to the
+//! kernel to receive the next request. Until entries of all queues are registered, and for
+//! forgets and interrupts, the kernel keeps using the FUSE device, so it's polled through the
+//! same io_uring. If registering fails (e.g. the fuse module's `enable_uring` parameter isn't
+//! set), the kernel falls back to the FUSE device as well.
+
+use fuse_abi::consts::{
+    FUSE_IO_URING_CMD_COMMIT_AND_FETCH, FUSE_IO_URING_CMD_REGISTER, FUSE_URING_OP_IN_OUT_SZ,
+};
+use fuse_abi::{fuse_in_header, fuse_out_header, fuse_uring_cmd_req, fuse_uring_req_header};
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use libc::{self, c_void, EIO};
+use log::{error, warn};
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::session::KERNEL_MAX_PAGES;
+use crate::splice;
+
+/// User data of the completion of polling the FUSE device
+const DEVICE: u64 = u64::MAX;
+
+/// User data of the completion of polling the wake fd
+const WAKE: u64 = u64::MAX - 1;
+
+/// Smallest buffer the kernel accepts for requests
+const FUSE_MIN_READ_BUFFER: usize = 8192;
+
+/// Flag of io_uring_enter to wait for completions
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+
+/// An entry registered with the kernel. It's owned by the kernel until a request was
+/// written to it, and by userspace until the reply was committed.
+struct RingEntry {
+    /// Queue the entry belongs to
+    qid: u16,
+    /// Headers of the request and the reply
+    header: Box<UnsafeCell<fuse_uring_req_header>>,
+    /// Further arguments of the request and payload of the reply
+    payload: UnsafeCell<Vec<u8>>,
+    /// Buffers passed to the kernel when registering
+    iovecs: [libc::iovec; 2],
+}
+
+impl RingEntry {
+    fn new(qid: u16, payload_size: usize) -> RingEntry {
+        let header: Box<UnsafeCell<fuse_uring_req_header>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        // Most of the payload buffer is never touched for most workloads, so it's only
+        // allocated (zeroed) lazily
+        let mut payload = vec![0; payload_size];
+        let iovecs = [
+            libc::iovec {
+                iov_base: header.get() as *mut c_void,
+                iov_len: mem::size_of::<fuse_uring_req_header>(),
+            },
+            libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut c_void,
+                iov_len: payload.len(),
+            },
+        ];
+        RingEntry {
+            qid,
+            header,
+            payload: UnsafeCell::new(payload),
+            iovecs,
+        }
+    }
+}
+
+/// State of an io_uring shared with the reply senders of its entries
+struct Shared {
+    /// The io_uring
+    ring: IoUring<squeue::Entry128>,
+    /// Serializes pushing to the submission queue
+    submission: Mutex<()>,
+    /// FUSE device fd the entries are registered on
+    dev: RawFd,
+    /// Registered entries. The vector isn't changed after registering, so that the iovecs
+    /// passed to the kernel stay in place.
+    entries: Vec<RingEntry>,
+    /// Number of entries owned by the kernel
+    in_flight: AtomicUsize,
+}
+
+// The buffers of an entry are only accessed by its owner, see `RingEntry`
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    /// Create a command for the entry with the given index
+    fn command(&self, index: usize, cmd_op: u32, commit_id: u64) -> squeue::Entry128 {
+        let entry = &self.entries[index];
+        let req = fuse_uring_cmd_req {
+            flags: 0,
+            commit_id,
+            qid: entry.qid,
+            padding: [0; 6],
+        };
+        let mut cmd = [0; 80];
+        unsafe {
+            ptr::copy_nonoverlapping(
+                &req as *const fuse_uring_cmd_req as *const u8,
+                cmd.as_mut_ptr(),
+                mem::size_of::<fuse_uring_cmd_req>(),
+            );
+        }
+        let sqe = opcode::UringCmd80::new(types::Fd(self.dev), cmd_op)
+            .cmd(cmd)
+            .build()
+            .user_data(index as u64);
+        // The kernel takes the buffers of an entry from an array of iovecs in the addr and
+        // len fields of the SQE, which the io-uring crate can't set for uring commands. The
+        // SQE layout is part of the kernel ABI: addr is at offset 16, len at offset 24.
+        let mut raw: [u8; 128] = unsafe { mem::transmute(sqe) };
+        raw[16..24].copy_from_slice(&(entry.iovecs.as_ptr() as u64).to_ne_bytes());
+        raw[24..28].copy_from_slice(&(entry.iovecs.len() as u32).to_ne_bytes());
+        unsafe { mem::transmute(raw) }
+    }
+
+    /// Push the given entry to the submission queue and submit it
+    fn submit(&self, sqe: &squeue::Entry128) -> io::Result<()> {
+        let _lock = self
+            .submission
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        // The queue is as large as the number of entries plus one for polling the device,
+        // and each of them has at most one command pending, so it can't be full
+        let res = unsafe { self.ring.submission_shared().push(sqe) };
+        res.map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.ring.submitter().submit()?;
+        Ok(())
+    }
+
+    /// Submit a command to commit a reply for or register the entry with the given index
+    fn submit_command(&self, index: usize, cmd_op: u32, commit_id: u64) -> io::Result<()> {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let res = self.submit(&self.command(index, cmd_op, commit_id));
+        if res.is_err() {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+        res
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // The kernel may still write to entries it owns until the io_uring is torn down,
+        // which happens asynchronously after it's closed
+        if *self.in_flight.get_mut() > 0 {
+            warn!("Leaking io_uring entries still owned by the kernel");
+            mem::forget(mem::take(&mut self.entries));
+        }
+    }
+}
+
+/// Entry of an io_uring to commit a reply to
+#[derive(Clone)]
+pub(crate) struct EntryRef {
+    shared: Arc<Shared>,
+    index: usize,
+}
+
+impl EntryRef {
+    /// Commit a reply to the entry, which also hands it back to the kernel to fetch the next
+    /// request. The first slice is the fuse_out_header, the others are the payload.
+    pub(crate) fn commit(&self, data: &[&[u8]]) -> io::Result<()> {
+        let entry = &self.shared.entries[self.index];
+        // The entry is owned by userspace until this commits it. A request is replied to
+        // exactly once, and the reply is sent after the request was copied from the entry.
+        let commit_id = unsafe {
+            let header = &mut *entry.header.get();
+            let payload = &mut *entry.payload.get();
+            let len = data.iter().skip(1).map(|d| d.len()).sum::<usize>();
+            match data.first() {
+                Some(out) if out.len() <= header.in_out.len() && len <= payload.len() => {
+                    header.in_out[..out.len()].copy_from_slice(out);
+                    let mut pos = 0;
+                    for d in data.iter().skip(1) {
+                        payload[pos..pos + d.len()].copy_from_slice(d);
+                        pos += d.len();
+                    }
+                    header.ring_ent_in_out.payload_sz = len as u32;
+                }
+                _ => {
+                    // The entry needs to be committed anyway to receive further requests
+                    error!(
+                        "FUSE reply of {} bytes doesn't fit into io_uring entry",
+                        len
+                    );
+                    let out = fuse_out_header {
+                        len: mem::size_of::<fuse_out_header>() as u32,
+                        error: -EIO,
+                        unique: header.ring_ent_in_out.commit_id,
+                    };
+                    ptr::write_unaligned(header.in_out.as_mut_ptr() as *mut _, out);
+                    header.ring_ent_in_out.payload_sz = 0;
+                }
+            }
+            header.ring_ent_in_out.commit_id
+        };
+        self.shared
+            .submit_command(self.index, FUSE_IO_URING_CMD_COMMIT_AND_FETCH, commit_id)
+    }
+}
+
+impl fmt::Debug for EntryRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "EntryRef({})", self.index)
+    }
+}
+
+/// Completion of an io_uring a session loop processes
+#[derive(Debug)]
+pub(crate) enum Completion {
+    /// A request was received into the entry with the given index
+    Request(usize),
+    /// The FUSE device is readable
+    Device,
+    /// The wake fd is readable
+    Wake,
+}
+
+/// An io_uring with entries of some of the queues, which also polls the FUSE device
+pub(crate) struct Ring {
+    shared: Arc<Shared>,
+    /// Completions that weren't processed yet
+    completions: VecDeque<cqueue::Entry>,
+    /// Entries with requests that were held back while the session was paused
+    held: VecDeque<usize>,
+    /// True while polling the FUSE device is pending
+    polling: bool,
+    /// Fd that is polled as well, e.g. to wake up for deferred requests
+    wake: Option<RawFd>,
+    /// True while polling the wake fd is pending
+    waking: bool,
+    /// True once the FUSE device was closed by the kernel
+    closed: bool,
+    /// True once registering an entry failed
+    failed: bool,
+}
+
+impl Ring {
+    /// Create an io_uring for the FUSE device and register `depth` entries for each of the
+    /// given queues (at least one). The filesystem needs to be initialized already with the
+    /// given max size of write requests. The FUSE device is switched to non-blocking mode,
+    /// since it's polled through the io_uring.
+    pub(crate) fn new(
+        dev: RawFd,
+        queues: &[u16],
+        depth: usize,
+        max_write: u32,
+    ) -> io::Result<Ring> {
+        let payload_size = payload_size(max_write);
+        let entries: Vec<_> = queues
+            .iter()
+            .flat_map(|&qid| (0..depth.max(1)).map(move |_| RingEntry::new(qid, payload_size)))
+            .collect();
+        let size = (entries.len() + 1).next_power_of_two() as u32;
+        let ring = IoUring::<squeue::Entry128>::builder().build(size)?;
+        let flags = unsafe { libc::fcntl(dev, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(dev, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let shared = Arc::new(Shared {
+            ring,
+            submission: Mutex::new(()),
+            dev,
+            entries,
+            in_flight: AtomicUsize::new(0),
+        });
+        for index in 0..shared.entries.len() {
+            shared.submit_command(index, FUSE_IO_URING_CMD_REGISTER, 0)?;
+        }
+        Ok(Ring {
+            shared,
+            completions: VecDeque::new(),
+            held: VecDeque::new(),
+            polling: false,
+            wake: None,
+            waking: false,
+            closed: false,
+            failed: false,
+        })
+    }
+
+    /// Wait for the next completion. Unless `requests` is true, received requests are held
+    /// back in their entries until the next call with `requests` set, so that only the FUSE
+    /// device and the wake fd are processed. The kernel sends interrupts and forgets through
+    /// the FUSE device. Returns None once the FUSE device was closed and the kernel released
+    /// all entries.
+    pub(crate) fn next(&mut self, requests: bool) -> io::Result<Option<Completion>> {
+        loop {
+            if requests {
+                if let Some(index) = self.held.pop_front() {
+                    return Ok(Some(Completion::Request(index)));
+                }
+            }
+            while let Some(cqe) = self.completions.pop_front() {
+                match self.complete(&cqe) {
+                    Some(Completion::Request(index)) if !requests => self.held.push_back(index),
+                    Some(completion) => return Ok(Some(completion)),
+                    None => (),
+                }
+            }
+            let held = self.held.len();
+            if self.closed && self.shared.in_flight.load(Ordering::Acquire) + held == 0 {
+                return Ok(None);
+            }
+            if !self.polling && !self.closed {
+                let sqe = opcode::PollAdd::new(types::Fd(self.shared.dev), libc::POLLIN as u32)
+                    .build()
+                    .user_data(DEVICE);
+                self.shared.submit(&sqe.into())?;
+                self.polling = true;
+            }
+            if let (Some(wake), false) = (self.wake, self.waking) {
+                let sqe = opcode::PollAdd::new(types::Fd(wake), libc::POLLIN as u32)
+                    .build()
+                    .user_data(WAKE);
+                self.shared.submit(&sqe.into())?;
+                self.waking = true;
+            }
+            // Commands are submitted by reply senders, so only wait for completions
+            let res = unsafe {
+                self.shared.ring.submitter().enter::<libc::sigset_t>(
+                    0,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    None,
+                )
+            };
+            match res {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+            self.completions
+                .extend(unsafe { self.shared.ring.completion_shared() });
+        }
+    }
+
+    /// Handle a completion, returns what to process
+    fn complete(&mut self, cqe: &cqueue::Entry) -> Option<Completion> {
+        if cqe.user_data() == DEVICE {
+            self.polling = false;
+            // Read the device on errors as well, to see why polling failed
+            return Some(Completion::Device);
+        }
+        if cqe.user_data() == WAKE {
+            self.waking = false;
+            return Some(Completion::Wake);
+        }
+        self.shared.in_flight.fetch_sub(1, Ordering::AcqRel);
+        let index = cqe.user_data() as usize;
+        if cqe.result() >= 0 {
+            return Some(Completion::Request(index));
+        }
+        // The entry is released by the kernel. That's expected once the filesystem is
+        // unmounted, otherwise the kernel uses the FUSE device instead.
+        let err = io::Error::from_raw_os_error(-cqe.result());
+        if !self.closed && !self.failed {
+            warn!("FUSE over io_uring failed, using the FUSE device: {}", err);
+            self.failed = true;
+        }
+        None
+    }
+
+    /// Also wait for the given fd to become readable
+    pub(crate) fn set_wake(&mut self, fd: RawFd) {
+        self.wake = Some(fd);
+    }
+
+    /// Mark the FUSE device as closed by the kernel, i.e. the filesystem was unmounted
+    pub(crate) fn close_device(&mut self) {
+        self.closed = true;
+    }
+
+    /// Copy the request received into the entry with the given index to the given buffer
+    pub(crate) fn receive(&self, index: usize, buffer: &mut Vec<u8>) {
+        let entry = &self.shared.entries[index];
+        // The entry is owned by userspace until the reply is committed
+        unsafe { copy_request(&*entry.header.get(), &*entry.payload.get(), buffer) }
+    }
+
+    /// Return the entry with the given index to commit the reply to
+    pub(crate) fn entry(&self, index: usize) -> EntryRef {
+        EntryRef {
+            shared: self.shared.clone(),
+            index,
+        }
+    }
+}
+
+impl fmt::Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Ring({} entries)", self.shared.entries.len())
+    }
+}
+
+/// Return the size of the payload buffer of entries, which needs to hold the largest request
+/// and reply. The kernel rejects smaller buffers.
+fn payload_size(max_write: u32) -> usize {
+    (max_write as usize)
+        .max(KERNEL_MAX_PAGES * splice::page_size())
+        .max(FUSE_MIN_READ_BUFFER)
+}
+
+/// Assemble a request received into an entry the same way it's read from the FUSE device:
+/// the fuse_in_header, followed by the operation specific header and further arguments.
+fn copy_request(header: &fuse_uring_req_header, payload: &[u8], buffer: &mut Vec<u8>) {
+    let in_header_len = mem::size_of::<fuse_in_header>();
+    let payload_len = (header.ring_ent_in_out.payload_sz as usize).min(payload.len());
+    let mut len = [0; 4];
+    len.copy_from_slice(&header.in_out[..4]);
+    // The length in the fuse_in_header is the length of the whole request
+    let op_len = (u32::from_ne_bytes(len) as usize)
+        .saturating_sub(in_header_len + payload_len)
+        .min(FUSE_URING_OP_IN_OUT_SZ);
+    buffer.clear();
+    buffer.extend_from_slice(&header.in_out[..in_header_len]);
+    buffer.extend_from_slice(&header.op_in[..op_len]);
+    buffer.extend_from_slice(&payload[..payload_len]);
+}
+
+/// Return the queues the given one of a number of workers registers entries for. The kernel
+/// has a queue for every possible CPU.
+pub(crate) fn queues(worker: usize, workers: usize) -> Vec<u16> {
+    let cpus = fs::read_to_string("/sys/devices/system/cpu/possible")
+        .ok()
+        .and_then(|list| cpu_count(&list))
+        .unwrap_or_else(|| unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as usize);
+    (0..cpus)
+        .filter(|cpu| cpu % workers.max(1) == worker)
+        .map(|cpu| cpu as u16)
+        .collect()
+}
+
+/// Return the number of CPUs in a CPU list like "0-3,6", i.e. the highest id plus one
+fn cpu_count(list: &str) -> Option<usize> {
+    let last = list.trim().rsplit([',', '-']).next()?;
+    last.parse::<usize>().ok().map(|cpu| cpu + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy_request, cpu_count};
+    use fuse_abi::fuse_uring_req_header;
+    use std::mem;
+
+    #[test]
+    fn possible_cpus() {
+        assert_eq!(cpu_count("0\n"), Some(1));
+        assert_eq!(cpu_count("0-7\n"), Some(8));
+        assert_eq!(cpu_count("0-3,6\n"), Some(7));
+        assert_eq!(cpu_count(""), None);
+    }
+
+    #[test]
+    fn assemble_request() {
+        let mut header: fuse_uring_req_header = unsafe { mem::zeroed() };
+        // fuse_in_header of a 52 bytes request: 40 bytes header, 8 bytes operation header,
+        // 4 bytes payload
+        header.in_out[..4].copy_from_slice(&52u32.to_ne_bytes());
+        header.in_out[4..8].copy_from_slice(&9u32.to_ne_bytes());
+        header.op_in[..8].copy_from_slice(&[0xed, 0x01, 0, 0, 0x12, 0, 0, 0]);
+        header.ring_ent_in_out.payload_sz = 4;
+        let mut buffer = vec![0xff; 100];
+        copy_request(&header, b"foo\0 and garbage", &mut buffer);
+        assert_eq!(buffer.len(), 52);
+        assert_eq!(&buffer[..8], &[52, 0, 0, 0, 9, 0, 0, 0]);
+        assert_eq!(&buffer[40..48], &[0xed, 0x01, 0, 0, 0x12, 0, 0, 0]);
+        assert_eq!(&buffer[48..], b"foo\0");
+    }
+}